@@ -7,5 +7,21 @@ static GLOBAL: Jemalloc = Jemalloc;
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     tracing_subscriber::fmt::init();
+
+    // replay a recorded CDP session as a fake browser: headless_browser replay <recording.jsonl> [address]
+    if std::env::args().nth(1).as_deref() == Some("replay") {
+        let path = std::env::args()
+            .nth(2)
+            .ok_or("usage: headless_browser replay <recording.jsonl> [address]")?;
+        let address = std::env::args()
+            .nth(3)
+            .unwrap_or_else(|| "127.0.0.1:9222".into());
+
+        let server = headless_browser_lib::replay::ReplayServer::from_file(path)?;
+        server.serve(&address).await?;
+
+        return Ok(());
+    }
+
     headless_browser_lib::run_main().await
 }
@@ -0,0 +1,297 @@
+use crate::conf::{ISOLATE_CONTEXTS, JWT_AUDIENCE, JWT_JWKS, JWT_SECRET, TLS_CLIENT_MAX_SESSIONS};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde_json::Value;
+
+/// The query param carrying the bearer token for clients that cannot set headers.
+pub(crate) const TOKEN_PARAM: &str = "access_token";
+
+lazy_static::lazy_static! {
+    /// The keys of the local JWKS file.
+    static ref JWKS: Option<JwkSet> = load_jwks(&JWT_JWKS);
+    /// The open proxy sessions by tenant.
+    static ref TENANT_SESSIONS: dashmap::DashMap<String, usize> = dashmap::DashMap::new();
+}
+
+/// Load the JWKS file. An invalid file disables the keys.
+fn load_jwks(path: &str) -> Option<JwkSet> {
+    if path.is_empty() {
+        return None;
+    }
+
+    match std::fs::read(path).map(|file| serde_json::from_slice::<JwkSet>(&file)) {
+        Ok(Ok(jwks)) => Some(jwks),
+        Ok(Err(e)) => {
+            tracing::error!("Invalid JWKS file {}: {}", path, e);
+            None
+        }
+        Err(e) => {
+            tracing::error!("Failed to read the JWKS file {}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// Is JWT auth enabled. The control routes and proxied upgrades then require a bearer token.
+pub(crate) fn enabled() -> bool {
+    !JWT_SECRET.is_empty() || !JWT_JWKS.is_empty()
+}
+
+/// The token of an `Authorization: Bearer {token}` header.
+pub(crate) fn bearer_token(authorization: Option<&str>) -> Option<&str> {
+    let (scheme, token) = authorization?.trim().split_once(' ')?;
+
+    if scheme.eq_ignore_ascii_case("bearer") && !token.trim().is_empty() {
+        Some(token.trim())
+    } else {
+        None
+    }
+}
+
+/// The tenant claims of a verified token.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Claims {
+    /// The tenant id from the `tenant` claim, falling back to `sub`.
+    pub tenant: String,
+    /// The max concurrent proxy sessions of the tenant.
+    pub max_sessions: Option<usize>,
+    /// The launch params the tenant can use, ex: `["headless", "window-size"]`. None allows all.
+    pub allowed_launch_options: Option<Vec<String>>,
+    /// The CDP domains the tenant can call, ex: `["Page", "Runtime"]`. None allows all.
+    pub allowed_domains: Option<Vec<String>>,
+    /// The token can call the instance lifecycle routes.
+    pub admin: bool,
+}
+
+impl Claims {
+    /// Is the tenant limited to its own targets by the allowed domains or isolated contexts.
+    pub fn restricted(&self) -> bool {
+        self.allowed_domains.is_some() || *ISOLATE_CONTEXTS
+    }
+
+    /// Read the tenant claims of the token payload. Tokens without a tenant are rejected.
+    fn from_json(claims: &Value) -> Result<Self, &'static str> {
+        let strings = |key: &str| {
+            claims.get(key).and_then(Value::as_array).map(|values| {
+                values
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(String::from)
+                    .collect()
+            })
+        };
+
+        let tenant = claims
+            .get("tenant")
+            .or_else(|| claims.get("sub"))
+            .and_then(Value::as_str)
+            .filter(|tenant| !tenant.is_empty())
+            .ok_or("missing tenant")?;
+
+        Ok(Self {
+            tenant: tenant.into(),
+            max_sessions: claims
+                .get("max_sessions")
+                .and_then(Value::as_u64)
+                .map(|max| max as usize),
+            allowed_launch_options: strings("allowed_launch_options"),
+            allowed_domains: strings("allowed_domains"),
+            admin: claims
+                .get("admin")
+                .and_then(Value::as_bool)
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Can the tenant use the launch param.
+    pub fn allows_launch_option(&self, param: &str) -> bool {
+        self.allowed_launch_options
+            .as_ref()
+            .is_none_or(|allowed| allowed.iter().any(|option| option == param))
+    }
+}
+
+/// The key to verify the token header with. HMAC tokens use the secret when set, the rest use the
+/// JWKS key of the `kid`, or the only key without one.
+fn decoding_key(
+    secret: &[u8],
+    jwks: Option<&JwkSet>,
+    header: &jsonwebtoken::Header,
+) -> Result<DecodingKey, &'static str> {
+    let hmac = matches!(
+        header.alg,
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+    );
+
+    if hmac && !secret.is_empty() {
+        return Ok(DecodingKey::from_secret(secret));
+    }
+
+    let jwks = jwks.ok_or("unsupported algorithm")?;
+
+    let jwk = match header.kid.as_deref() {
+        Some(kid) => jwks.find(kid),
+        _ if jwks.keys.len() == 1 => jwks.keys.first(),
+        _ => None,
+    }
+    .ok_or("unknown key")?;
+
+    // the key algorithm pins the token algorithm when set.
+    if let Some(key_algorithm) = jwk.common.key_algorithm {
+        if format!("{:?}", key_algorithm) != format!("{:?}", header.alg) {
+            return Err("algorithm does not match the key");
+        }
+    }
+
+    DecodingKey::from_jwk(jwk).map_err(|_| "invalid key")
+}
+
+/// Verify a bearer token against the secret or JWKS keys returning the tenant claims.
+pub(crate) fn verify_with(
+    secret: &[u8],
+    jwks: Option<&JwkSet>,
+    audience: &str,
+    token: &str,
+) -> Result<Claims, &'static str> {
+    let header = jsonwebtoken::decode_header(token).map_err(|_| "malformed token")?;
+    let key = decoding_key(secret, jwks, &header)?;
+
+    let mut validation = Validation::new(header.alg);
+
+    if audience.is_empty() {
+        validation.validate_aud = false;
+    } else {
+        validation.set_audience(&[audience]);
+        validation.set_required_spec_claims(&["exp", "aud"]);
+    }
+
+    let token =
+        jsonwebtoken::decode::<Value>(token, &key, &validation).map_err(|e| match e.kind() {
+            ErrorKind::ExpiredSignature => "expired token",
+            ErrorKind::InvalidAudience => "invalid audience",
+            ErrorKind::InvalidSignature => "invalid signature",
+            _ => "invalid token",
+        })?;
+
+    Claims::from_json(&token.claims)
+}
+
+/// Verify a bearer token with the configured keys.
+pub(crate) fn verify(token: &str) -> Result<Claims, &'static str> {
+    verify_with(JWT_SECRET.as_bytes(), JWKS.as_ref(), &JWT_AUDIENCE, token)
+}
+
+/// The tenant claims of a verified client certificate identity when JWT auth is disabled. The
+/// sessions are limited by `TLS_CLIENT_MAX_SESSIONS`.
+pub(crate) fn identity_claims(identity: &str) -> Claims {
+    Claims {
+        tenant: identity.to_string(),
+        max_sessions: Some(*TLS_CLIENT_MAX_SESSIONS).filter(|max| *max > 0),
+        ..Default::default()
+    }
+}
+
+/// An open proxy session counted against the tenant limit until dropped.
+pub(crate) struct TenantSession {
+    /// The tenant id.
+    tenant: String,
+}
+
+impl TenantSession {
+    /// Count a new session for the tenant. Errors when the tenant is at the max sessions.
+    pub fn acquire(claims: &Claims) -> Result<Self, &'static str> {
+        let mut sessions = TENANT_SESSIONS.entry(claims.tenant.clone()).or_insert(0);
+
+        if claims.max_sessions.is_some_and(|max| *sessions >= max) {
+            return Err("too many sessions");
+        }
+
+        *sessions += 1;
+
+        Ok(Self {
+            tenant: claims.tenant.clone(),
+        })
+    }
+}
+
+impl Drop for TenantSession {
+    fn drop(&mut self) {
+        TENANT_SESSIONS.remove_if_mut(&self.tenant, |_, sessions| {
+            *sessions = sessions.saturating_sub(1);
+            *sessions == 0
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    #[test]
+    fn test_tenant_claims() {
+        let secret = b"test-jwt-secret";
+        let exp = jsonwebtoken::get_current_timestamp() + 60;
+        let claims = serde_json::json!({
+            "sub": "user-1",
+            "tenant": "acme",
+            "exp": exp,
+            "max_sessions": 1,
+            "allowed_launch_options": ["window-size"],
+            "allowed_domains": ["Page", "Runtime"],
+        });
+        let token = encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(secret),
+        )
+        .unwrap();
+
+        let verified = verify_with(secret, None, "", &token).expect("valid token");
+
+        assert_eq!(verified.tenant, "acme");
+        assert!(!verified.admin);
+        assert!(verified.allows_launch_option("window-size"));
+        assert!(!verified.allows_launch_option("proxy-server"));
+        assert_eq!(
+            verified.allowed_domains.as_deref(),
+            Some(&["Page".to_string(), "Runtime".to_string()][..])
+        );
+
+        assert_eq!(
+            verify_with(b"other-secret", None, "", &token),
+            Err("invalid signature")
+        );
+        assert_eq!(
+            verify_with(secret, None, "headless-browser", &token),
+            Err("invalid token")
+        );
+
+        let anonymous = encode(
+            &Header::default(),
+            &serde_json::json!({ "exp": exp, "admin": true }),
+            &EncodingKey::from_secret(secret),
+        )
+        .unwrap();
+        assert_eq!(
+            verify_with(secret, None, "", &anonymous),
+            Err("missing tenant")
+        );
+
+        let identity = identity_claims("spiffe://example.org/client");
+        assert_eq!(identity.tenant, "spiffe://example.org/client");
+        assert!(!identity.admin && identity.allowed_domains.is_none());
+
+        let session = TenantSession::acquire(&verified).expect("first session");
+        assert!(TenantSession::acquire(&verified).is_err());
+        drop(session);
+        assert!(TenantSession::acquire(&verified).is_ok());
+
+        assert_eq!(
+            bearer_token(Some("Bearer abc.def.ghi")),
+            Some("abc.def.ghi")
+        );
+        assert_eq!(bearer_token(Some("Basic dXNlcg==")), None);
+    }
+}
@@ -0,0 +1,35 @@
+use hyper::body::{Bytes, Frame};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+
+/// A response body streamed from a channel. An error aborts the response before the last chunk
+/// so the client can tell the body is incomplete.
+pub(crate) struct ChannelBody {
+    /// The chunks of the body.
+    receiver: mpsc::Receiver<std::io::Result<Bytes>>,
+}
+
+impl ChannelBody {
+    /// A body and the sender of its chunks buffering up to `buffer` chunks. The body ends when
+    /// the sender is dropped.
+    pub fn channel(buffer: usize) -> (mpsc::Sender<std::io::Result<Bytes>>, Self) {
+        let (sender, receiver) = mpsc::channel(buffer);
+
+        (sender, Self { receiver })
+    }
+}
+
+impl hyper::body::Body for ChannelBody {
+    type Data = Bytes;
+    type Error = std::io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        self.receiver
+            .poll_recv(cx)
+            .map(|chunk| chunk.map(|chunk| chunk.map(Frame::data)))
+    }
+}
@@ -0,0 +1,256 @@
+use crate::cdp::CdpClient;
+use crate::conf::{CANARY_FAILURE_THRESHOLD, CANARY_INTERVAL, CANARY_MAX_LATENCY};
+use crate::instances::{self, InstanceKind};
+use serde_json::Value;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// The built-in test page with a single 32px square.
+const CANARY_PAGE: &str = "data:text/html,<html><body style=\"margin:0\"><div id=\"canary\" style=\"width:32px;height:32px;background:%23e33\"></div></body></html>";
+
+/// Resolves the width of the square after the page loaded and a frame was rendered.
+const CANARY_SCRIPT: &str = "new Promise((resolve) => { const done = () => requestAnimationFrame(() => resolve(document.getElementById('canary').getBoundingClientRect().width)); document.readyState === 'complete' ? done() : addEventListener('load', done); })";
+
+/// The max time to close the canary target after a failed check.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+lazy_static::lazy_static! {
+    /// The consecutive failed checks of the instances by process id.
+    static ref FAILURES: dashmap::DashMap<u32, u32> = dashmap::DashMap::new();
+}
+
+/// Run the future before the deadline failing as slow after.
+async fn within<T>(
+    deadline: Instant,
+    future: impl std::future::Future<Output = std::io::Result<T>>,
+) -> std::io::Result<T> {
+    tokio::time::timeout_at(deadline, future)
+        .await
+        .map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("canary exceeded {}ms", CANARY_MAX_LATENCY.as_millis()),
+            )
+        })?
+}
+
+/// Evaluate the canary script and capture a screenshot of the square in the attached target.
+async fn check(client: &mut CdpClient, target_id: &str) -> std::io::Result<()> {
+    let attached = client
+        .send(
+            "Target.attachToTarget",
+            serde_json::json!({ "targetId": target_id, "flatten": true }),
+            None,
+        )
+        .await?;
+    let session_id = attached
+        .get("sessionId")
+        .and_then(Value::as_str)
+        .ok_or_else(|| std::io::Error::other("canary target was not attached"))?;
+
+    let evaluated = client
+        .send(
+            "Runtime.evaluate",
+            serde_json::json!({
+                "expression": CANARY_SCRIPT,
+                "awaitPromise": true,
+                "returnByValue": true,
+            }),
+            Some(session_id),
+        )
+        .await?;
+
+    if let Some(exception) = evaluated.get("exceptionDetails") {
+        return Err(std::io::Error::other(format!(
+            "canary script threw: {}",
+            exception
+        )));
+    }
+
+    let width = evaluated
+        .pointer("/result/value")
+        .and_then(Value::as_f64)
+        .unwrap_or_default();
+
+    if width != 32.0 {
+        return Err(std::io::Error::other(format!(
+            "canary script returned {}",
+            width
+        )));
+    }
+
+    let screenshot = client
+        .send(
+            "Page.captureScreenshot",
+            serde_json::json!({
+                "format": "png",
+                "clip": { "x": 0, "y": 0, "width": 32, "height": 32, "scale": 1 },
+            }),
+            Some(session_id),
+        )
+        .await?;
+
+    if screenshot
+        .get("data")
+        .and_then(Value::as_str)
+        .is_none_or(str::is_empty)
+    {
+        return Err(std::io::Error::other("canary screenshot was empty"));
+    }
+
+    Ok(())
+}
+
+/// Open a target with the canary page, evaluate the script, capture a screenshot, and close the
+/// target. Returns the latency. Checks slower than `CANARY_MAX_LATENCY` fail.
+pub(crate) async fn run(address: &str) -> std::io::Result<Duration> {
+    let started = Instant::now();
+    let deadline = started + *CANARY_MAX_LATENCY;
+
+    let path = within(deadline, crate::launch::browser_path(address)).await?;
+    let mut client = within(deadline, CdpClient::connect(address, &path)).await?;
+
+    let target = within(
+        deadline,
+        client.send(
+            "Target.createTarget",
+            serde_json::json!({ "url": CANARY_PAGE }),
+            None,
+        ),
+    )
+    .await?;
+    let target_id = target
+        .get("targetId")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string();
+
+    if target_id.is_empty() {
+        return Err(std::io::Error::other("canary target was not created"));
+    }
+
+    let checked = within(deadline, check(&mut client, &target_id)).await;
+    let latency = started.elapsed();
+
+    // a hung renderer can leave the close unanswered.
+    let _ = tokio::time::timeout(
+        CLOSE_TIMEOUT,
+        client.send(
+            "Target.closeTarget",
+            serde_json::json!({ "targetId": target_id }),
+            None,
+        ),
+    )
+    .await;
+
+    checked.map(|_| latency)
+}
+
+/// Check a forked instance. Failed checks mark the instance unhealthy and the instance is
+/// recycled after `CANARY_FAILURE_THRESHOLD` consecutive failures.
+async fn check_instance(pid: u32, port: u32) {
+    match run(&crate::probe::instance_address(port)).await {
+        Ok(latency) => {
+            FAILURES.remove(&pid);
+            instances::set_healthy(pid, true);
+            crate::metrics::CANARY_SECONDS.observe(latency);
+        }
+        Err(e) => {
+            crate::metrics::CANARY_FAILURES.inc();
+
+            if instances::set_healthy(pid, false) {
+                crate::events::emit(crate::events::Event::InstanceUnhealthy {
+                    pid,
+                    error: e.to_string(),
+                });
+            }
+
+            let failures = {
+                let mut failures = FAILURES.entry(pid).or_default();
+                *failures += 1;
+                *failures
+            };
+
+            tracing::warn!(
+                "Canary of chrome {} failed ({} of {}): {}",
+                pid,
+                failures,
+                *CANARY_FAILURE_THRESHOLD,
+                e
+            );
+
+            if failures >= *CANARY_FAILURE_THRESHOLD {
+                FAILURES.remove(&pid);
+
+                match crate::recycle_instance(pid).await {
+                    Ok(replacement) => {
+                        tracing::warn!("Recycled unhealthy chrome {} as {}", pid, replacement)
+                    }
+                    Err(e) => tracing::error!("Failed to recycle chrome {}: {}", pid, e),
+                }
+            }
+        }
+    }
+}
+
+/// Check the forked instances on the `CANARY_INTERVAL`. Dedicated instances belong to a single
+/// session and are not checked.
+pub(crate) async fn monitor() {
+    if *CANARY_INTERVAL == 0 {
+        return;
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_secs(*CANARY_INTERVAL));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        let mut checks = tokio::task::JoinSet::new();
+
+        for (pid, port) in instances::ports() {
+            let forked = instances::info(pid).is_some_and(|info| info.kind == InstanceKind::Fork);
+
+            if let (true, Some(port)) = (forked, port) {
+                checks.spawn(check_instance(pid, port));
+            }
+        }
+
+        checks.join_all().await;
+
+        FAILURES.retain(|pid, _| instances::contains(*pid));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::serve_recording;
+
+    /// A canary recording with the width of the square.
+    fn recording(width: u32) -> String {
+        format!(
+            r#"{{"timestamp":1,"direction":"send","session":"1","id":1,"method":"Target.createTarget"}}
+{{"timestamp":2,"direction":"receive","session":"1","id":1,"method":"Target.createTarget","result":{{"targetId":"T1"}}}}
+{{"timestamp":3,"direction":"send","session":"1","id":2,"method":"Target.attachToTarget"}}
+{{"timestamp":4,"direction":"receive","session":"1","id":2,"method":"Target.attachToTarget","result":{{"sessionId":"S1"}}}}
+{{"timestamp":5,"direction":"send","session":"1","id":3,"method":"Runtime.evaluate"}}
+{{"timestamp":6,"direction":"receive","session":"1","id":3,"method":"Runtime.evaluate","result":{{"result":{{"type":"number","value":{}}}}}}}
+{{"timestamp":7,"direction":"send","session":"1","id":4,"method":"Page.captureScreenshot"}}
+{{"timestamp":8,"direction":"receive","session":"1","id":4,"method":"Page.captureScreenshot","result":{{"data":"iVBORw0KGgo="}}}}
+{{"timestamp":9,"direction":"send","session":"1","id":5,"method":"Target.closeTarget"}}
+{{"timestamp":10,"direction":"receive","session":"1","id":5,"method":"Target.closeTarget","result":{{"success":true}}}}"#,
+            width
+        )
+    }
+
+    #[tokio::test]
+    async fn test_canary() {
+        let address = serve_recording(&recording(32)).await;
+        assert!(run(&address).await.is_ok());
+
+        let address = serve_recording(&recording(0)).await;
+        let error = run(&address).await.expect_err("blank render");
+        assert_eq!(error.to_string(), "canary script returned 0");
+    }
+}
@@ -0,0 +1,134 @@
+use crate::ws::{
+    encode_frame, read_head, FrameReader, OPCODE_CLOSE, OPCODE_PING, OPCODE_PONG, OPCODE_TEXT,
+};
+use serde_json::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// A minimal CDP websocket client used by the manager to drive chrome directly.
+pub(crate) struct CdpClient {
+    /// The websocket connection.
+    stream: TcpStream,
+    /// The incoming frames.
+    reader: FrameReader,
+    /// The next command id.
+    next_id: u64,
+}
+
+/// A CDP protocol error.
+fn cdp_error(message: String) -> std::io::Error {
+    std::io::Error::other(message)
+}
+
+impl CdpClient {
+    /// Connect to the websocket debugger path of the chrome address, ex: `/devtools/browser/{id}`.
+    pub async fn connect(address: &str, path: &str) -> std::io::Result<Self> {
+        use base64::Engine;
+
+        let mut stream = TcpStream::connect(address).await?;
+        let key = base64::engine::general_purpose::STANDARD.encode(rand::random::<[u8; 16]>());
+
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            path, address, key
+        );
+
+        stream.write_all(request.as_bytes()).await?;
+
+        let (head, rest) = read_head(&mut stream).await?;
+
+        if !head.starts_with(b"HTTP/1.1 101") {
+            return Err(cdp_error(format!(
+                "websocket upgrade rejected: {}",
+                String::from_utf8_lossy(&head)
+                    .lines()
+                    .next()
+                    .unwrap_or_default()
+            )));
+        }
+
+        let mut reader = FrameReader::new();
+        reader.feed(&rest);
+
+        Ok(Self {
+            stream,
+            reader,
+            next_id: 1,
+        })
+    }
+
+    /// Send a command and wait for its result. Events received in between are skipped.
+    pub async fn send(
+        &mut self,
+        method: &str,
+        params: Value,
+        session_id: Option<&str>,
+    ) -> std::io::Result<Value> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let mut command = serde_json::json!({
+            "id": id,
+            "method": method,
+            "params": params,
+        });
+
+        if let Some(session_id) = session_id {
+            command["sessionId"] = session_id.into();
+        }
+
+        let frame = encode_frame(
+            OPCODE_TEXT,
+            command.to_string().as_bytes(),
+            Some(rand::random()),
+        );
+
+        self.stream.write_all(&frame).await?;
+
+        loop {
+            let mut message = self.read_message().await?;
+
+            if message.get("id").and_then(Value::as_u64) == Some(id) {
+                return match message.get_mut("error") {
+                    Some(error) => Err(cdp_error(format!("{} failed: {}", method, error))),
+                    _ => Ok(message
+                        .get_mut("result")
+                        .map(Value::take)
+                        .unwrap_or_default()),
+                };
+            }
+        }
+    }
+
+    /// Read the next CDP message answering pings.
+    async fn read_message(&mut self) -> std::io::Result<Value> {
+        let mut buf = vec![0u8; 16384];
+
+        loop {
+            while let Some(frame) = self.reader.next_frame() {
+                match frame.opcode {
+                    OPCODE_CLOSE => return Err(cdp_error("websocket closed".into())),
+                    OPCODE_PING => {
+                        let pong = encode_frame(OPCODE_PONG, &frame.payload, Some(rand::random()));
+                        self.stream.write_all(&pong).await?;
+                    }
+                    _ => {
+                        if let Some(message) = self.reader.push_frame(frame) {
+                            if let Ok(message) = serde_json::from_slice::<Value>(&message) {
+                                return Ok(message);
+                            }
+                        }
+                    }
+                }
+            }
+
+            let size = self.stream.read(&mut buf).await?;
+
+            if size == 0 {
+                return Err(cdp_error("websocket closed".into()));
+            }
+
+            self.reader.feed(&buf[..size]);
+        }
+    }
+}
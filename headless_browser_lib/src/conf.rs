@@ -205,6 +205,8 @@ lazy_static::lazy_static! {
     pub(crate) static ref CACHEABLE: AtomicBool = {
         AtomicBool::new(true)
     };
+    /// The node received a shutdown signal. New sessions are rejected and the node is not ready.
+    pub(crate) static ref DRAINING: AtomicBool = AtomicBool::new(false);
     /// The last cache date period.
     pub(crate) static ref LAST_CACHE: AtomicU64 = {
         AtomicU64::new(0)
@@ -241,6 +243,173 @@ lazy_static::lazy_static! {
     pub(crate) static ref TEN_SECONDS: std::time::Duration = {
         std::time::Duration::from_secs(10)
     };
+    /// The control server listen addresses. A comma separated list of `ip:port`, `[ipv6]:port`, or `unix:/path`.
+    pub(crate) static ref SERVER_LISTEN: Result<Vec<crate::listener::ListenAddr>, String> = {
+        crate::listener::listen_addrs("SERVER_LISTEN", &format!("0.0.0.0:{}", *DEFAULT_PORT_SERVER))
+    };
+    /// The proxy listen addresses. A comma separated list of `ip:port`, `[ipv6]:port`, or `unix:/path`.
+    pub(crate) static ref PROXY_LISTEN: Result<Vec<crate::listener::ListenAddr>, String> = {
+        crate::listener::listen_addrs("PROXY_LISTEN", *ENTRY)
+    };
+    /// Serve the control routes and the websocket proxy on the control listeners only.
+    pub(crate) static ref SINGLE_PORT: bool = std::env::var("SINGLE_PORT").unwrap_or_default() == "true";
+    /// The public proxy port used when rewriting the json output. The first tcp proxy listener, or control listener in single port mode.
+    pub(crate) static ref PROXY_PUBLIC_PORT: String = {
+        let listen = if *SINGLE_PORT { &*SERVER_LISTEN } else { &*PROXY_LISTEN };
+
+        listen
+            .iter()
+            .flatten()
+            .find_map(|addr| addr.port())
+            .map(|port| format!(":{}", port))
+            .unwrap_or_else(|| String::from_utf8_lossy(TARGET_REPLACEMENT.1).into_owned())
+    };
+    /// The PEM certificate chain for TLS on the control server and proxy. Rotated files are reloaded.
+    pub(crate) static ref TLS_CERT: String = std::env::var("TLS_CERT").unwrap_or_default();
+    /// The PEM private key for TLS on the control server and proxy.
+    pub(crate) static ref TLS_KEY: String = std::env::var("TLS_KEY").unwrap_or_default();
+    /// The PEM CA bundle to verify client certificates against. Clients without a certificate from the CA are rejected.
+    pub(crate) static ref TLS_CLIENT_CA: String = std::env::var("TLS_CLIENT_CA").unwrap_or_default();
+    /// The max concurrent sessions of a client certificate identity when JWT auth is disabled. Unlimited when 0, the default.
+    pub(crate) static ref TLS_CLIENT_MAX_SESSIONS: usize = std::env::var("TLS_CLIENT_MAX_SESSIONS").ok().and_then(|max| max.parse().ok()).unwrap_or(0);
+    /// The HMAC key to sign session urls with `POST /sessions`. Proxied connections then require a valid `token`.
+    pub(crate) static ref SESSION_SIGNING_KEY: String = std::env::var("SESSION_SIGNING_KEY").unwrap_or_default();
+    /// The default and max seconds until a signed session url expires. Defaults to 300.
+    pub(crate) static ref SESSION_TTL: u64 = std::env::var("SESSION_TTL").ok().and_then(|ttl| ttl.parse().ok()).unwrap_or(300);
+    /// Seconds between the CDP readiness probes of the instances. Defaults to 5.
+    pub(crate) static ref READINESS_PROBE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(
+        std::env::var("READINESS_PROBE_INTERVAL").ok().and_then(|interval| interval.parse().ok()).unwrap_or(5).max(1)
+    );
+    /// The max seconds since the last successful CDP probe for the service to be ready. Defaults to 30.
+    pub(crate) static ref READINESS_MAX_AGE: u64 = std::env::var("READINESS_MAX_AGE").ok().and_then(|age| age.parse().ok()).unwrap_or(30);
+    /// Seconds between the canary page checks of the forked instances. Disabled when 0, the default.
+    pub(crate) static ref CANARY_INTERVAL: u64 = std::env::var("CANARY_INTERVAL").ok().and_then(|interval| interval.parse().ok()).unwrap_or(0);
+    /// The max milliseconds of a canary check before it fails as slow. Defaults to 10000.
+    pub(crate) static ref CANARY_MAX_LATENCY: std::time::Duration = std::time::Duration::from_millis(
+        std::env::var("CANARY_MAX_LATENCY").ok().and_then(|latency| latency.parse().ok()).unwrap_or(10_000)
+    );
+    /// The consecutive failed canary checks before an unhealthy instance is recycled. Defaults to 2.
+    pub(crate) static ref CANARY_FAILURE_THRESHOLD: u32 = std::env::var("CANARY_FAILURE_THRESHOLD").ok().and_then(|threshold| threshold.parse().ok()).unwrap_or(2).max(1);
+    /// The max tracked chrome instances. Fork requests and dedicated launches past it are rejected. Defaults to 0 for unlimited.
+    pub(crate) static ref MAX_INSTANCES: usize = std::env::var("MAX_INSTANCES").ok().and_then(|max| max.parse().ok()).unwrap_or(0);
+    /// The crashes within the `CRASH_LOOP_WINDOW` that trip the crash loop breaker. Defaults to 5.
+    pub(crate) static ref CRASH_LOOP_THRESHOLD: usize = std::env::var("CRASH_LOOP_THRESHOLD").ok().and_then(|threshold| threshold.parse().ok()).unwrap_or(5).max(1);
+    /// The seconds of the crash loop window. Restarts are paused for the window once tripped. Defaults to 60.
+    pub(crate) static ref CRASH_LOOP_WINDOW: std::time::Duration = std::time::Duration::from_secs(
+        std::env::var("CRASH_LOOP_WINDOW").ok().and_then(|window| window.parse().ok()).unwrap_or(60)
+    );
+    /// The max seconds to wait for the proxied sessions to end after a shutdown signal. Defaults to 20.
+    pub(crate) static ref DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(
+        std::env::var("DRAIN_TIMEOUT").ok().and_then(|timeout| timeout.parse().ok()).unwrap_or(20)
+    );
+    /// The webhook urls receiving the instance crash, crash loop, capacity, and draining events, ex: `https://hooks.example/browsers`.
+    pub(crate) static ref WEBHOOK_URLS: Vec<String> = std::env::var("WEBHOOK_URLS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|url| url.trim().to_string())
+        .filter(|url| !url.is_empty())
+        .collect();
+    /// The HMAC key to sign the webhook payloads. Unsigned when empty.
+    pub(crate) static ref WEBHOOK_SECRET: String = std::env::var("WEBHOOK_SECRET").unwrap_or_default();
+    /// The delivery attempts of a webhook before it is dropped. Defaults to 5.
+    pub(crate) static ref WEBHOOK_MAX_ATTEMPTS: u32 = std::env::var("WEBHOOK_MAX_ATTEMPTS").ok().and_then(|attempts| attempts.parse().ok()).unwrap_or(5).max(1);
+    /// The PEM CA bundle to verify https webhook urls. Defaults to `SSL_CERT_FILE` or the system bundle.
+    pub(crate) static ref WEBHOOK_CA: String = std::env::var("WEBHOOK_CA")
+        .or_else(|_| std::env::var("SSL_CERT_FILE"))
+        .unwrap_or_else(|_| "/etc/ssl/certs/ca-certificates.crt".into());
+    /// The max concurrent screenshot and PDF jobs. Defaults to 8.
+    pub(crate) static ref MAX_RENDERS: usize = std::env::var("MAX_RENDERS").ok().and_then(|max| max.parse().ok()).unwrap_or(8).max(1);
+    /// The chrome arg names a fork request can add or remove, ex: `--lang,--proxy-server`.
+    pub(crate) static ref FORK_ALLOWED_ARGS: Vec<String> = std::env::var("FORK_ALLOWED_ARGS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|arg| arg.trim().split('=').next().unwrap_or_default().to_string())
+        .filter(|arg| !arg.is_empty())
+        .collect();
+    /// The environment variables a fork request can set, ex: `TZ,LANG`.
+    pub(crate) static ref FORK_ALLOWED_ENV: Vec<String> = std::env::var("FORK_ALLOWED_ENV")
+        .unwrap_or_default()
+        .split(',')
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect();
+    /// The browser binaries a fork request can pick by name, ex: `brave=/usr/bin/brave-browser,chromium=/usr/bin/chromium`.
+    pub(crate) static ref FORK_BROWSERS: Vec<(String, String)> = std::env::var("FORK_BROWSERS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|browser| browser.split_once('='))
+        .map(|(name, path)| (name.trim().to_string(), path.trim().to_string()))
+        .filter(|(name, path)| !name.is_empty() && !path.is_empty())
+        .collect();
+    /// The directory of the named profiles of fork requests. Profiles are disabled when empty.
+    pub(crate) static ref FORK_PROFILE_DIR: String = std::env::var("FORK_PROFILE_DIR").unwrap_or_default();
+    /// The HMAC secret for HS256, HS384, or HS512 JWT bearer tokens.
+    pub(crate) static ref JWT_SECRET: String = std::env::var("JWT_SECRET").unwrap_or_default();
+    /// The path to a local JWKS file with the public keys for JWT bearer tokens, ex: `/etc/headless-browser/jwks.json`.
+    pub(crate) static ref JWT_JWKS: String = std::env::var("JWT_JWKS").unwrap_or_default();
+    /// The required `aud` claim of JWT bearer tokens.
+    pub(crate) static ref JWT_AUDIENCE: String = std::env::var("JWT_AUDIENCE").unwrap_or_default();
+    /// The external base url of the service, ex: `https://gw.example/browsers/node-3/`. Used for the json url rewrites instead of the request headers.
+    pub(crate) static ref PUBLIC_URL: Option<crate::modify::PublicAddress> = {
+        crate::modify::PublicAddress::parse_url(&std::env::var("PUBLIC_URL").unwrap_or_default())
+    };
+    /// The path prefix the service is mounted under, ex: `/browsers/node-3`. Defaults to the `PUBLIC_URL` path.
+    pub(crate) static ref BASE_PATH: String = {
+        let base_path = std::env::var("BASE_PATH").unwrap_or_default();
+        let base_path = if base_path.trim().is_empty() {
+            PUBLIC_URL.as_ref().map(|public| public.base_path.clone()).unwrap_or_default()
+        } else {
+            base_path
+        };
+        crate::modify::normalize_base_path(&base_path)
+    };
+    /// How long proxied clients are parked while chrome restarts before receiving a 503. Defaults to 30 seconds.
+    pub(crate) static ref CHROME_RESTART_TIMEOUT: std::time::Duration = {
+        let seconds = std::env::var("CHROME_RESTART_TIMEOUT")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(30);
+        std::time::Duration::from_secs(seconds)
+    };
+    /// Require the HAProxy PROXY protocol v1 or v2 header on the tcp proxy and control listeners.
+    pub(crate) static ref PROXY_PROTOCOL: bool = std::env::var("PROXY_PROTOCOL").unwrap_or_default() == "true";
+    /// Scope every proxied browser session to its own browser context. Sessions can opt in with the `isolate=true` query param.
+    pub(crate) static ref ISOLATE_CONTEXTS: bool = std::env::var("ISOLATE_CONTEXTS").unwrap_or_default() == "true";
+    /// Close the targets left open by proxied browser sessions when the client disconnects. Set the value to true.
+    pub(crate) static ref CLOSE_LEAKED_TARGETS: bool = std::env::var("CLOSE_LEAKED_TARGETS").unwrap_or_default() == "true";
+}
+
+lazy_static::lazy_static! {
+    /// Record the CDP traffic of every proxied session. Admin tokens can opt in a session with the `record=true` query param.
+    pub(crate) static ref CDP_RECORD: bool = std::env::var("CDP_RECORD").unwrap_or_default() == "true";
+    /// The directory to write the CDP recordings.
+    pub(crate) static ref CDP_RECORD_DIR: String = {
+        std::env::var("CDP_RECORD_DIR")
+            .ok()
+            .filter(|dir| !dir.is_empty())
+            .unwrap_or_else(|| "cdp-recordings".into())
+    };
+    /// Truncate recorded string values longer than the length. Defaults to 0 to keep the full values.
+    pub(crate) static ref CDP_RECORD_MAX_LENGTH: usize = {
+        std::env::var("CDP_RECORD_MAX_LENGTH")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(0)
+    };
+    /// The max bytes of a CDP recording file. Later messages are not recorded. Defaults to 100MB, 0 is unlimited.
+    pub(crate) static ref CDP_RECORD_MAX_BYTES: u64 = {
+        std::env::var("CDP_RECORD_MAX_BYTES")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(100 * 1024 * 1024)
+    };
+    /// The max sessions recorded at the same time. Later sessions are not recorded. Defaults to 8.
+    pub(crate) static ref CDP_RECORD_MAX_SESSIONS: usize = {
+        std::env::var("CDP_RECORD_MAX_SESSIONS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(8)
+    };
 }
 
 #[cfg(not(feature = "physical_gpu"))]
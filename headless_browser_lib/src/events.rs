@@ -0,0 +1,311 @@
+use crate::body::ChannelBody;
+use hyper::body::Bytes;
+use serde_json::Value;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// The events buffered for slow receivers before they lag.
+const CAPACITY: usize = 1024;
+
+/// The interval of the keep alive comments on idle event streams.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// A lifecycle event of the chrome instances and proxied sessions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A chrome instance was launched.
+    InstanceForked {
+        /// The process id.
+        pid: u32,
+        /// The remote debugging port.
+        port: Option<u32>,
+        /// How the instance was launched, `fork` or `dedicated`.
+        kind: &'static str,
+    },
+    /// A chrome instance completed its first CDP round trip.
+    InstanceReady {
+        /// The process id. None for the proxy target chrome not launched by the server.
+        pid: Option<u32>,
+        /// The debugging address.
+        address: String,
+    },
+    /// A canary check of a healthy chrome instance failed.
+    InstanceUnhealthy {
+        /// The process id.
+        pid: u32,
+        /// The canary error.
+        error: String,
+    },
+    /// An unhealthy chrome instance was replaced.
+    InstanceRecycled {
+        /// The process id of the unhealthy instance.
+        pid: u32,
+        /// The process id of the replacement.
+        replacement: u32,
+    },
+    /// A chrome instance exited without a shutdown.
+    InstanceCrashed {
+        /// The process id.
+        pid: u32,
+        /// The exit code.
+        code: Option<i32>,
+        /// The signal that terminated the process.
+        signal: Option<i32>,
+    },
+    /// The crashes within the window tripped the crash loop breaker pausing restarts.
+    CrashLoopTripped {
+        /// The crashes within the window.
+        crashes: usize,
+        /// The crash loop window.
+        window: Duration,
+    },
+    /// A fork or dedicated launch was rejected at the max instances.
+    CapacityExhausted {
+        /// The tracked instances.
+        instances: usize,
+        /// The max instances.
+        max: usize,
+    },
+    /// The node received a shutdown signal and is waiting for the sessions to end.
+    NodeDraining {
+        /// The proxied sessions still connected.
+        sessions: u64,
+    },
+    /// A chrome instance was shutdown.
+    InstanceShutdown {
+        /// The process id.
+        pid: u32,
+    },
+    /// A proxied session connected to chrome.
+    SessionOpened {
+        /// The session id.
+        id: String,
+        /// The tenant of the bearer token.
+        tenant: Option<String>,
+    },
+    /// A proxied session disconnected.
+    SessionClosed {
+        /// The session id.
+        id: String,
+        /// The tenant of the bearer token.
+        tenant: Option<String>,
+        /// The session duration.
+        duration: Duration,
+    },
+}
+
+impl Event {
+    /// The event name, ex: `instance.forked`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::InstanceForked { .. } => "instance.forked",
+            Self::InstanceReady { .. } => "instance.ready",
+            Self::InstanceUnhealthy { .. } => "instance.unhealthy",
+            Self::InstanceRecycled { .. } => "instance.recycled",
+            Self::InstanceCrashed { .. } => "instance.crashed",
+            Self::CrashLoopTripped { .. } => "instance.crash_loop",
+            Self::CapacityExhausted { .. } => "capacity.exhausted",
+            Self::NodeDraining { .. } => "node.draining",
+            Self::InstanceShutdown { .. } => "instance.shutdown",
+            Self::SessionOpened { .. } => "session.opened",
+            Self::SessionClosed { .. } => "session.closed",
+        }
+    }
+
+    /// The json payload of the event.
+    pub fn to_json(&self) -> Value {
+        let mut payload = match self {
+            Self::InstanceForked { pid, port, kind } => {
+                serde_json::json!({ "pid": pid, "port": port, "kind": kind })
+            }
+            Self::InstanceReady { pid, address } => {
+                serde_json::json!({ "pid": pid, "address": address })
+            }
+            Self::InstanceUnhealthy { pid, error } => {
+                serde_json::json!({ "pid": pid, "error": error })
+            }
+            Self::InstanceRecycled { pid, replacement } => {
+                serde_json::json!({ "pid": pid, "replacement": replacement })
+            }
+            Self::InstanceCrashed { pid, code, signal } => {
+                serde_json::json!({ "pid": pid, "code": code, "signal": signal })
+            }
+            Self::CrashLoopTripped { crashes, window } => {
+                serde_json::json!({ "crashes": crashes, "windowSeconds": window.as_secs() })
+            }
+            Self::CapacityExhausted { instances, max } => {
+                serde_json::json!({ "instances": instances, "max": max })
+            }
+            Self::NodeDraining { sessions } => serde_json::json!({ "sessions": sessions }),
+            Self::InstanceShutdown { pid } => serde_json::json!({ "pid": pid }),
+            Self::SessionOpened { id, tenant } => {
+                serde_json::json!({ "id": id, "tenant": tenant })
+            }
+            Self::SessionClosed {
+                id,
+                tenant,
+                duration,
+            } => serde_json::json!({
+                "id": id,
+                "tenant": tenant,
+                "durationMs": duration.as_millis() as u64,
+            }),
+        };
+
+        payload["type"] = self.name().into();
+        payload["timestamp"] = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64)
+            .into();
+
+        payload
+    }
+
+    /// The event as a server-sent event.
+    fn to_sse(&self) -> Bytes {
+        Bytes::from(format!(
+            "event: {}\ndata: {}\n\n",
+            self.name(),
+            self.to_json()
+        ))
+    }
+}
+
+lazy_static::lazy_static! {
+    /// The sender of the lifecycle events.
+    static ref EVENTS: broadcast::Sender<Event> = broadcast::channel(CAPACITY).0;
+}
+
+/// Subscribe to the lifecycle events. Receivers more than 1024 events behind skip the oldest.
+pub fn subscribe() -> broadcast::Receiver<Event> {
+    EVENTS.subscribe()
+}
+
+/// Publish a lifecycle event to the subscribers.
+pub(crate) fn emit(event: Event) {
+    tracing::debug!("Lifecycle event {}", event.name());
+    let _ = EVENTS.send(event);
+}
+
+/// A proxied session published as opened until dropped.
+pub(crate) struct SessionEvents {
+    /// The session id.
+    id: String,
+    /// The tenant of the bearer token.
+    tenant: Option<String>,
+    /// When the session opened.
+    started: std::time::Instant,
+}
+
+impl SessionEvents {
+    /// Publish the session as opened.
+    pub fn open(id: &str, tenant: Option<&str>) -> Self {
+        let session = Self {
+            id: id.to_string(),
+            tenant: tenant.map(str::to_string),
+            started: std::time::Instant::now(),
+        };
+
+        emit(Event::SessionOpened {
+            id: session.id.clone(),
+            tenant: session.tenant.clone(),
+        });
+
+        session
+    }
+}
+
+impl Drop for SessionEvents {
+    fn drop(&mut self) {
+        emit(Event::SessionClosed {
+            id: std::mem::take(&mut self.id),
+            tenant: self.tenant.take(),
+            duration: self.started.elapsed(),
+        });
+    }
+}
+
+/// A server-sent event stream of the lifecycle events for `GET /events`. The subscription ends
+/// when the body is dropped.
+pub(crate) fn event_stream() -> ChannelBody {
+    let mut events = subscribe();
+    let (sender, body) = ChannelBody::channel(16);
+
+    tokio::spawn(async move {
+        // flush the headers to the client before the first event.
+        if sender
+            .send(Ok(Bytes::from_static(b": connected\n\n")))
+            .await
+            .is_err()
+        {
+            return;
+        }
+
+        loop {
+            let message = match tokio::time::timeout(KEEP_ALIVE, events.recv()).await {
+                Ok(Ok(event)) => event.to_sse(),
+                Ok(Err(broadcast::error::RecvError::Lagged(skipped))) => {
+                    Bytes::from(format!(": skipped {} events\n\n", skipped))
+                }
+                Ok(Err(broadcast::error::RecvError::Closed)) => return,
+                Err(_) => Bytes::from_static(b": keep-alive\n\n"),
+            };
+
+            if sender.send(Ok(message)).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    #[tokio::test]
+    async fn test_event_stream() {
+        let mut events = subscribe();
+        let mut stream = event_stream();
+
+        let connected = stream.frame().await.expect("frame").expect("data");
+        assert_eq!(connected.into_data().expect("data"), ": connected\n\n");
+
+        emit(Event::InstanceCrashed {
+            pid: u32::MAX - 9,
+            code: None,
+            signal: Some(9),
+        });
+
+        assert_eq!(
+            events.recv().await.expect("event"),
+            Event::InstanceCrashed {
+                pid: u32::MAX - 9,
+                code: None,
+                signal: Some(9),
+            }
+        );
+
+        // other tests can emit events concurrently.
+        loop {
+            let frame = stream.frame().await.expect("frame").expect("data");
+            let message =
+                String::from_utf8(frame.into_data().expect("data").to_vec()).expect("utf8");
+
+            if message.starts_with("event: instance.crashed\n") {
+                let data = message
+                    .trim_end()
+                    .split_once("data: ")
+                    .map(|(_, data)| serde_json::from_str::<Value>(data).expect("json"))
+                    .expect("data line");
+
+                assert_eq!(data["type"], "instance.crashed");
+                assert_eq!(data["pid"], u32::MAX - 9);
+                assert_eq!(data["signal"], 9);
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,421 @@
+use crate::conf::{CHROME_INSTANCES, CRASH_LOOP_THRESHOLD, CRASH_LOOP_WINDOW, MAX_INSTANCES};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::process::{Child, ExitStatus};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use sysinfo::{Pid, ProcessStatus, ProcessesToUpdate, System};
+
+/// How a chrome instance was launched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum InstanceKind {
+    /// Forked on startup, by the control API, or by a restart.
+    Fork,
+    /// Launched for a single proxied session with client launch options.
+    Dedicated,
+}
+
+impl InstanceKind {
+    /// The name of the kind.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Fork => "fork",
+            Self::Dedicated => "dedicated",
+        }
+    }
+}
+
+/// The metadata of a launched chrome instance.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct InstanceInfo {
+    /// The process id.
+    pub pid: u32,
+    /// The remote debugging port.
+    pub port: Option<u32>,
+    /// How the instance was launched.
+    pub kind: InstanceKind,
+    /// The launch time in unix seconds.
+    pub started_at: u64,
+    /// The browser name picked by the fork request.
+    pub browser: Option<String>,
+    /// The profile name picked by the fork request.
+    pub profile: Option<String>,
+    /// The fork options to launch a replacement with.
+    pub options: crate::launch::ForkOptions,
+    /// Did the last canary check pass.
+    pub healthy: bool,
+}
+
+impl InstanceInfo {
+    /// The metadata of an instance launched now.
+    pub fn new(pid: u32, port: Option<u32>, kind: InstanceKind) -> Self {
+        Self {
+            pid,
+            port,
+            kind,
+            started_at: now(),
+            browser: None,
+            profile: None,
+            options: Default::default(),
+            healthy: true,
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    /// The metadata of the instances in `CHROME_INSTANCES` by process id.
+    static ref INSTANCES: dashmap::DashMap<u32, InstanceInfo> = dashmap::DashMap::new();
+    /// The spawned processes waiting to be reaped by process id.
+    static ref CHILDREN: dashmap::DashMap<u32, Child> = dashmap::DashMap::new();
+    /// The recent crash times and when the tripped crash loop breaker resets.
+    static ref CRASH_LOOP: Mutex<(VecDeque<Instant>, Option<Instant>)> = Mutex::new((VecDeque::new(), None));
+}
+
+/// The current unix time in seconds.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// The remote debugging port of the chrome args.
+pub(crate) fn debugging_port(chrome_args: &[String]) -> Option<u32> {
+    chrome_args
+        .iter()
+        .rev()
+        .find_map(|arg| arg.strip_prefix("--remote-debugging-port="))
+        .and_then(|port| port.parse().ok())
+}
+
+/// Keep the spawned process to reap it and read the exit status. Returns the process id.
+pub(crate) fn adopt(child: Child) -> u32 {
+    let pid = child.id();
+    CHILDREN.insert(pid, child);
+    pid
+}
+
+/// Reap the spawned processes that exited.
+fn reap() -> Vec<(u32, ExitStatus)> {
+    let pids = CHILDREN
+        .iter()
+        .map(|child| *child.key())
+        .collect::<Vec<_>>();
+    let mut exited = Vec::new();
+
+    for pid in pids {
+        let status = CHILDREN
+            .get_mut(&pid)
+            .and_then(|mut child| child.try_wait().ok().flatten());
+
+        if let Some(status) = status {
+            CHILDREN.remove(&pid);
+            exited.push((pid, status));
+        }
+    }
+
+    exited
+}
+
+/// Count a crash. Returns the crashes within the window when they trip the crash loop breaker.
+fn record_crash(now: Instant) -> Option<usize> {
+    let mut crash_loop = CRASH_LOOP.lock().ok()?;
+    let (crashes, tripped_until) = &mut *crash_loop;
+
+    crashes.push_back(now);
+
+    while crashes
+        .front()
+        .is_some_and(|crashed| now.duration_since(*crashed) > *CRASH_LOOP_WINDOW)
+    {
+        crashes.pop_front();
+    }
+
+    let tripped = tripped_until.is_some_and(|until| now < until);
+
+    if !tripped && crashes.len() >= *CRASH_LOOP_THRESHOLD {
+        *tripped_until = Some(now + *CRASH_LOOP_WINDOW);
+        Some(crashes.len())
+    } else {
+        None
+    }
+}
+
+/// Is the crash loop breaker tripped. Restarts and recycling are paused until it resets.
+pub(crate) fn crash_loop_tripped() -> bool {
+    CRASH_LOOP
+        .lock()
+        .is_ok_and(|crash_loop| crash_loop.1.is_some_and(|until| Instant::now() < until))
+}
+
+/// Is there capacity for another instance under `MAX_INSTANCES`. Publishes the capacity as
+/// exhausted when there is not.
+pub(crate) fn has_capacity() -> bool {
+    let instances = CHROME_INSTANCES.len();
+
+    if *MAX_INSTANCES == 0 || instances < *MAX_INSTANCES {
+        return true;
+    }
+
+    tracing::warn!("Rejected a launch at {} instances", instances);
+    crate::events::emit(crate::events::Event::CapacityExhausted {
+        instances,
+        max: *MAX_INSTANCES,
+    });
+
+    false
+}
+
+/// Reap the exited processes every second. Tracked instances that exit without a shutdown are
+/// counted as crashed and untracked.
+pub(crate) async fn monitor() {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        interval.tick().await;
+
+        for (pid, status) in reap() {
+            if unregister(pid) {
+                crate::metrics::CRASHES.inc();
+                tracing::warn!("Chrome {} exited unexpectedly with {}", pid, status);
+
+                #[cfg(unix)]
+                let signal = std::os::unix::process::ExitStatusExt::signal(&status);
+                #[cfg(not(unix))]
+                let signal = None;
+
+                crate::events::emit(crate::events::Event::InstanceCrashed {
+                    pid,
+                    code: status.code(),
+                    signal,
+                });
+
+                if let Some(crashes) = record_crash(Instant::now()) {
+                    tracing::error!(
+                        "Crash loop of {} crashes within {:?}. Pausing restarts.",
+                        crashes,
+                        *CRASH_LOOP_WINDOW
+                    );
+                    crate::events::emit(crate::events::Event::CrashLoopTripped {
+                        crashes,
+                        window: *CRASH_LOOP_WINDOW,
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Track a launched instance.
+pub(crate) fn register(info: InstanceInfo) {
+    match info.kind {
+        InstanceKind::Fork => crate::metrics::FORKS.inc(),
+        InstanceKind::Dedicated => crate::metrics::DEDICATED_LAUNCHES.inc(),
+    }
+
+    crate::events::emit(crate::events::Event::InstanceForked {
+        pid: info.pid,
+        port: info.port,
+        kind: info.kind.as_str(),
+    });
+
+    CHROME_INSTANCES.insert(info.pid);
+    INSTANCES.insert(info.pid, info);
+}
+
+/// Set the remote debugging port of an instance picked by chrome.
+pub(crate) fn set_port(pid: u32, port: u32) {
+    if let Some(mut info) = INSTANCES.get_mut(&pid) {
+        info.port = Some(port);
+    }
+}
+
+/// Stop tracking an instance. Returns false when the instance was not tracked.
+pub(crate) fn unregister(pid: u32) -> bool {
+    INSTANCES.remove(&pid);
+    CHROME_INSTANCES.remove(&pid).is_some()
+}
+
+/// Stop tracking all the instances.
+pub(crate) fn clear() {
+    INSTANCES.clear();
+    CHROME_INSTANCES.clear();
+}
+
+/// Is the instance tracked.
+pub(crate) fn contains(pid: u32) -> bool {
+    CHROME_INSTANCES.contains(&pid)
+}
+
+/// The remote debugging ports of the tracked instances by process id.
+pub(crate) fn ports() -> Vec<(u32, Option<u32>)> {
+    let mut ports = CHROME_INSTANCES
+        .iter()
+        .map(|pid| (*pid, INSTANCES.get(&pid).and_then(|info| info.port)))
+        .collect::<Vec<_>>();
+    ports.sort_unstable();
+    ports
+}
+
+/// The metadata of a tracked instance.
+pub(crate) fn info(pid: u32) -> Option<InstanceInfo> {
+    INSTANCES.get(&pid).map(|info| info.clone())
+}
+
+/// Mark the health of a tracked instance from a canary check. Returns the previous health.
+pub(crate) fn set_healthy(pid: u32, healthy: bool) -> bool {
+    INSTANCES
+        .get_mut(&pid)
+        .is_none_or(|mut info| std::mem::replace(&mut info.healthy, healthy))
+}
+
+/// Is the named profile used by a tracked instance.
+pub(crate) fn profile_in_use(profile: &str) -> bool {
+    INSTANCES
+        .iter()
+        .any(|info| info.profile.as_deref() == Some(profile))
+}
+
+/// The json metadata of an instance with the process state.
+fn to_json(pid: u32, system: &System) -> Value {
+    let info = INSTANCES.get(&pid).map(|info| info.clone());
+    let now = now();
+
+    let running = system
+        .process(Pid::from_u32(pid))
+        .is_some_and(|process| process.status() != ProcessStatus::Zombie);
+
+    serde_json::json!({
+        "id": pid,
+        "pid": pid,
+        "port": info.as_ref().and_then(|info| info.port),
+        "kind": info.as_ref().map(|info| info.kind.as_str()),
+        "browser": info.as_ref().and_then(|info| info.browser.clone()),
+        "profile": info.as_ref().and_then(|info| info.profile.clone()),
+        "startedAt": info.as_ref().map(|info| info.started_at),
+        "uptime": info.as_ref().map(|info| now.saturating_sub(info.started_at)),
+        "running": running,
+        "healthy": info.as_ref().is_none_or(|info| info.healthy),
+    })
+}
+
+/// The process state of the pids.
+fn processes(pids: &[u32]) -> System {
+    let pids = pids
+        .iter()
+        .map(|pid| Pid::from_u32(*pid))
+        .collect::<Vec<_>>();
+    let mut system = System::new();
+
+    system.refresh_processes(ProcessesToUpdate::Some(&pids), true);
+    system
+}
+
+/// The resource usage of a tracked instance.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct InstanceUsage {
+    /// The process id.
+    pub pid: u32,
+    /// How the instance was launched.
+    pub kind: &'static str,
+    /// Is the process running.
+    pub running: bool,
+    /// The resident memory in bytes of the process and its children.
+    pub memory: u64,
+    /// The cpu time in milliseconds of the process and its children.
+    pub cpu_time: u64,
+}
+
+/// The resource usage of the tracked instances including the renderer and gpu processes. The
+/// process table is sampled at most every 5 seconds since a refresh walks every process.
+#[cached::proc_macro::once(sync_writes = true, time = 5)]
+pub(crate) fn usage() -> Vec<InstanceUsage> {
+    let mut system = System::new();
+    system.refresh_processes(ProcessesToUpdate::All, true);
+
+    let mut children = std::collections::HashMap::<Pid, Vec<Pid>>::new();
+
+    for (pid, process) in system.processes() {
+        if let Some(parent) = process.parent() {
+            children.entry(parent).or_default().push(*pid);
+        }
+    }
+
+    let mut pids = CHROME_INSTANCES.iter().map(|pid| *pid).collect::<Vec<_>>();
+    pids.sort_unstable();
+
+    pids.into_iter()
+        .map(|pid| {
+            let kind = INSTANCES
+                .get(&pid)
+                .map_or("fork", |info| info.kind.as_str());
+            let mut usage = InstanceUsage {
+                pid,
+                kind,
+                running: false,
+                memory: 0,
+                cpu_time: 0,
+            };
+
+            let mut stack = vec![Pid::from_u32(pid)];
+
+            while let Some(pid) = stack.pop() {
+                if let Some(process) = system.process(pid) {
+                    if process.status() == ProcessStatus::Zombie {
+                        continue;
+                    }
+                    usage.running = true;
+                    usage.memory += process.memory();
+                    usage.cpu_time += process.accumulated_cpu_time();
+                }
+                if let Some(children) = children.get(&pid) {
+                    stack.extend(children);
+                }
+            }
+
+            usage
+        })
+        .collect()
+}
+
+/// The json metadata of the tracked instances ordered by process id.
+pub(crate) fn list() -> Vec<Value> {
+    let mut pids = CHROME_INSTANCES.iter().map(|pid| *pid).collect::<Vec<_>>();
+    pids.sort_unstable();
+
+    let system = processes(&pids);
+
+    pids.into_iter().map(|pid| to_json(pid, &system)).collect()
+}
+
+/// The json metadata of a tracked instance.
+pub(crate) fn get(pid: u32) -> Option<Value> {
+    contains(pid).then(|| to_json(pid, &processes(&[pid])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instances() {
+        let args = [
+            "--headless".to_string(),
+            "--remote-debugging-port=9333".into(),
+        ];
+        assert_eq!(debugging_port(&args), Some(9333));
+
+        // a pid that is never running so shutting down the instances cannot kill anything.
+        let pid = u32::MAX - 7;
+        register(InstanceInfo::new(pid, Some(9333), InstanceKind::Dedicated));
+
+        let instance = get(pid).expect("tracked instance");
+        assert_eq!(instance["port"], 9333);
+        assert_eq!(instance["kind"], "dedicated");
+        assert_eq!(instance["running"], false);
+        assert!(list().iter().any(|instance| instance["pid"] == pid));
+
+        assert!(unregister(pid));
+        assert!(!unregister(pid));
+        assert!(get(pid).is_none());
+    }
+}
@@ -0,0 +1,511 @@
+use crate::conf::{
+    CHROME_RESTART_TIMEOUT, FORK_ALLOWED_ARGS, FORK_ALLOWED_ENV, FORK_BROWSERS, FORK_PROFILE_DIR,
+    LIGHT_PANDA,
+};
+use crate::instances::{self, InstanceInfo, InstanceKind};
+use crate::ws::RequestHead;
+use crate::{chrome_args, shutdown, spawn_chrome};
+use serde_json::Value;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// The websocket query params that map to chrome launch args.
+pub(crate) const LAUNCH_PARAMS: [&str; 6] = [
+    "headless",
+    "window-size",
+    "proxy-server",
+    "stealth",
+    "lang",
+    "user-agent",
+];
+
+/// An invalid launch option.
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
+
+/// The name of a chrome arg without the value, ex: `--window-size`.
+fn arg_name(arg: &str) -> &str {
+    arg.split_once('=').map_or(arg, |(name, _)| name)
+}
+
+/// Is the value a `width,height` window size, ex: `1920,1080`.
+fn valid_window_size(value: &str) -> bool {
+    value.split_once(',').is_some_and(|(width, height)| {
+        width.parse::<u16>().is_ok() && height.parse::<u16>().is_ok()
+    })
+}
+
+/// Chrome launch options requested by a client, ex: `?headless=false&window-size=1920,1080`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct LaunchOptions {
+    /// Run headless. None keeps the shared instance mode.
+    headless: Option<bool>,
+    /// The args replacing the shared args of the same name.
+    args: Vec<String>,
+}
+
+impl LaunchOptions {
+    /// Parse the allowed launch params of the upgrade request. Returns None without launch params.
+    pub fn from_request(request: &RequestHead) -> std::io::Result<Option<Self>> {
+        let mut options = Self::default();
+        let mut found = false;
+
+        for (key, value) in request.query_params() {
+            if !LAUNCH_PARAMS.contains(&key.as_str()) {
+                continue;
+            }
+
+            found = true;
+
+            let valid_value = !value.is_empty() && !value.chars().any(char::is_whitespace);
+
+            match key.as_str() {
+                "headless" => match value.as_str() {
+                    "true" | "1" => options.headless = Some(true),
+                    "false" | "0" => options.headless = Some(false),
+                    "new" => options.args.push("--headless=new".into()),
+                    _ => return Err(invalid(format!("invalid headless value: {}", value))),
+                },
+                "window-size" => {
+                    if !valid_window_size(&value) {
+                        return Err(invalid(format!("invalid window-size value: {}", value)));
+                    }
+                    options.args.push(format!("--window-size={}", value));
+                }
+                "stealth" => match value.as_str() {
+                    "true" | "1" => options
+                        .args
+                        .push("--disable-blink-features=AutomationControlled".into()),
+                    "false" | "0" => (),
+                    _ => return Err(invalid(format!("invalid stealth value: {}", value))),
+                },
+                "user-agent" if !value.is_empty() && !value.contains(['\r', '\n']) => {
+                    options.args.push(format!("--user-agent={}", value));
+                }
+                _ if valid_value => options.args.push(format!("--{}={}", key, value)),
+                _ => return Err(invalid(format!("invalid {} value: {}", key, value))),
+            }
+        }
+
+        Ok(found.then_some(options))
+    }
+
+    /// Merge the options into the chrome args.
+    pub fn apply(&self, mut chrome_args: Vec<String>) -> Vec<String> {
+        match self.headless {
+            Some(false) => chrome_args.retain(|arg| arg_name(arg) != "--headless"),
+            Some(true) if !chrome_args.iter().any(|arg| arg_name(arg) == "--headless") => {
+                chrome_args.push("--headless=new".into())
+            }
+            _ => (),
+        }
+
+        for arg in self.args.iter() {
+            let name = arg_name(arg);
+            chrome_args.retain(|current| arg_name(current) != name);
+            chrome_args.push(arg.clone());
+        }
+
+        chrome_args
+    }
+
+    /// The options match the args of the shared instance.
+    pub fn is_shared(&self) -> bool {
+        let shared = chrome_args(None);
+        self.apply(shared.clone()) == shared
+    }
+}
+
+/// The options of a fork request validated against the fork allowlist, ex:
+/// `{"port":9333,"headless":false,"window_size":"1920,1080","args":["--lang=de"],"remove_args":["--disable-gpu"],"env":{"TZ":"Europe/Berlin"},"browser":"brave","profile":"acme"}`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct ForkOptions {
+    /// The remote debugging port.
+    pub port: Option<u32>,
+    /// The headless mode, window size, and added args.
+    launch: LaunchOptions,
+    /// The arg names removed from the shared args.
+    remove_args: Vec<String>,
+    /// The environment variables of the process.
+    pub env: Vec<(String, String)>,
+    /// The browser name and binary path from `FORK_BROWSERS`.
+    pub browser: Option<(String, String)>,
+    /// The profile name kept in `FORK_PROFILE_DIR`.
+    pub profile: Option<String>,
+}
+
+impl ForkOptions {
+    /// Parse and validate the options of a json body. An empty body forks with the shared args.
+    pub fn from_json(body: &[u8]) -> Result<Self, String> {
+        let mut options = Self::default();
+
+        if body.iter().all(u8::is_ascii_whitespace) {
+            return Ok(options);
+        }
+
+        let json = match serde_json::from_slice::<Value>(body).map_err(|e| e.to_string())? {
+            Value::Object(json) => json,
+            _ => return Err("the body must be a json object".into()),
+        };
+
+        let strings = |key: &str, value: &Value| -> Result<Vec<String>, String> {
+            value
+                .as_array()
+                .and_then(|values| {
+                    values
+                        .iter()
+                        .map(|value| value.as_str().map(String::from))
+                        .collect()
+                })
+                .ok_or_else(|| format!("{} must be an array of strings", key))
+        };
+
+        let allowed_arg = |arg: &str| -> Result<(), String> {
+            let name = arg_name(arg);
+            if !FORK_ALLOWED_ARGS.iter().any(|allowed| allowed == name) {
+                return Err(format!("arg {} is not allowed", name));
+            }
+            if arg.contains(['\r', '\n', '\0']) {
+                return Err(format!("invalid arg: {}", name));
+            }
+            Ok(())
+        };
+
+        for (key, value) in json.iter() {
+            match (key.as_str(), value) {
+                (_, Value::Null) => (),
+                ("port", value) => match value.as_u64().and_then(|port| u16::try_from(port).ok()) {
+                    Some(port) if port > 0 => options.port = Some(port.into()),
+                    _ => return Err("port must be between 1 and 65535".into()),
+                },
+                ("headless", Value::Bool(headless)) => options.launch.headless = Some(*headless),
+                ("window_size", Value::String(size)) if valid_window_size(size) => {
+                    options.launch.args.push(format!("--window-size={}", size))
+                }
+                ("args", value) => {
+                    for arg in strings(key, value)? {
+                        allowed_arg(&arg)?;
+                        options.launch.args.push(arg);
+                    }
+                }
+                ("remove_args", value) => {
+                    for arg in strings(key, value)? {
+                        allowed_arg(&arg)?;
+                        options.remove_args.push(arg_name(&arg).into());
+                    }
+                }
+                ("env", Value::Object(env)) => {
+                    for (name, value) in env.iter() {
+                        if !FORK_ALLOWED_ENV.contains(name) {
+                            return Err(format!("env {} is not allowed", name));
+                        }
+                        match value.as_str() {
+                            Some(value) if !value.contains('\0') => {
+                                options.env.push((name.clone(), value.into()))
+                            }
+                            _ => return Err(format!("invalid env {} value", name)),
+                        }
+                    }
+                }
+                ("browser", Value::String(name)) => {
+                    options.browser = Some(
+                        FORK_BROWSERS
+                            .iter()
+                            .find(|(browser, _)| browser == name)
+                            .cloned()
+                            .ok_or_else(|| format!("browser {} is not allowed", name))?,
+                    );
+                }
+                ("profile", Value::String(name)) => {
+                    if FORK_PROFILE_DIR.is_empty() {
+                        return Err("profiles are not enabled".into());
+                    }
+                    let valid = !name.is_empty()
+                        && name.len() <= 64
+                        && name
+                            .bytes()
+                            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+                    if !valid {
+                        return Err(format!("invalid profile name: {}", name));
+                    }
+                    options.profile = Some(name.clone());
+                }
+                ("headless" | "window_size" | "env" | "browser" | "profile", _) => {
+                    return Err(format!("invalid {} value", key))
+                }
+                _ => return Err(format!("unknown option {}", key)),
+            }
+        }
+
+        if *LIGHT_PANDA
+            && options
+                != (Self {
+                    port: options.port,
+                    ..Default::default()
+                })
+        {
+            return Err("fork options are not supported by lightpanda".into());
+        }
+
+        Ok(options)
+    }
+
+    /// The launch option names used for the tenant claims, ex: `["headless", "lang", "profile"]`.
+    pub fn launch_params(&self) -> Vec<String> {
+        let mut params = Vec::new();
+
+        if self.launch.headless.is_some() {
+            params.push("headless".to_string());
+        }
+
+        let args = self.launch.args.iter().map(|arg| arg_name(arg));
+
+        for name in args.chain(self.remove_args.iter().map(String::as_str)) {
+            params.push(name.trim_start_matches('-').to_string());
+        }
+        if !self.env.is_empty() {
+            params.push("env".into());
+        }
+        if self.browser.is_some() {
+            params.push("browser".into());
+        }
+        if self.profile.is_some() {
+            params.push("profile".into());
+        }
+
+        params.sort();
+        params.dedup();
+        params
+    }
+
+    /// The directory of the named profile.
+    pub fn profile_dir(&self) -> Option<std::path::PathBuf> {
+        self.profile
+            .as_ref()
+            .map(|profile| std::path::Path::new(FORK_PROFILE_DIR.as_str()).join(profile))
+    }
+
+    /// Merge the options into the chrome args.
+    pub fn apply(&self, chrome_args: Vec<String>) -> Vec<String> {
+        let mut chrome_args = self.launch.apply(chrome_args);
+
+        chrome_args.retain(|arg| !self.remove_args.iter().any(|name| name == arg_name(arg)));
+
+        if let Some(profile_dir) = self.profile_dir() {
+            chrome_args.retain(|arg| arg_name(arg) != "--user-data-dir");
+            chrome_args.push(format!("--user-data-dir={}", profile_dir.display()));
+        }
+
+        chrome_args
+    }
+}
+
+/// A chrome instance launched for a single session. The process and its profile are removed on
+/// drop.
+pub(crate) struct DedicatedInstance {
+    /// The process id.
+    pid: u32,
+    /// The profile directory.
+    user_data_dir: std::path::PathBuf,
+    /// The debugging address, ex: `127.0.0.1:40123`.
+    pub address: String,
+    /// The browser websocket path, ex: `/devtools/browser/{id}`.
+    pub browser_path: String,
+}
+
+impl DedicatedInstance {
+    /// Launch chrome with the options on a port picked by chrome and wait until it accepts
+    /// connections. Each launch gets its own temporary profile.
+    pub async fn launch(options: &LaunchOptions) -> std::io::Result<Self> {
+        if *LIGHT_PANDA {
+            return Err(invalid(
+                "launch options are not supported by lightpanda".into(),
+            ));
+        }
+
+        let user_data_dir =
+            std::env::temp_dir().join(format!("headless-browser-{:032x}", rand::random::<u128>()));
+
+        std::fs::create_dir(&user_data_dir)?;
+
+        let mut launch_args = options.apply(chrome_args(Some(0)));
+        launch_args.retain(|arg| arg_name(arg) != "--user-data-dir");
+        launch_args.push(format!("--user-data-dir={}", user_data_dir.display()));
+
+        let pid = spawn_chrome(&launch_args);
+
+        if pid == 0 {
+            let _ = std::fs::remove_dir_all(&user_data_dir);
+            return Err(std::io::Error::other("chrome did not start"));
+        }
+
+        instances::register(InstanceInfo::new(pid, None, InstanceKind::Dedicated));
+
+        let mut instance = Self {
+            pid,
+            user_data_dir,
+            address: String::new(),
+            browser_path: String::new(),
+        };
+
+        let deadline = Instant::now() + *CHROME_RESTART_TIMEOUT;
+
+        while Instant::now() < deadline {
+            if let Some((port, browser_path)) = active_port(&instance.user_data_dir) {
+                tracing::info!("Launched dedicated chrome {} on port {}", pid, port);
+                instances::set_port(pid, port);
+                instance.address = format!("127.0.0.1:{}", port);
+                instance.browser_path = browser_path;
+                return Ok(instance);
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "dedicated chrome was not ready before the timeout",
+        ))
+    }
+}
+
+impl Drop for DedicatedInstance {
+    fn drop(&mut self) {
+        shutdown(&self.pid);
+        instances::unregister(self.pid);
+        crate::events::emit(crate::events::Event::InstanceShutdown { pid: self.pid });
+        tracing::info!("Shutdown dedicated chrome {}", self.pid);
+
+        let user_data_dir = std::mem::take(&mut self.user_data_dir);
+
+        // the profile is locked until the process exits.
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let _ = std::fs::remove_dir_all(user_data_dir);
+            });
+        }
+    }
+}
+
+/// The port and browser websocket path chrome writes to the `DevToolsActivePort` file of the
+/// profile once it accepts connections.
+fn active_port(user_data_dir: &std::path::Path) -> Option<(u32, String)> {
+    let file = std::fs::read_to_string(user_data_dir.join("DevToolsActivePort")).ok()?;
+    let mut lines = file.lines();
+
+    let port = lines.next()?.trim().parse().ok()?;
+    let browser_path = lines.next()?.trim();
+
+    browser_path
+        .starts_with("/devtools/browser/")
+        .then(|| (port, browser_path.to_string()))
+}
+
+/// Get the browser websocket path from `/json/version` of the chrome address.
+pub(crate) async fn browser_path(address: &str) -> std::io::Result<String> {
+    let mut stream = TcpStream::connect(address).await?;
+
+    stream
+        .write_all(
+            format!(
+                "GET /json/version HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                address
+            )
+            .as_bytes(),
+        )
+        .await?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+
+    let body = crate::ws::find_head_end(&response).map_or(&response[..0], |end| &response[end..]);
+
+    serde_json::from_slice::<Value>(body)
+        .ok()
+        .as_ref()
+        .and_then(|version| version.get("webSocketDebuggerUrl"))
+        .and_then(Value::as_str)
+        .and_then(|url| url.find("/devtools/").map(|start| url[start..].to_string()))
+        .ok_or_else(|| std::io::Error::other("missing webSocketDebuggerUrl"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_launch_options() {
+        let head = b"GET /?headless=false&window-size=1920,1080&record=true HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let request = RequestHead::parse(head).expect("request");
+        let options = LaunchOptions::from_request(&request)
+            .expect("valid options")
+            .expect("launch options");
+
+        let chrome_args = vec![
+            "--remote-debugging-port=9222".to_string(),
+            "--headless".to_string(),
+            "--window-size=800,600".to_string(),
+        ];
+
+        assert_eq!(
+            options.apply(chrome_args),
+            ["--remote-debugging-port=9222", "--window-size=1920,1080"]
+        );
+
+        let head = b"GET /?window-size=wide HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let request = RequestHead::parse(head).expect("request");
+        assert!(LaunchOptions::from_request(&request).is_err());
+    }
+
+    #[test]
+    fn test_fork_options() {
+        let options =
+            ForkOptions::from_json(br#"{"port":9333,"headless":false,"window_size":"1280,720"}"#)
+                .expect("valid options");
+
+        assert_eq!(options.port, Some(9333));
+        assert_eq!(options.launch_params(), ["headless", "window-size"]);
+        assert_eq!(
+            options.apply(vec!["--headless".into(), "--window-size=800,600".into()]),
+            ["--window-size=1280,720"]
+        );
+
+        // nothing outside the allowlist is accepted by default.
+        for body in [
+            r#"{"args":["--no-sandbox"]}"#,
+            r#"{"env":{"LD_PRELOAD":"/tmp/x.so"}}"#,
+            r#"{"browser":"/bin/sh"}"#,
+            r#"{"profile":"../etc"}"#,
+            r#"{"window_size":"wide"}"#,
+            r#"{"binary":"/bin/sh"}"#,
+        ] {
+            assert!(ForkOptions::from_json(body.as_bytes()).is_err(), "{}", body);
+        }
+    }
+
+    #[test]
+    fn test_active_port() {
+        let user_data_dir = std::env::temp_dir().join(format!(
+            "headless-browser-test-{:032x}",
+            rand::random::<u128>()
+        ));
+        std::fs::create_dir(&user_data_dir).expect("profile");
+
+        assert_eq!(active_port(&user_data_dir), None);
+
+        std::fs::write(
+            user_data_dir.join("DevToolsActivePort"),
+            "40123\n/devtools/browser/abc\n",
+        )
+        .expect("write");
+
+        assert_eq!(
+            active_port(&user_data_dir),
+            Some((40123, "/devtools/browser/abc".into()))
+        );
+
+        let _ = std::fs::remove_dir_all(user_data_dir);
+    }
+}
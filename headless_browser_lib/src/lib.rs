@@ -1,21 +1,59 @@
 use cached::proc_macro::once;
 
+/// JWT bearer auth with tenant claims.
+mod auth;
+/// Streaming response bodies.
+mod body;
+/// Canary page checks recycling unhealthy instances.
+mod canary;
+/// Minimal CDP client for manager commands.
+mod cdp;
 /// Chrome configuration.
 pub mod conf;
+/// Lifecycle events of the instances and sessions.
+pub mod events;
+/// Chrome instance metadata for the control API.
+mod instances;
+/// Dedicated chrome instances for client launch options.
+mod launch;
+/// Tcp and unix socket listeners.
+pub mod listener;
+/// Prometheus metrics of the instances and proxied sessions.
+mod metrics;
 /// Chrome json modifiers.
 mod modify;
+/// CDP readiness probes of the instances.
+mod probe;
 /// Proxy forwarder TCP to chrome instances.
 pub mod proxy;
+/// HAProxy PROXY protocol header parsing.
+mod proxy_protocol;
+/// CDP traffic recorder.
+pub mod record;
+/// Screenshots of pages driven over CDP.
+mod render;
 /// Chrome renderer configuration.
 mod render_conf;
+/// Offline CDP replay server built from recordings.
+pub mod replay;
+/// CDP aware inspection of proxied sessions.
+mod session;
+/// Signed expiring websocket session urls.
+mod signing;
+/// TLS termination for the control server and proxy.
+mod tls;
+/// Signed webhook deliveries of the lifecycle events.
+mod webhooks;
+/// Websocket handshake and frame helpers.
+mod ws;
 
 use conf::{
     CACHEABLE, CHROME_ADDRESS, CHROME_ARGS, CHROME_INSTANCES, CHROME_PATH, DEBUG_JSON,
-    DEFAULT_PORT, DEFAULT_PORT_SERVER, ENDPOINT, HOST_NAME, IS_HEALTHY, LAST_CACHE,
-    LIGHTPANDA_ARGS, LIGHT_PANDA, TARGET_REPLACEMENT,
+    DEFAULT_PORT, DRAINING, DRAIN_TIMEOUT, ENDPOINT, ENDPOINT_BASE, IS_HEALTHY, LAST_CACHE,
+    LIGHTPANDA_ARGS, LIGHT_PANDA, SERVER_LISTEN, SINGLE_PORT, TARGET_REPLACEMENT,
 };
 use core::sync::atomic::Ordering;
-use http_body_util::Full;
+use http_body_util::{Either, Full};
 use hyper::{
     body::{Bytes, Incoming},
     server::conn::http1,
@@ -23,17 +61,40 @@ use hyper::{
     Method, Request, Response, StatusCode,
 };
 use hyper_util::rt::TokioIo;
+use listener::Listener;
 use std::convert::Infallible;
 use std::net::SocketAddr;
 use std::process::Command;
-use tokio::{
-    net::{TcpListener, TcpStream},
-    signal,
-};
+use tokio::{net::TcpStream, signal};
 
 use std::time::Duration;
 use tokio::time::{sleep, timeout};
 
+/// The client of a control server or proxy connection.
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    /// The real client address. Resolved from the PROXY protocol header when enabled and empty
+    /// for unix socket connections.
+    pub addr: Option<SocketAddr>,
+    /// The verified client certificate identity when mutual TLS is enabled. The first URI, DNS,
+    /// or email SAN, falling back to the subject common name. Keys the tenant limits when JWT
+    /// auth is disabled.
+    pub identity: Option<String>,
+}
+
+impl std::fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.addr {
+            Some(addr) => write!(f, "{}", addr)?,
+            _ => write!(f, "unix socket")?,
+        }
+        match self.identity.as_ref() {
+            Some(identity) => write!(f, " ({})", identity),
+            _ => Ok(()),
+        }
+    }
+}
+
 /// Empty default response without a 'webSocketDebuggerUrl'.
 const EMPTY_RESPONSE: Bytes = Bytes::from_static(
     br#"{
@@ -46,6 +107,10 @@ const EMPTY_RESPONSE: Bytes = Bytes::from_static(
 }"#,
 );
 
+/// The response body of the control routes. The event stream stays open until the client
+/// disconnects and PDFs are streamed as chrome prints them.
+type ControlBody = Either<Full<Bytes>, body::ChannelBody>;
+
 /// Attempt the connection.
 async fn connect_with_retries(address: &str) -> Option<TcpStream> {
     let mut attempts = 0;
@@ -81,6 +146,8 @@ async fn connect_with_retries(address: &str) -> Option<TcpStream> {
             return None;
         }
 
+        metrics::CONNECT_RETRIES.inc();
+
         let rng = rand::random_range(if connection_failed {
             80..=150
         } else {
@@ -154,57 +221,60 @@ pub fn get_chrome_args_test() -> [&'static str; crate::conf::PERF_ARGS] {
     *crate::conf::CHROME_ARGS
 }
 
-/// Fork a chrome process.
-pub fn fork(port: Option<u32>) -> String {
-    let chrome_args = get_env_args("CHROME_ARGS");
-
-    let id = if !*LIGHT_PANDA {
-        let mut command = Command::new(&*CHROME_PATH);
+/// The chrome args for the debugging port including the `CHROME_ARGS` env args.
+pub(crate) fn chrome_args(port: Option<u32>) -> Vec<String> {
+    let mut chrome_args = if *crate::conf::TEST_NO_ARGS {
+        get_chrome_args_test().map(|e| e.to_string()).to_vec()
+    } else {
+        CHROME_ARGS.map(|e| e.to_string()).to_vec()
+    };
 
-        let cmd = if *crate::conf::TEST_NO_ARGS {
-            let mut chrome_args = get_chrome_args_test().map(|e| e.to_string());
-            if !CHROME_ADDRESS.is_empty() {
-                chrome_args[0] =
-                    format!("--remote-debugging-address={}", &CHROME_ADDRESS.to_string());
-            }
-            if let Some(port) = port {
-                chrome_args[1] = format!("--remote-debugging-port={}", &port.to_string());
-            }
-            command.args(&chrome_args)
-        } else {
-            let mut chrome_args = CHROME_ARGS.map(|e| e.to_string());
+    if !CHROME_ADDRESS.is_empty() {
+        chrome_args[0] = format!("--remote-debugging-address={}", &CHROME_ADDRESS.to_string());
+    }
 
-            if !CHROME_ADDRESS.is_empty() {
-                chrome_args[0] =
-                    format!("--remote-debugging-address={}", &CHROME_ADDRESS.to_string());
-            }
+    if let Some(port) = port {
+        chrome_args[1] = format!("--remote-debugging-port={}", &port.to_string());
+    }
 
-            if let Some(port) = port {
-                chrome_args[1] = format!("--remote-debugging-port={}", &port.to_string());
-            }
+    chrome_args.extend(get_env_args("CHROME_ARGS"));
 
-            command.args(&chrome_args)
-        };
+    chrome_args
+}
 
-        let cmd = if !chrome_args.is_empty() {
-            cmd.args(chrome_args)
-        } else {
-            cmd
-        };
+/// Spawn chrome with the args returning the process id or 0 when it did not start.
+pub(crate) fn spawn_chrome(chrome_args: &[String]) -> u32 {
+    spawn_browser(&CHROME_PATH, chrome_args, &[])
+}
 
-        let id = match cmd.spawn() {
-            Ok(child) => {
-                let cid = child.id();
-                tracing::info!("Chrome PID: {}", cid);
-                cid
-            }
-            Err(e) => {
-                tracing::error!("{} command didn't start {:?}", &*CHROME_PATH, e);
-                0
-            }
-        };
+/// Spawn the browser binary with the args and environment variables returning the process id
+/// or 0 when it did not start.
+fn spawn_browser(path: &str, chrome_args: &[String], env: &[(String, String)]) -> u32 {
+    match Command::new(path)
+        .args(chrome_args)
+        .envs(env.iter().map(|(key, value)| (key, value)))
+        .spawn()
+    {
+        Ok(child) => {
+            let cid = instances::adopt(child);
+            tracing::info!("Chrome PID: {}", cid);
+            cid
+        }
+        Err(e) => {
+            tracing::error!("{} command didn't start {:?}", path, e);
+            0
+        }
+    }
+}
 
-        id
+/// Fork a chrome process.
+pub fn fork(port: Option<u32>) -> String {
+    let (id, port) = if !*LIGHT_PANDA {
+        let chrome_args = chrome_args(port);
+        (
+            spawn_chrome(&chrome_args),
+            instances::debugging_port(&chrome_args),
+        )
     } else {
         let panda_args = LIGHTPANDA_ARGS.map(|e| e.to_string());
         let mut command = Command::new(&*CHROME_PATH);
@@ -212,6 +282,7 @@ pub fn fork(port: Option<u32>) -> String {
         let host = panda_args[0].replace("--host=", "");
         let port = panda_args[1].replace("--port=", "");
         let cmd = command.args(["--port", &port, "--host", &host]);
+        let chrome_args = get_env_args("CHROME_ARGS");
 
         let cmd = if !chrome_args.is_empty() {
             cmd.args(chrome_args)
@@ -220,7 +291,7 @@ pub fn fork(port: Option<u32>) -> String {
         };
 
         let id = if let Ok(child) = cmd.spawn() {
-            let cid = child.id();
+            let cid = instances::adopt(child);
 
             tracing::info!("Chrome PID: {}", cid);
 
@@ -230,18 +301,105 @@ pub fn fork(port: Option<u32>) -> String {
             0
         };
 
-        id
+        (id, port.parse().ok())
     };
 
-    CHROME_INSTANCES.insert(id.into());
+    if id != 0 {
+        instances::register(instances::InstanceInfo::new(
+            id,
+            port,
+            instances::InstanceKind::Fork,
+        ));
+    }
 
     id.to_string()
 }
 
+/// Fork a chrome process with the options of a fork request returning the process id.
+fn fork_with(options: &launch::ForkOptions) -> std::io::Result<u32> {
+    if *options == launch::ForkOptions::default() || *LIGHT_PANDA {
+        return Ok(fork(options.port).parse().unwrap_or_default());
+    }
+
+    if let Some(profile_dir) = options.profile_dir() {
+        std::fs::create_dir_all(profile_dir)?;
+    }
+
+    let chrome_args = options.apply(chrome_args(options.port));
+    let path = options
+        .browser
+        .as_ref()
+        .map_or(CHROME_PATH.as_str(), |(_, path)| path.as_str());
+
+    let id = spawn_browser(path, &chrome_args, &options.env);
+
+    if id == 0 {
+        return Err(std::io::Error::other("chrome did not start"));
+    }
+
+    let mut info = instances::InstanceInfo::new(
+        id,
+        instances::debugging_port(&chrome_args),
+        instances::InstanceKind::Fork,
+    );
+    info.browser = options.browser.as_ref().map(|(name, _)| name.clone());
+    info.profile = options.profile.clone();
+    info.options = options.clone();
+    instances::register(info);
+
+    Ok(id)
+}
+
+/// Read the fork options of a request body checked against the tenant claims.
+async fn read_fork_options(
+    req: Request<Incoming>,
+) -> Result<launch::ForkOptions, (StatusCode, String)> {
+    let claims = req.extensions().get::<auth::Claims>().cloned();
+
+    let body = read_body(req)
+        .await
+        .ok_or((StatusCode::BAD_REQUEST, "Invalid body.".into()))?;
+    let options =
+        launch::ForkOptions::from_json(&body).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+    if let Some(claims) = claims {
+        if let Some(param) = options
+            .launch_params()
+            .into_iter()
+            .find(|param| !claims.allows_launch_option(param))
+        {
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!("launch option {} is not allowed", param),
+            ));
+        }
+    }
+
+    if let Some(profile) = options.profile.as_deref() {
+        if instances::profile_in_use(profile) {
+            return Err((
+                StatusCode::CONFLICT,
+                format!("profile {} is in use", profile),
+            ));
+        }
+    }
+
+    if !instances::has_capacity() {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "instance capacity exhausted".into(),
+        ));
+    }
+
+    Ok(options)
+}
+
 /// Get json endpoint for chrome instance proxying.
 async fn version_handler_bytes_base(endpoint_path: Option<&str>) -> Option<Bytes> {
     use http_body_util::BodyExt;
 
+    let started = std::time::Instant::now();
+
     let url = endpoint_path
         .unwrap_or(&ENDPOINT.as_str())
         .parse::<hyper::Uri>()
@@ -289,12 +447,7 @@ async fn version_handler_bytes_base(endpoint_path: Option<&str>) -> Option<Bytes
                         }
                     }
 
-                    if !HOST_NAME.is_empty() {
-                        let body = modify::modify_json_output(bytes_mut.into());
-                        Some(body)
-                    } else {
-                        Some(bytes_mut.into())
-                    }
+                    Some(bytes_mut.into())
                 }
                 _ => {
                     IS_HEALTHY.store(false, Ordering::Relaxed);
@@ -308,15 +461,39 @@ async fn version_handler_bytes_base(endpoint_path: Option<&str>) -> Option<Bytes
         None
     };
 
+    metrics::VERSION_FETCH_SECONDS.observe(started.elapsed());
+
     resp
 }
 
-/// Get json endpoint for chrome instance proxying.
+/// Get json endpoint for chrome instance proxying. Only runs on cache misses.
 #[once(option = true, sync_writes = true, time = 10)]
-async fn version_handler_bytes(endpoint_path: Option<&str>) -> Option<Bytes> {
+async fn version_handler_bytes_cached(endpoint_path: Option<&str>) -> Option<Bytes> {
+    metrics::VERSION_CACHE_MISSES.inc();
     version_handler_bytes_base(endpoint_path).await
 }
 
+/// Get the cached json endpoint for chrome instance proxying.
+async fn version_handler_bytes(endpoint_path: Option<&str>) -> Option<Bytes> {
+    metrics::VERSION_CACHE_LOOKUPS.inc();
+    version_handler_bytes_cached(endpoint_path).await
+}
+
+/// Prometheus metrics handler. The process usage is sampled off the async workers.
+async fn metrics_handler() -> Result<Response<Full<Bytes>>, Infallible> {
+    let body = tokio::task::spawn_blocking(metrics::render)
+        .await
+        .unwrap_or_default();
+    let mut resp = Response::new(Full::new(Bytes::from(body)));
+
+    resp.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+
+    Ok(resp)
+}
+
 /// Health check handler
 async fn health_check_handler() -> Result<Response<Full<Bytes>>, Infallible> {
     if IS_HEALTHY.load(Ordering::Relaxed) {
@@ -329,9 +506,66 @@ async fn health_check_handler() -> Result<Response<Full<Bytes>>, Infallible> {
     }
 }
 
-/// Fork handler.
-async fn fork_handler(port: Option<u32>) -> Result<Response<Full<Bytes>>, Infallible> {
-    let pid = fork(port);
+/// A plain text response with the status.
+fn text_response(status: StatusCode, body: &'static str) -> Response<Full<Bytes>> {
+    let mut resp = Response::new(Full::new(Bytes::from(body)));
+
+    *resp.status_mut() = status;
+
+    resp
+}
+
+/// Liveness handler. The manager process is serving requests.
+async fn livez_handler() -> Result<Response<Full<Bytes>>, Infallible> {
+    Ok(text_response(StatusCode::OK, "ok"))
+}
+
+/// Readiness handler. An instance completed a CDP round trip within `READINESS_MAX_AGE`. The
+/// `detail` query param responds with the probe of each instance as json.
+async fn readyz_handler(detail: bool) -> Result<Response<Full<Bytes>>, Infallible> {
+    let status = if probe::is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    Ok(if detail {
+        json_response(status, probe::details())
+    } else if status == StatusCode::OK {
+        text_response(status, "ready")
+    } else {
+        text_response(status, "not ready")
+    })
+}
+
+/// Fork handler. The port of the path takes precedence over the body.
+async fn fork_handler(
+    port: Option<u32>,
+    req: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let mut options = match read_fork_options(req).await {
+        Ok(options) => options,
+        Err((status, message)) => {
+            let mut resp = Response::new(Full::new(Bytes::from(message)));
+            *resp.status_mut() = status;
+            return Ok(resp);
+        }
+    };
+
+    if port.is_some() {
+        options.port = port;
+    }
+
+    let pid = match fork_with(&options) {
+        Ok(pid) if pid != 0 => pid,
+        _ => {
+            return Ok(text_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to fork chrome.",
+            ))
+        }
+    };
+
     let pid = format!("Forked process with pid: {}", pid);
 
     Ok(Response::new(Full::new(Bytes::from(pid))))
@@ -340,6 +574,7 @@ async fn fork_handler(port: Option<u32>) -> Result<Response<Full<Bytes>>, Infall
 /// Json version handler.
 async fn json_version_handler(
     endpoint_path: Option<&str>,
+    public: Option<modify::PublicAddress>,
 ) -> Result<Response<Full<Bytes>>, Infallible> {
     let mut attempts = 0;
     let mut body: Option<Bytes> = None;
@@ -370,7 +605,10 @@ async fn json_version_handler(
     }
 
     let empty = body.is_none();
-    let body = body.unwrap_or_else(|| EMPTY_RESPONSE);
+    let body = match body {
+        Some(body) => modify::modify_json_output(body, public.as_ref()),
+        _ => EMPTY_RESPONSE,
+    };
 
     if *DEBUG_JSON {
         tracing::info!("{:?}", body);
@@ -390,13 +628,454 @@ async fn json_version_handler(
     Ok(resp)
 }
 
+/// Forward a DevTools http request to the chrome address returning the status, content type, and
+/// body.
+async fn chrome_http_request(
+    address: &str,
+    method: Method,
+    path_and_query: &str,
+) -> Option<(StatusCode, Option<hyper::header::HeaderValue>, Bytes)> {
+    use http_body_util::BodyExt;
+
+    let req = Request::builder()
+        .method(method)
+        .uri(path_and_query)
+        .header(hyper::header::HOST, address)
+        .header(hyper::header::CONNECTION, "keep-alive")
+        .body(http_body_util::Empty::<Bytes>::new())
+        .ok()?;
+
+    let stream = connect_with_retries(address).await?;
+    let (mut client, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .ok()?;
+
+    tokio::task::spawn(async move {
+        if let Err(err) = conn.await {
+            tracing::error!("Connection failed: {:?}", err);
+        }
+    });
+
+    let resp = client.send_request(req).await.ok()?;
+    let status = resp.status();
+    let content_type = resp.headers().get(hyper::header::CONTENT_TYPE).cloned();
+    let body = resp.into_body().collect().await.ok()?.to_bytes();
+
+    Some((status, content_type, body))
+}
+
+/// DevTools http endpoints passthrough, ex: `/json/list`, `/json/new?url`, `/json/activate/{id}`,
+/// `/json/close/{id}`, and `/json/protocol`.
+async fn json_passthrough_handler(
+    req: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let path = modify::strip_base_path(req.uri().path());
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{}?{}", path, query),
+        _ => path.to_string(),
+    };
+
+    Ok(json_passthrough(
+        ENDPOINT_BASE.trim_start_matches("http://"),
+        req.method().clone(),
+        &path_and_query,
+        modify::PublicAddress::from_headers(req.headers()).as_ref(),
+    )
+    .await)
+}
+
+/// Forward the DevTools http request to the chrome address rewriting the target urls for the
+/// public address.
+async fn json_passthrough(
+    address: &str,
+    method: Method,
+    path_and_query: &str,
+    public: Option<&modify::PublicAddress>,
+) -> Response<Full<Bytes>> {
+    let path = path_and_query.split('?').next().unwrap_or_default();
+
+    match chrome_http_request(address, method, path_and_query).await {
+        Some((status, content_type, body)) => {
+            // the protocol schema has no target urls to rewrite.
+            let body = if path != "/json/protocol" {
+                modify::modify_json_output(body, public)
+            } else {
+                body
+            };
+
+            if *DEBUG_JSON {
+                tracing::info!("{:?}", body);
+            }
+
+            let mut resp = Response::new(Full::new(body));
+            *resp.status_mut() = status;
+
+            if let Some(content_type) = content_type {
+                resp.headers_mut()
+                    .insert(hyper::header::CONTENT_TYPE, content_type);
+            }
+
+            resp
+        }
+        _ => {
+            let mut resp = Response::new(Full::new(Bytes::from("Chrome is not available")));
+            *resp.status_mut() = StatusCode::BAD_GATEWAY;
+            resp
+        }
+    }
+}
+
+/// A json response with the status.
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Full<Bytes>> {
+    let mut resp = Response::new(Full::new(Bytes::from(body.to_string())));
+
+    *resp.status_mut() = status;
+    resp.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/json"),
+    );
+
+    resp
+}
+
+/// A json error response with the status, ex: `{"error":"Instance not found.","status":404}`.
+fn json_error(status: StatusCode, message: &str) -> Response<Full<Bytes>> {
+    json_response(
+        status,
+        serde_json::json!({ "error": message, "status": status.as_u16() }),
+    )
+}
+
+/// The max size of a control request body.
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// The max size of a render request body with the html to render.
+const MAX_RENDER_BODY_SIZE: usize = 5 * 1024 * 1024;
+
+/// Read a control request body up to the max size.
+async fn read_body(req: Request<Incoming>) -> Option<Bytes> {
+    read_body_limited(req, MAX_BODY_SIZE).await
+}
+
+/// Read a request body up to the limit.
+async fn read_body_limited(req: Request<Incoming>, limit: usize) -> Option<Bytes> {
+    use http_body_util::BodyExt;
+
+    http_body_util::Limited::new(req.into_body(), limit)
+        .collect()
+        .await
+        .ok()
+        .map(|body| body.to_bytes())
+}
+
+/// Mint a signed session url for the browser, ex: `{"ttl":60,"single_use":true}`.
+async fn sessions_handler(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+    if !signing::enabled() {
+        return Ok(json_error(
+            StatusCode::NOT_FOUND,
+            "Session signing is not enabled.",
+        ));
+    }
+
+    let public = modify::PublicAddress::from_headers(req.headers());
+
+    let options = match read_body(req).await {
+        Some(body) => signing::SessionOptions::from_json(&body),
+        _ => Err("Invalid body.".into()),
+    };
+
+    let options = match options {
+        Ok(options) => options,
+        Err(e) => return Ok(json_error(StatusCode::BAD_REQUEST, &e)),
+    };
+
+    let version = match version_handler_bytes(None).await {
+        Some(body) => modify::modify_json_output(body, public.as_ref()),
+        _ => {
+            return Ok(json_error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Chrome is not available.",
+            ))
+        }
+    };
+
+    let url = serde_json::from_slice::<serde_json::Value>(&version)
+        .ok()
+        .and_then(|version| {
+            version
+                .get("webSocketDebuggerUrl")
+                .and_then(serde_json::Value::as_str)
+                .map(String::from)
+        })
+        .unwrap_or_default();
+
+    let (token, expires_at) = signing::sign(conf::SESSION_SIGNING_KEY.as_bytes(), &options);
+    let separator = if url.contains('?') { '&' } else { '?' };
+
+    Ok(json_response(
+        StatusCode::CREATED,
+        serde_json::json!({
+            "webSocketDebuggerUrl": format!("{}{}{}={}", url, separator, signing::TOKEN_PARAM, token),
+            "expiresAt": expires_at,
+            "singleUse": options.single_use,
+        }),
+    ))
+}
+
+/// Stream the lifecycle events as server-sent events, ex: `GET /events`.
+fn events_handler() -> Response<ControlBody> {
+    let mut resp = Response::new(Either::Right(events::event_stream()));
+
+    resp.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("text/event-stream"),
+    );
+    resp.headers_mut().insert(
+        hyper::header::CACHE_CONTROL,
+        hyper::header::HeaderValue::from_static("no-cache"),
+    );
+
+    resp
+}
+
+/// The error response of a failed render. Chrome that cannot be reached or a node without free
+/// render slots is unavailable.
+fn render_error(err: std::io::Error) -> Response<Full<Bytes>> {
+    tracing::warn!("Render failed: {}", err);
+
+    let status = match err.kind() {
+        std::io::ErrorKind::TimedOut => StatusCode::GATEWAY_TIMEOUT,
+        std::io::ErrorKind::ConnectionRefused
+        | std::io::ErrorKind::NotFound
+        | std::io::ErrorKind::ResourceBusy => StatusCode::SERVICE_UNAVAILABLE,
+        std::io::ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+        std::io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+        std::io::ErrorKind::QuotaExceeded => StatusCode::TOO_MANY_REQUESTS,
+        _ => StatusCode::BAD_GATEWAY,
+    };
+
+    json_error(status, &err.to_string())
+}
+
+/// Capture a screenshot of a url or html on a pooled instance, ex: `POST /v1/screenshot`.
+async fn screenshot_handler(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+    let claims = req.extensions().get::<auth::Claims>().cloned();
+
+    let body = match read_body_limited(req, MAX_RENDER_BODY_SIZE).await {
+        Some(body) => body,
+        _ => return Ok(json_error(StatusCode::BAD_REQUEST, "Invalid body.")),
+    };
+
+    let options = match render::ScreenshotOptions::from_json(&body) {
+        Ok(options) => options,
+        Err(e) => return Ok(json_error(StatusCode::BAD_REQUEST, &e)),
+    };
+
+    // the slot is held until the capture is done.
+    let _slot = match render::RenderSlot::acquire(claims.as_ref(), &render::SCREENSHOT_DOMAINS) {
+        Ok(slot) => slot,
+        Err(err) => return Ok(render_error(err)),
+    };
+
+    Ok(
+        match render::screenshot(&render::pooled_address(), &options).await {
+            Ok(image) => {
+                let mut resp = Response::new(Full::new(Bytes::from(image)));
+
+                resp.headers_mut().insert(
+                    hyper::header::CONTENT_TYPE,
+                    hyper::header::HeaderValue::from_static(options.format.content_type()),
+                );
+
+                resp
+            }
+            Err(err) => render_error(err),
+        },
+    )
+}
+
+/// Print a url or html to a PDF on a pooled instance streaming the document, ex: `POST /v1/pdf`.
+async fn pdf_handler(req: Request<Incoming>) -> Result<Response<ControlBody>, Infallible> {
+    let claims = req.extensions().get::<auth::Claims>().cloned();
+
+    let body = match read_body_limited(req, MAX_RENDER_BODY_SIZE).await {
+        Some(body) => body,
+        _ => {
+            return Ok(json_error(StatusCode::BAD_REQUEST, "Invalid body.").map(Either::Left));
+        }
+    };
+
+    let options = match render::PdfOptions::from_json(&body) {
+        Ok(options) => options,
+        Err(e) => return Ok(json_error(StatusCode::BAD_REQUEST, &e).map(Either::Left)),
+    };
+
+    let slot = match render::RenderSlot::acquire(claims.as_ref(), &render::PDF_DOMAINS) {
+        Ok(slot) => slot,
+        Err(err) => return Ok(render_error(err).map(Either::Left)),
+    };
+
+    Ok(
+        match render::pdf(&render::pooled_address(), &options, slot).await {
+            Ok(pdf) => {
+                let mut resp = Response::new(Either::Right(pdf));
+
+                resp.headers_mut().insert(
+                    hyper::header::CONTENT_TYPE,
+                    hyper::header::HeaderValue::from_static("application/pdf"),
+                );
+
+                resp
+            }
+            Err(err) => render_error(err).map(Either::Left),
+        },
+    )
+}
+
 /// Shutdown all the chrome instances launched.
 pub async fn shutdown_instances() {
     for pid in CHROME_INSTANCES.iter() {
         shutdown(&pid);
+        events::emit(events::Event::InstanceShutdown { pid: *pid });
+    }
+    instances::clear();
+    CACHEABLE.store(false, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// Shutdown a chrome instance launched. Returns false when the instance is unknown.
+fn shutdown_instance(pid: u32) -> bool {
+    if !instances::unregister(pid) {
+        return false;
     }
-    CHROME_INSTANCES.clear();
+
+    shutdown(&pid);
+    events::emit(events::Event::InstanceShutdown { pid });
     CACHEABLE.store(false, std::sync::atomic::Ordering::Relaxed);
+
+    true
+}
+
+/// Replace an unhealthy instance with a new process on the same port and fork options. Returns
+/// the process id of the replacement.
+async fn recycle_instance(pid: u32) -> std::io::Result<u32> {
+    let _restart = proxy::proxy::RESTART_LOCK.lock().await;
+
+    if instances::crash_loop_tripped() {
+        return Err(std::io::Error::other("the crash loop breaker is tripped"));
+    }
+
+    let info = instances::info(pid)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "instance not found"))?;
+
+    shutdown_instance(pid);
+
+    // wait for the killed process to release the port.
+    if let Some(port) = info.port {
+        let address = probe::instance_address(port);
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+
+        while tokio::time::Instant::now() < deadline && TcpStream::connect(&address).await.is_ok() {
+            sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    metrics::RECYCLES.inc();
+
+    let mut options = info.options;
+    options.port = info.port;
+
+    let replacement = fork_with(&options)?;
+    events::emit(events::Event::InstanceRecycled { pid, replacement });
+
+    Ok(replacement)
+}
+
+/// List the chrome instances, ex: `GET /v1/instances`.
+async fn list_instances_handler() -> Result<Response<Full<Bytes>>, Infallible> {
+    Ok(json_response(
+        StatusCode::OK,
+        serde_json::json!({ "instances": instances::list() }),
+    ))
+}
+
+/// Get a chrome instance, ex: `GET /v1/instances/{id}`.
+async fn get_instance_handler(id: &str) -> Result<Response<Full<Bytes>>, Infallible> {
+    let pid = match id.parse::<u32>() {
+        Ok(pid) => pid,
+        _ => return Ok(json_error(StatusCode::BAD_REQUEST, "Invalid instance id.")),
+    };
+
+    Ok(match instances::get(pid) {
+        Some(instance) => json_response(StatusCode::OK, instance),
+        _ => json_error(StatusCode::NOT_FOUND, "Instance not found."),
+    })
+}
+
+/// Fork a chrome instance, ex: `POST /v1/instances` with `{"port":9333,"headless":false}`.
+async fn create_instance_handler(
+    req: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let options = match read_fork_options(req).await {
+        Ok(options) => options,
+        Err((status, message)) => return Ok(json_error(status, &message)),
+    };
+
+    let pid = match fork_with(&options) {
+        Ok(pid) => pid,
+        Err(e) => {
+            tracing::error!("Failed to fork chrome: {}", e);
+            0
+        }
+    };
+
+    let instance = match instances::get(pid) {
+        Some(instance) => instance,
+        _ => {
+            return Ok(json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to fork chrome.",
+            ))
+        }
+    };
+
+    let mut resp = json_response(StatusCode::CREATED, instance);
+
+    if let Ok(location) =
+        hyper::header::HeaderValue::from_str(&format!("{}/v1/instances/{}", *conf::BASE_PATH, pid))
+    {
+        resp.headers_mut().insert(hyper::header::LOCATION, location);
+    }
+
+    Ok(resp)
+}
+
+/// Shutdown a chrome instance, ex: `DELETE /v1/instances/{id}`.
+async fn delete_instance_handler(id: &str) -> Result<Response<Full<Bytes>>, Infallible> {
+    let pid = match id.parse::<u32>() {
+        Ok(pid) => pid,
+        _ => return Ok(json_error(StatusCode::BAD_REQUEST, "Invalid instance id.")),
+    };
+
+    if !shutdown_instance(pid) {
+        return Ok(json_error(StatusCode::NOT_FOUND, "Instance not found."));
+    }
+
+    let mut resp = Response::new(Full::new(Bytes::new()));
+    *resp.status_mut() = StatusCode::NO_CONTENT;
+
+    Ok(resp)
+}
+
+/// A json 405 response with the allowed methods.
+fn method_not_allowed(allow: &'static str) -> Response<Full<Bytes>> {
+    let mut resp = json_error(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed.");
+
+    resp.headers_mut().insert(
+        hyper::header::ALLOW,
+        hyper::header::HeaderValue::from_static(allow),
+    );
+
+    resp
 }
 
 /// Shutdown handler.
@@ -408,30 +1087,172 @@ async fn shutdown_handler() -> Result<Response<Full<Bytes>>, Infallible> {
     ))))
 }
 
+/// Is the route off limits to the tenant token. Tenant claims only shape proxy sessions: the
+/// instance lifecycle routes need an admin token, and restricted tenants cannot list, open,
+/// close, or activate targets through the chrome json routes that skip the session checks.
+pub(crate) fn forbidden_route(method: &str, path: &str, claims: &auth::Claims) -> bool {
+    if claims.admin {
+        return false;
+    }
+
+    let lifecycle = match method {
+        "POST" => {
+            matches!(path, "/fork" | "/shutdown" | "/v1/instances")
+                || path.starts_with("/fork/")
+                || path.starts_with("/shutdown/")
+        }
+        "DELETE" => path.starts_with("/v1/instances/"),
+        _ => false,
+    };
+
+    let targets = matches!(path, "/json" | "/json/list")
+        || ["/json/new", "/json/close/", "/json/activate/"]
+            .iter()
+            .any(|prefix| path.starts_with(prefix));
+
+    // the events carry the sessions and instances of every tenant.
+    lifecycle || path == "/events" || (claims.restricted() && targets)
+}
+
 /// Request handler.
-async fn request_handler(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
-    match (req.method(), req.uri().path()) {
+async fn request_handler(mut req: Request<Incoming>) -> Result<Response<ControlBody>, Infallible> {
+    // routes are mounted under the base path behind ingress.
+    let path = modify::strip_base_path(req.uri().path()).into_owned();
+
+    // the health checks stay open for the load balancer.
+    if auth::enabled() && !matches!(path.as_str(), "/" | "/health" | "/livez" | "/readyz") {
+        let authorization = req
+            .headers()
+            .get(hyper::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+
+        match auth::bearer_token(authorization)
+            .ok_or("missing token")
+            .and_then(auth::verify)
+        {
+            Ok(claims) if forbidden_route(req.method().as_str(), &path, &claims) => {
+                tracing::warn!(
+                    "Rejected {} {} for tenant {}",
+                    req.method(),
+                    path,
+                    claims.tenant
+                );
+
+                return Ok(json_error(
+                    StatusCode::FORBIDDEN,
+                    "The token is not allowed to call the route.",
+                )
+                .map(Either::Left));
+            }
+            Ok(claims) => {
+                tracing::debug!("Authorized {} for tenant {}", path, claims.tenant);
+                req.extensions_mut().insert(claims);
+            }
+            Err(reason) => {
+                if let Some(client) = req.extensions().get::<ClientInfo>() {
+                    tracing::warn!("Rejected bearer token from {}: {}", client, reason);
+                }
+
+                let mut resp = json_error(StatusCode::UNAUTHORIZED, "Invalid bearer token.");
+                resp.headers_mut().insert(
+                    hyper::header::WWW_AUTHENTICATE,
+                    hyper::header::HeaderValue::from_static("Bearer"),
+                );
+
+                return Ok(resp.map(Either::Left));
+            }
+        }
+    }
+
+    // the client certificate identity is the tenant without JWT auth.
+    if !auth::enabled() {
+        if let Some(claims) = req
+            .extensions()
+            .get::<ClientInfo>()
+            .and_then(|client| client.identity.as_deref())
+            .map(auth::identity_claims)
+        {
+            req.extensions_mut().insert(claims);
+        }
+    }
+
+    match (req.method(), path.as_str()) {
+        (&Method::GET, "/events") => Ok(events_handler()),
+        (_, "/events") => Ok(method_not_allowed("GET").map(Either::Left)),
+        (&Method::POST, "/v1/pdf") => pdf_handler(req).await,
+        (_, "/v1/pdf") => Ok(method_not_allowed("POST").map(Either::Left)),
+        _ => route(req, &path).await.map(|resp| resp.map(Either::Left)),
+    }
+}
+
+/// Route the control requests answered with a complete body.
+async fn route(req: Request<Incoming>, path: &str) -> Result<Response<Full<Bytes>>, Infallible> {
+    match (req.method(), path) {
         (&Method::GET, "/health") => health_check_handler().await,
         (&Method::GET, "/") => health_check_handler().await,
-        (&Method::POST, "/fork") => fork_handler(None).await,
+        (&Method::GET, "/livez") => livez_handler().await,
+        (&Method::GET, "/readyz") => {
+            let detail = req.uri().query().is_some_and(|query| {
+                ws::parse_query(query)
+                    .iter()
+                    .any(|(key, value)| key == "detail" && value != "false" && value != "0")
+            });
+            readyz_handler(detail).await
+        }
+        (&Method::POST, "/fork") => fork_handler(None, req).await,
         (&Method::POST, path) if path.starts_with("/fork/") => {
             if let Some(port) = path.split('/').nth(2) {
                 if let Ok(port) = port.parse::<u32>() {
-                    fork_handler(Some(port)).await
+                    fork_handler(Some(port), req).await
                 } else {
-                    let message = Response::new(Full::new(Bytes::from("Invalid port argument")));
-
-                    Ok(message)
+                    Ok(text_response(
+                        StatusCode::BAD_REQUEST,
+                        "Invalid port argument",
+                    ))
                 }
             } else {
-                let message = Response::new(Full::new(Bytes::from("Invalid path")));
-
-                Ok(message)
+                Ok(text_response(StatusCode::BAD_REQUEST, "Invalid path"))
             }
         }
         // we only care about the main /json/version for 9223 for the proxy forwarder.
-        (&Method::GET, "/json/version") => json_version_handler(None).await,
+        (&Method::GET, "/json/version") => {
+            json_version_handler(None, modify::PublicAddress::from_headers(req.headers())).await
+        }
+        (_, path) if path == "/json" || path.starts_with("/json/") => {
+            json_passthrough_handler(req).await
+        }
+        (&Method::GET, "/metrics") => metrics_handler().await,
         (&Method::POST, "/shutdown") => shutdown_handler().await,
+        (&Method::POST, path) if path.starts_with("/shutdown/") => {
+            match path["/shutdown/".len()..].parse::<u32>() {
+                Ok(pid) if shutdown_instance(pid) => {
+                    Ok(text_response(StatusCode::OK, "Shutdown successful."))
+                }
+                Ok(_) => Ok(text_response(StatusCode::NOT_FOUND, "Instance not found.")),
+                _ => Ok(text_response(
+                    StatusCode::BAD_REQUEST,
+                    "Invalid pid argument",
+                )),
+            }
+        }
+        (&Method::GET, "/v1/instances") => list_instances_handler().await,
+        (&Method::POST, "/v1/instances") => create_instance_handler(req).await,
+        (_, "/v1/instances") => Ok(method_not_allowed("GET, POST")),
+        (method, path) if path.starts_with("/v1/instances/") => {
+            let id = &path["/v1/instances/".len()..];
+
+            match *method {
+                Method::GET => get_instance_handler(id).await,
+                Method::DELETE => delete_instance_handler(id).await,
+                _ => Ok(method_not_allowed("GET, DELETE")),
+            }
+        }
+        (&Method::POST, "/v1/screenshot") => screenshot_handler(req).await,
+        (_, "/v1/screenshot") => Ok(method_not_allowed("POST")),
+        (_, path) if path.starts_with("/v1/") => {
+            Ok(json_error(StatusCode::NOT_FOUND, "Not Found."))
+        }
+        (&Method::POST, "/sessions") => sessions_handler(req).await,
         _ => {
             let mut resp = Response::new(Full::new(Bytes::from("Not Found")));
 
@@ -442,6 +1263,124 @@ async fn request_handler(req: Request<Incoming>) -> Result<Response<Full<Bytes>>
     }
 }
 
+/// Is the request head a websocket upgrade for the CDP proxy, ex: `/devtools/browser/{id}`.
+fn is_devtools_upgrade(head: &[u8]) -> bool {
+    ws::RequestHead::parse(head).is_some_and(|request| {
+        request.is_upgrade() && modify::strip_base_path(&request.path).starts_with("/devtools/")
+    })
+}
+
+/// Serve the control routes for the connections of a listener. In single port mode the
+/// websocket upgrades for `/devtools/*` are proxied to the devtools target.
+async fn serve_control(
+    listener: Listener,
+    builder_options: std::sync::Arc<http1::Builder>,
+    devtools_target: Option<&'static str>,
+) {
+    let base_time = std::time::Instant::now();
+
+    loop {
+        if let Ok((mut stream, peer_addr)) = listener.accept().await {
+            let builder_options = builder_options.clone();
+
+            tokio::task::spawn(async move {
+                let client = match proxy_protocol::client_info(&mut stream, peer_addr).await {
+                    Ok(client) => client,
+                    Err(err) => {
+                        tracing::warn!("Rejected connection: {}", err);
+                        return;
+                    }
+                };
+
+                let mut stream = match tls::accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        tracing::warn!("TLS handshake failed for {}: {}", client, err);
+                        return;
+                    }
+                };
+
+                let mut client = client;
+                client.identity = tls::client_identity(&stream);
+
+                tracing::debug!("Accepted control connection from {}", client);
+
+                let prefix = if let Some(target) = devtools_target {
+                    let (head, rest) = match ws::read_head(&mut stream).await {
+                        Ok(read) => read,
+                        Err(err) => {
+                            tracing::warn!("Failed to read the request: {}", err);
+                            return;
+                        }
+                    };
+
+                    if is_devtools_upgrade(&head) {
+                        tracing::info!("Accepted connection from {}", client);
+                        proxy::proxy::proxy_connection(
+                            &mut stream,
+                            &client,
+                            head,
+                            rest,
+                            base_time,
+                            target,
+                        )
+                        .await;
+                        return;
+                    }
+
+                    [head, rest].concat()
+                } else {
+                    Vec::new()
+                };
+
+                let io = TokioIo::new(listener::Prefixed::new(prefix, stream));
+                let service = service_fn(move |mut req: Request<Incoming>| {
+                    req.extensions_mut().insert(client.clone());
+                    request_handler(req)
+                });
+
+                if let Err(err) = builder_options.serve_connection(io, service).await {
+                    eprintln!("Error serving connection: {:?}", err);
+                }
+            });
+        }
+    }
+}
+
+/// Wait for ctrl-c or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    if let Ok(mut terminate) = signal::unix::signal(signal::unix::SignalKind::terminate()) {
+        tokio::select! {
+            _ = signal::ctrl_c() => (),
+            _ = terminate.recv() => (),
+        }
+        return;
+    }
+
+    let _ = signal::ctrl_c().await;
+}
+
+/// Reject new sessions and wait up to `DRAIN_TIMEOUT` for the proxied sessions and webhook
+/// deliveries to end.
+async fn drain() {
+    DRAINING.store(true, Ordering::Relaxed);
+
+    let sessions = metrics::ACTIVE_SESSIONS.load(Ordering::Relaxed);
+    tracing::info!("Draining {} sessions", sessions);
+    events::emit(events::Event::NodeDraining { sessions });
+
+    let deadline = tokio::time::Instant::now() + *DRAIN_TIMEOUT;
+
+    while metrics::ACTIVE_SESSIONS.load(Ordering::Relaxed) > 0
+        && tokio::time::Instant::now() < deadline
+    {
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    webhooks::flush(deadline).await;
+}
+
 /// Launch chrome, start the server, and proxy for management.
 pub async fn run_main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let auto_start = std::env::args().nth(3).unwrap_or_else(|| {
@@ -452,16 +1391,37 @@ pub async fn run_main() -> Result<(), Box<dyn std::error::Error + Send + Sync>>
         }
     });
 
+    tls::init()?;
+
+    // a typo in the listen addresses fails the start instead of binding nothing.
+    let server_listen = SERVER_LISTEN.as_ref().map_err(String::as_str)?;
+
+    if !*SINGLE_PORT {
+        conf::PROXY_LISTEN.as_ref().map_err(String::as_str)?;
+    }
+
+    if signing::enabled() && !auth::enabled() {
+        return Err(
+            "SESSION_SIGNING_KEY requires JWT_SECRET or JWT_JWKS to authorize minting session urls"
+                .into(),
+        );
+    }
+
+    tokio::spawn(instances::monitor());
+    tokio::spawn(probe::monitor());
+    tokio::spawn(canary::monitor());
+    tokio::spawn(webhooks::deliver(events::subscribe()));
+
     if auto_start == "init" {
         fork(Some(*DEFAULT_PORT));
     }
 
-    let addr = SocketAddr::new(
-        std::net::IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0)),
-        *DEFAULT_PORT_SERVER,
-    );
+    let mut listeners = Vec::with_capacity(server_listen.len());
 
-    let listener = TcpListener::bind(addr).await.expect("connection");
+    for addr in server_listen {
+        listeners.push(Listener::bind(addr).await?);
+        println!("Chrome server running on {}", addr);
+    }
 
     let make_svc = async move {
         let builder_options = std::sync::Arc::new(
@@ -474,37 +1434,33 @@ pub async fn run_main() -> Result<(), Box<dyn std::error::Error + Send + Sync>>
                 .to_owned(),
         );
 
-        loop {
-            if let Ok((tcp, _)) = listener.accept().await {
-                let builder_options = builder_options.clone();
-
-                tokio::task::spawn(async move {
-                    let io = TokioIo::new(tcp);
-                    if let Err(err) = builder_options
-                        .serve_connection(io, service_fn(request_handler))
-                        .await
-                    {
-                        eprintln!("Error serving connection: {:?}", err);
-                    }
-                });
-            }
+        let mut accept_loops = tokio::task::JoinSet::new();
+
+        for listener in listeners {
+            accept_loops.spawn(serve_control(
+                listener,
+                builder_options.clone(),
+                SINGLE_PORT.then_some(*conf::TARGET),
+            ));
         }
+
+        while accept_loops.join_next().await.is_some() {}
     };
 
-    println!(
-        "Chrome server running on {}:{}",
-        if CHROME_ADDRESS.is_empty() {
-            "localhost"
-        } else {
-            &CHROME_ADDRESS
-        },
-        DEFAULT_PORT_SERVER.to_string()
-    );
+    let run_proxy = async {
+        if *SINGLE_PORT {
+            std::future::pending::<()>().await;
+        }
+        let _ = crate::proxy::proxy::run_proxy().await;
+    };
 
     tokio::select! {
         _ = make_svc => Ok(()),
-        _ = crate::proxy::proxy::run_proxy() =>  Ok(()),
-        _ = signal::ctrl_c() => Ok(()),
+        _ = run_proxy =>  Ok(()),
+        _ = async {
+            shutdown_signal().await;
+            drain().await;
+        } => Ok(()),
     }
 }
 
@@ -556,4 +1512,102 @@ mod tests {
         let result = smart_split_args(input);
         assert_eq!(result, vec![r#"--arg="quoted \"inner\" text""#, "--next"]);
     }
+
+    #[test]
+    fn test_forbidden_route() {
+        let tenant = auth::Claims {
+            tenant: "acme".into(),
+            allowed_domains: Some(vec!["Page".into()]),
+            ..Default::default()
+        };
+        let admin = auth::Claims {
+            admin: true,
+            ..tenant.clone()
+        };
+
+        assert!(forbidden_route("POST", "/shutdown/42", &tenant));
+        assert!(forbidden_route("DELETE", "/v1/instances/42", &tenant));
+        assert!(forbidden_route("PUT", "/json/new", &tenant));
+        assert!(forbidden_route("GET", "/json/list", &tenant));
+        assert!(!forbidden_route("GET", "/v1/instances/42", &tenant));
+        assert!(!forbidden_route("GET", "/json/version", &tenant));
+        assert!(forbidden_route("GET", "/events", &tenant));
+        assert!(!forbidden_route("GET", "/events", &admin));
+        assert!(!forbidden_route("POST", "/fork", &admin));
+    }
+
+    #[tokio::test]
+    async fn test_json_list_passthrough() {
+        use http_body_util::BodyExt;
+
+        let address = replay::serve_recording("").await;
+        let public = modify::PublicAddress::parse_url("https://gw.example/browsers/node-3/");
+
+        let resp = json_passthrough(&address, Method::GET, "/json/list", public.as_ref()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let targets: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(
+            targets[0]["webSocketDebuggerUrl"],
+            "wss://gw.example/browsers/node-3/devtools/page/replay"
+        );
+        assert_eq!(
+            targets[0]["devtoolsFrontendUrl"],
+            "/devtools/inspector.html?wss=gw.example/browsers/node-3/devtools/page/replay"
+        );
+    }
+    #[tokio::test]
+    async fn test_single_port_split() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let recording = r#"{"timestamp":1,"direction":"send","session":"1","id":1,"method":"Browser.getVersion"}
+{"timestamp":2,"direction":"receive","session":"1","id":1,"method":"Browser.getVersion","result":{"product":"HeadlessChrome/131.0.6778.139"}}"#;
+        let target: &'static str = Box::leak(replay::serve_recording(recording).await.into());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(serve_control(
+            Listener::Tcp(listener),
+            std::sync::Arc::new(http1::Builder::new()),
+            Some(target),
+        ));
+
+        // the control routes are served by hyper.
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"GET /livez HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+
+        // the devtools upgrades on the same port are proxied to chrome.
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"GET /devtools/browser/replay HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n")
+            .await
+            .unwrap();
+        stream
+            .write_all(&ws::encode_frame(
+                ws::OPCODE_TEXT,
+                br#"{"id":1,"method":"Browser.getVersion"}"#,
+                Some([1, 2, 3, 4]),
+            ))
+            .await
+            .unwrap();
+
+        let mut response = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !response.ends_with(b"}}") {
+            let size = stream.read(&mut buf).await.unwrap();
+            assert!(size > 0, "closed before the reply");
+            response.extend_from_slice(&buf[..size]);
+        }
+        assert!(response.starts_with(b"HTTP/1.1 101"));
+        assert!(String::from_utf8_lossy(&response).contains("HeadlessChrome/131.0.6778.139"));
+    }
 }
@@ -0,0 +1,308 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+/// A listen address for the control server or the proxy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    /// An IPv4 or IPv6 socket address, ex: `0.0.0.0:6000` or `[::]:6000`.
+    Tcp(SocketAddr),
+    /// A unix domain socket path, ex: `unix:/run/headless_browser.sock`.
+    Unix(std::path::PathBuf),
+}
+
+impl ListenAddr {
+    /// Parse a single listen address.
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+
+        match value.strip_prefix("unix:") {
+            Some(path) if !path.is_empty() => Some(ListenAddr::Unix(path.into())),
+            Some(_) => None,
+            _ => value.parse().ok().map(ListenAddr::Tcp),
+        }
+    }
+
+    /// The tcp port of the address.
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            ListenAddr::Tcp(addr) => Some(addr.port()),
+            ListenAddr::Unix(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Parse a comma separated list of listen addresses from the env, falling back to the default.
+/// Errors on the first invalid or empty entry.
+pub(crate) fn listen_addrs(env_key: &str, default: &str) -> Result<Vec<ListenAddr>, String> {
+    let value = std::env::var(env_key).unwrap_or_default();
+    let value = if value.trim().is_empty() {
+        default
+    } else {
+        &value
+    };
+
+    parse_listen_addrs(env_key, value)
+}
+
+/// Parse a comma separated list of listen addresses.
+fn parse_listen_addrs(env_key: &str, value: &str) -> Result<Vec<ListenAddr>, String> {
+    value
+        .split(',')
+        .map(|addr| {
+            ListenAddr::parse(addr)
+                .ok_or_else(|| format!("{} has an invalid listen address: {:?}", env_key, addr))
+        })
+        .collect()
+}
+
+/// A bound tcp or unix listener.
+pub(crate) enum Listener {
+    /// A tcp listener.
+    Tcp(TcpListener),
+    /// A unix domain socket listener.
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Bind the listen address. Stale unix socket files are replaced, any other file at the path is
+    /// left in place and fails the bind.
+    pub async fn bind(addr: &ListenAddr) -> std::io::Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+
+                match std::fs::symlink_metadata(path) {
+                    Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+                    Ok(_) => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::AlreadyExists,
+                            format!("{} exists and is not a unix socket", path.display()),
+                        ))
+                    }
+                    Err(error) if error.kind() == std::io::ErrorKind::NotFound => (),
+                    Err(error) => return Err(error),
+                }
+                Ok(Listener::Unix(UnixListener::bind(path)?))
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "unix sockets are not supported on this platform",
+            )),
+        }
+    }
+
+    /// Accept the next connection. Unix connections have no peer address.
+    pub async fn accept(&self) -> std::io::Result<(Stream, Option<SocketAddr>)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), Some(addr)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Stream::Unix(stream), None))
+            }
+        }
+    }
+}
+
+/// An accepted tcp or unix connection.
+pub(crate) enum Stream {
+    /// A tcp connection.
+    Tcp(TcpStream),
+    /// A unix domain socket connection.
+    #[cfg(unix)]
+    Unix(UnixStream),
+    /// A TLS connection over a tcp or unix connection.
+    Tls(Box<tokio_rustls::server::TlsStream<Stream>>),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// A stream that replays bytes already read before reading from the inner stream.
+pub(crate) struct Prefixed<S> {
+    /// The bytes already read.
+    prefix: Vec<u8>,
+    /// The replayed length of the prefix.
+    offset: usize,
+    /// The inner stream.
+    inner: S,
+}
+
+impl<S> Prefixed<S> {
+    /// A new stream replaying the prefix first.
+    pub fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self {
+            prefix,
+            offset: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Prefixed<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.offset < this.prefix.len() {
+            let size = buf.remaining().min(this.prefix.len() - this.offset);
+            buf.put_slice(&this.prefix[this.offset..this.offset + size]);
+            this.offset += size;
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Prefixed<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Bracket IPv6 hosts for use in urls, ex: `::1` to `[::1]`.
+pub(crate) fn url_host(host: &str) -> std::borrow::Cow<'_, str> {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]", host).into()
+    } else {
+        host.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listen_addrs() {
+        assert_eq!(
+            ListenAddr::parse("[::]:6000"),
+            Some(ListenAddr::Tcp("[::]:6000".parse().unwrap()))
+        );
+        assert_eq!(
+            ListenAddr::parse(" unix:/tmp/hb.sock "),
+            Some(ListenAddr::Unix("/tmp/hb.sock".into()))
+        );
+        assert_eq!(ListenAddr::parse("localhost"), None);
+        assert_eq!(
+            parse_listen_addrs("PROXY_LISTEN", "[::]:6000,unix:/tmp/hb.sock")
+                .map(|addrs| addrs.len()),
+            Ok(2)
+        );
+        assert_eq!(
+            parse_listen_addrs("PROXY_LISTEN", "0.0.0.0:9222,,localhost"),
+            Err("PROXY_LISTEN has an invalid listen address: \"\"".into())
+        );
+        assert_eq!(url_host("fd00::1"), "[fd00::1]");
+        assert_eq!(url_host("example.com"), "example.com");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_bind_unix_replaces_only_sockets() {
+        let dir = std::env::temp_dir().join(format!("hb-listener-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let file = dir.join("file");
+        std::fs::write(&file, b"keep").unwrap();
+        let error = Listener::bind(&ListenAddr::Unix(file.clone()))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(&file).unwrap(), b"keep");
+
+        let socket = dir.join("socket");
+        drop(
+            Listener::bind(&ListenAddr::Unix(socket.clone()))
+                .await
+                .unwrap(),
+        );
+        assert!(Listener::bind(&ListenAddr::Unix(socket)).await.is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,334 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// A monotonically increasing counter.
+pub(crate) struct Counter(AtomicU64);
+
+impl Counter {
+    /// A new counter at zero.
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    /// Increment the counter.
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    /// Add to the counter.
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// The current value.
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A histogram of durations with fixed bucket upper bounds in seconds.
+pub(crate) struct Histogram<const N: usize> {
+    /// The bucket upper bounds in seconds.
+    bounds: [f64; N],
+    /// The observations of each bucket, not cumulative.
+    buckets: [AtomicU64; N],
+    /// The number of observations.
+    count: AtomicU64,
+    /// The sum of the observations in microseconds.
+    sum_micros: AtomicU64,
+}
+
+impl<const N: usize> Histogram<N> {
+    /// A new histogram with the bucket upper bounds in seconds.
+    pub const fn new(bounds: [f64; N]) -> Self {
+        Self {
+            bounds,
+            buckets: [const { AtomicU64::new(0) }; N],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    /// Record a duration.
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+
+        if let Some(bucket) = self.bounds.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Write the histogram in the Prometheus text format.
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+
+        let mut cumulative = 0;
+
+        for (bound, bucket) in self.bounds.iter().zip(self.buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+/// The chrome instances forked.
+pub(crate) static FORKS: Counter = Counter::new();
+/// The dedicated chrome instances launched for client launch options.
+pub(crate) static DEDICATED_LAUNCHES: Counter = Counter::new();
+/// The chrome restarts after failed proxy connections.
+pub(crate) static RESTARTS: Counter = Counter::new();
+/// The chrome instances that exited without a shutdown.
+pub(crate) static CRASHES: Counter = Counter::new();
+/// The unhealthy chrome instances replaced after failed canary checks.
+pub(crate) static RECYCLES: Counter = Counter::new();
+/// The failed or slow canary checks.
+pub(crate) static CANARY_FAILURES: Counter = Counter::new();
+/// The latency of the passed canary checks.
+pub(crate) static CANARY_SECONDS: Histogram<10> =
+    Histogram::new([0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0]);
+/// The proxied sessions.
+pub(crate) static SESSIONS: Counter = Counter::new();
+/// The proxied sessions still connected.
+pub(crate) static ACTIVE_SESSIONS: AtomicU64 = AtomicU64::new(0);
+/// The bytes proxied from the clients to chrome.
+pub(crate) static CLIENT_BYTES: Counter = Counter::new();
+/// The bytes proxied from chrome to the clients.
+pub(crate) static BROWSER_BYTES: Counter = Counter::new();
+/// The connection retries to chrome.
+pub(crate) static CONNECT_RETRIES: Counter = Counter::new();
+/// The lookups of the cached `/json/version`.
+pub(crate) static VERSION_CACHE_LOOKUPS: Counter = Counter::new();
+/// The lookups of the cached `/json/version` that fetched from chrome.
+pub(crate) static VERSION_CACHE_MISSES: Counter = Counter::new();
+/// The latency of the `/json/version` fetch from chrome.
+pub(crate) static VERSION_FETCH_SECONDS: Histogram<11> = Histogram::new([
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+]);
+/// The duration of the proxied sessions.
+pub(crate) static SESSION_DURATION_SECONDS: Histogram<10> = Histogram::new([
+    1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0,
+]);
+
+/// A proxied session counted as active until dropped.
+pub(crate) struct SessionMetrics {
+    /// When the session started.
+    started: Instant,
+}
+
+impl SessionMetrics {
+    /// Count a new proxied session.
+    pub fn start() -> Self {
+        SESSIONS.inc();
+        ACTIVE_SESSIONS.fetch_add(1, Ordering::Relaxed);
+
+        Self {
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Drop for SessionMetrics {
+    fn drop(&mut self) {
+        ACTIVE_SESSIONS.fetch_sub(1, Ordering::Relaxed);
+        SESSION_DURATION_SECONDS.observe(self.started.elapsed());
+    }
+}
+
+/// Write a counter or gauge with a single sample.
+fn render_sample(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// The metrics in the Prometheus text format.
+pub(crate) fn render() -> String {
+    let mut out = String::with_capacity(4096);
+    let usage = crate::instances::usage();
+
+    let running = usage.iter().filter(|usage| usage.running).count();
+
+    out.push_str("# HELP headless_browser_instances The chrome instances by state.\n");
+    out.push_str("# TYPE headless_browser_instances gauge\n");
+    let _ = writeln!(
+        out,
+        "headless_browser_instances{{state=\"running\"}} {}",
+        running
+    );
+    let _ = writeln!(
+        out,
+        "headless_browser_instances{{state=\"stopped\"}} {}",
+        usage.len() - running
+    );
+
+    out.push_str("# HELP headless_browser_forks_total The chrome instances launched by kind.\n");
+    out.push_str("# TYPE headless_browser_forks_total counter\n");
+    let _ = writeln!(
+        out,
+        "headless_browser_forks_total{{kind=\"fork\"}} {}",
+        FORKS.get()
+    );
+    let _ = writeln!(
+        out,
+        "headless_browser_forks_total{{kind=\"dedicated\"}} {}",
+        DEDICATED_LAUNCHES.get()
+    );
+
+    render_sample(
+        &mut out,
+        "headless_browser_restarts_total",
+        "counter",
+        "The chrome restarts after failed proxy connections.",
+        RESTARTS.get(),
+    );
+    render_sample(
+        &mut out,
+        "headless_browser_crashes_total",
+        "counter",
+        "The chrome instances that exited without a shutdown.",
+        CRASHES.get(),
+    );
+    render_sample(
+        &mut out,
+        "headless_browser_recycles_total",
+        "counter",
+        "The unhealthy chrome instances replaced after failed canary checks.",
+        RECYCLES.get(),
+    );
+    render_sample(
+        &mut out,
+        "headless_browser_canary_failures_total",
+        "counter",
+        "The failed or slow canary checks.",
+        CANARY_FAILURES.get(),
+    );
+    render_sample(
+        &mut out,
+        "headless_browser_sessions_active",
+        "gauge",
+        "The proxied sessions still connected.",
+        ACTIVE_SESSIONS.load(Ordering::Relaxed),
+    );
+    render_sample(
+        &mut out,
+        "headless_browser_sessions_total",
+        "counter",
+        "The proxied sessions.",
+        SESSIONS.get(),
+    );
+
+    out.push_str("# HELP headless_browser_proxied_bytes_total The bytes proxied by direction.\n");
+    out.push_str("# TYPE headless_browser_proxied_bytes_total counter\n");
+    let _ = writeln!(
+        out,
+        "headless_browser_proxied_bytes_total{{direction=\"client_to_browser\"}} {}",
+        CLIENT_BYTES.get()
+    );
+    let _ = writeln!(
+        out,
+        "headless_browser_proxied_bytes_total{{direction=\"browser_to_client\"}} {}",
+        BROWSER_BYTES.get()
+    );
+
+    render_sample(
+        &mut out,
+        "headless_browser_connect_retries_total",
+        "counter",
+        "The connection retries to chrome.",
+        CONNECT_RETRIES.get(),
+    );
+
+    let lookups = VERSION_CACHE_LOOKUPS.get();
+    let misses = VERSION_CACHE_MISSES.get();
+
+    render_sample(
+        &mut out,
+        "headless_browser_version_cache_hits_total",
+        "counter",
+        "The /json/version lookups served from the cache.",
+        lookups.saturating_sub(misses),
+    );
+    render_sample(
+        &mut out,
+        "headless_browser_version_cache_misses_total",
+        "counter",
+        "The /json/version lookups fetched from chrome.",
+        misses,
+    );
+
+    VERSION_FETCH_SECONDS.render(
+        &mut out,
+        "headless_browser_version_fetch_seconds",
+        "The latency of the /json/version fetch from chrome.",
+    );
+    CANARY_SECONDS.render(
+        &mut out,
+        "headless_browser_canary_seconds",
+        "The latency of the passed canary checks.",
+    );
+    SESSION_DURATION_SECONDS.render(
+        &mut out,
+        "headless_browser_session_duration_seconds",
+        "The duration of the proxied sessions.",
+    );
+
+    out.push_str("# HELP headless_browser_instance_resident_memory_bytes The resident memory of the chrome instance and its child processes.\n");
+    out.push_str("# TYPE headless_browser_instance_resident_memory_bytes gauge\n");
+    for usage in usage.iter() {
+        let _ = writeln!(
+            out,
+            "headless_browser_instance_resident_memory_bytes{{pid=\"{}\",kind=\"{}\"}} {}",
+            usage.pid, usage.kind, usage.memory
+        );
+    }
+
+    out.push_str("# HELP headless_browser_instance_cpu_seconds_total The cpu time of the chrome instance and its child processes.\n");
+    out.push_str("# TYPE headless_browser_instance_cpu_seconds_total counter\n");
+    for usage in usage.iter() {
+        let _ = writeln!(
+            out,
+            "headless_browser_instance_cpu_seconds_total{{pid=\"{}\",kind=\"{}\"}} {}",
+            usage.pid,
+            usage.kind,
+            usage.cpu_time as f64 / 1000.0
+        );
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::new([0.1, 1.0]);
+
+        histogram.observe(Duration::from_millis(50));
+        histogram.observe(Duration::from_millis(500));
+        histogram.observe(Duration::from_secs(5));
+
+        let mut out = String::new();
+        histogram.render(&mut out, "test_seconds", "A test.");
+
+        assert!(out.contains("test_seconds_bucket{le=\"0.1\"} 1\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"1\"} 2\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("test_seconds_sum 5.55\n"));
+        assert!(out.contains("test_seconds_count 3\n"));
+    }
+}
@@ -1,44 +1,300 @@
+use crate::listener::url_host;
 use hyper::body::Bytes;
+use hyper::HeaderMap;
+use serde_json::Value;
 
-/// modify the json output for the bytes hosting. The headless instance cannot accept external request so we use the proxy.
-pub(crate) fn modify_json_output(body_bytes: Bytes) -> Bytes {
-    let buffer = body_bytes.as_ref();
-    let target_host = b"127.0.0.1";
-    let replacement_host = crate::HOST_NAME.as_bytes();
+/// The url fields of the DevTools json output pointing at chrome.
+const URL_FIELDS: [&str; 2] = ["webSocketDebuggerUrl", "devtoolsFrontendUrl"];
 
-    let (target_port, replacement_port) = *crate::TARGET_REPLACEMENT;
+/// The public address clients use to reach the proxy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PublicAddress {
+    /// The websocket scheme, `ws` or `wss`.
+    pub scheme: &'static str,
+    /// The host and optional port, ex: `example.com:9222` or `[::1]:9222`.
+    pub authority: String,
+    /// The path prefix, ex: `/browsers/node-3`.
+    pub base_path: String,
+}
+
+/// Normalize a path prefix to a leading slash without a trailing slash. The root is empty.
+pub(crate) fn normalize_base_path(base_path: &str) -> String {
+    let base_path = base_path.trim().trim_matches('/');
+
+    if base_path.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", base_path)
+    }
+}
+
+/// Strip the `BASE_PATH` prefix from a request path, ex: `/browsers/node-3/json/version` to
+/// `/json/version`. Paths outside the prefix are returned as is.
+pub(crate) fn strip_base_path(path: &str) -> std::borrow::Cow<'_, str> {
+    strip_path_prefix(path, &crate::conf::BASE_PATH)
+}
+
+/// Strip the normalized path prefix from a request path. Paths outside the prefix are returned as
+/// is.
+pub(crate) fn strip_path_prefix<'a>(path: &'a str, base_path: &str) -> std::borrow::Cow<'a, str> {
+    match path.strip_prefix(base_path) {
+        Some(rest) if !base_path.is_empty() && rest.is_empty() => "/".into(),
+        Some(rest) if !base_path.is_empty() && rest.starts_with('/') => rest.into(),
+        _ => path.into(),
+    }
+}
+
+/// Split a host header value into the host and port, ex: `[::1]:6000` or `localhost:6000`.
+fn split_host_port(value: &str) -> (&str, Option<&str>) {
+    let value = value.trim();
+
+    match value.strip_prefix('[') {
+        Some(rest) => match rest.split_once(']') {
+            Some((host, port)) => (host, port.strip_prefix(':')),
+            _ => (value, None),
+        },
+        _ => match value.rsplit_once(':') {
+            Some((host, port))
+                if !host.contains(':') && port.bytes().all(|b| b.is_ascii_digit()) =>
+            {
+                (host, Some(port))
+            }
+            _ => (value, None),
+        },
+    }
+}
+
+/// The first value of a comma separated header.
+fn first_header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+/// The `host` and `proto` of the first `Forwarded` element, ex: `for=1.2.3.4;host=example.com;proto=https`.
+fn forwarded(headers: &HeaderMap) -> (Option<&str>, Option<&str>) {
+    let mut host = None;
+    let mut proto = None;
+
+    if let Some(element) = first_header(headers, "forwarded") {
+        for pair in element.split(';') {
+            if let Some((key, value)) = pair.split_once('=') {
+                let value = value.trim().trim_matches('"');
+                match key.trim().to_ascii_lowercase().as_str() {
+                    "host" => host = Some(value),
+                    "proto" => proto = Some(value),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    (host, proto)
+}
+
+impl PublicAddress {
+    /// Derive the public address from the request headers. The host comes from `Forwarded`,
+    /// `X-Forwarded-Host`, or `Host`, falling back to `HOSTNAME_OVERRIDE`. The port comes from
+    /// `X-Forwarded-Port` or an explicit forwarded host port, falling back to the proxy port since
+    /// the `Host` port is the control server. Returns None without a host.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        if let Some(public) = crate::conf::PUBLIC_URL.as_ref() {
+            return Some(public.clone());
+        }
+
+        let (forwarded_host, forwarded_proto) = forwarded(headers);
+
+        let forwarded_host = forwarded_host.or_else(|| first_header(headers, "x-forwarded-host"));
+        let proto = forwarded_proto.or_else(|| first_header(headers, "x-forwarded-proto"));
+
+        let (host, port) = match forwarded_host {
+            Some(forwarded_host) => split_host_port(forwarded_host),
+            _ => match first_header(headers, "host") {
+                Some(host) => (split_host_port(host).0, None),
+                _ => (crate::conf::HOST_NAME.as_str(), None),
+            },
+        };
+
+        if host.is_empty() {
+            return None;
+        }
+
+        let port = first_header(headers, "x-forwarded-port").or(port);
+
+        let secure = match proto.map(|proto| proto.to_ascii_lowercase()) {
+            Some(proto) => proto == "https" || proto == "wss",
+            _ => crate::tls::enabled(),
+        };
 
-    // Estimate a suitable capacity
-    let mut modified_buffer =
-        Vec::with_capacity(buffer.len() + (replacement_host.len() - target_host.len()));
+        let authority = match port {
+            Some(port) => format!("{}:{}", url_host(host), port),
+            _ => format!("{}{}", url_host(host), *crate::conf::PROXY_PUBLIC_PORT),
+        };
 
-    let mut start = 0;
+        Some(Self {
+            scheme: if secure { "wss" } else { "ws" },
+            authority,
+            base_path: crate::conf::BASE_PATH.clone(),
+        })
+    }
+
+    /// Parse an external base url, ex: `https://gw.example/browsers/node-3/`.
+    pub fn parse_url(url: &str) -> Option<Self> {
+        let (scheme, rest) = url.trim().split_once("://")?;
+
+        let secure = match scheme.to_ascii_lowercase().as_str() {
+            "https" | "wss" => true,
+            "http" | "ws" => false,
+            _ => return None,
+        };
+
+        let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+
+        if authority.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            scheme: if secure { "wss" } else { "ws" },
+            authority: authority.into(),
+            base_path: normalize_base_path(path),
+        })
+    }
+
+    /// Rewrite a chrome websocket url, ex: `ws://127.0.0.1:9223/devtools/browser/{id}`.
+    fn rewrite_ws_url(&self, url: &str) -> Option<String> {
+        let (_, rest) = url.split_once("://")?;
+        let path = rest.find('/').map_or("", |start| &rest[start..]);
+
+        Some(format!(
+            "{}://{}{}{}",
+            self.scheme, self.authority, self.base_path, path
+        ))
+    }
+
+    /// Rewrite the `ws=` param of a devtools frontend url, ex: `/devtools/inspector.html?ws=127.0.0.1:9223/devtools/page/{id}`.
+    fn rewrite_frontend_url(&self, url: &str) -> Option<String> {
+        let (base, query) = url.split_once('?')?;
+
+        let params = query
+            .split('&')
+            .map(|param| match param.split_once('=') {
+                Some(("ws", value)) | Some(("wss", value)) => {
+                    let path = value.find('/').map_or("", |start| &value[start..]);
+                    format!(
+                        "{}={}{}{}",
+                        self.scheme, self.authority, self.base_path, path
+                    )
+                }
+                _ => param.to_string(),
+            })
+            .collect::<Vec<_>>();
+
+        Some(format!("{}?{}", base, params.join("&")))
+    }
 
-    // Replace occurrences of the target host
-    while let Some(pos) = buffer[start..]
-        .windows(target_host.len())
-        .position(|window| window == target_host)
-    {
-        modified_buffer.extend_from_slice(&buffer[start..start + pos]);
-        modified_buffer.extend_from_slice(replacement_host);
-        start += pos + target_host.len();
+    /// Rewrite the url fields of a json object in place.
+    fn rewrite_object(&self, object: &mut serde_json::Map<String, Value>) {
+        for field in URL_FIELDS {
+            if let Some(Value::String(url)) = object.get_mut(field) {
+                let rewritten = if field == "devtoolsFrontendUrl" {
+                    self.rewrite_frontend_url(url)
+                } else {
+                    self.rewrite_ws_url(url)
+                };
+
+                if let Some(rewritten) = rewritten {
+                    *url = rewritten;
+                }
+            }
+        }
     }
-    modified_buffer.extend_from_slice(&buffer[start..]);
+}
 
-    // Now handle the port replacement
-    let mut final_buffer =
-        Vec::with_capacity(modified_buffer.len() + (replacement_port.len() - target_port.len()));
-    start = 0;
+/// Rewrite the target urls of the DevTools json output, ex: `/json/version` or `/json/list`, for
+/// the public address. Only the url fields are changed. Invalid json is returned as is.
+pub(crate) fn modify_json_output(body_bytes: Bytes, public: Option<&PublicAddress>) -> Bytes {
+    let public = match public {
+        Some(public) => public,
+        _ => return body_bytes,
+    };
 
-    while let Some(pos) = modified_buffer[start..]
-        .windows(target_port.len())
-        .position(|window| window == target_port)
-    {
-        final_buffer.extend_from_slice(&modified_buffer[start..start + pos]);
-        final_buffer.extend_from_slice(replacement_port);
-        start += pos + target_port.len();
+    let mut json = match serde_json::from_slice::<Value>(&body_bytes) {
+        Ok(json) => json,
+        _ => return body_bytes,
+    };
+
+    match &mut json {
+        Value::Object(object) => public.rewrite_object(object),
+        Value::Array(targets) => {
+            for target in targets.iter_mut() {
+                if let Value::Object(object) = target {
+                    public.rewrite_object(object);
+                }
+            }
+        }
+        _ => return body_bytes,
     }
-    final_buffer.extend_from_slice(&modified_buffer[start..]);
 
-    final_buffer.into()
+    serde_json::to_vec_pretty(&json)
+        .map(Bytes::from)
+        .unwrap_or(body_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_modify_json_output() {
+        let mut headers = HeaderMap::new();
+        headers.insert("host", "localhost:6000".parse().unwrap());
+        headers.insert(
+            "forwarded",
+            "for=10.0.0.1;host=\"gw.example:443\";proto=https"
+                .parse()
+                .unwrap(),
+        );
+
+        let public = PublicAddress::from_headers(&headers);
+        let body = Bytes::from_static(
+            br#"[{"title":"127.0.0.1:9223","devtoolsFrontendUrl":"/devtools/inspector.html?ws=127.0.0.1:9223/devtools/page/A1","webSocketDebuggerUrl":"ws://127.0.0.1:9223/devtools/page/A1"}]"#,
+        );
+
+        let json: Value =
+            serde_json::from_slice(&modify_json_output(body, public.as_ref())).unwrap();
+
+        assert_eq!(json[0]["title"], "127.0.0.1:9223");
+        assert_eq!(
+            json[0]["webSocketDebuggerUrl"],
+            "wss://gw.example:443/devtools/page/A1"
+        );
+        assert_eq!(
+            json[0]["devtoolsFrontendUrl"],
+            "/devtools/inspector.html?wss=gw.example:443/devtools/page/A1"
+        );
+
+        let public = PublicAddress::parse_url("https://gw.example/browsers/node-3/");
+        let body = Bytes::from_static(
+            br#"{"webSocketDebuggerUrl":"ws://127.0.0.1:9223/devtools/browser/B1"}"#,
+        );
+        let json: Value =
+            serde_json::from_slice(&modify_json_output(body, public.as_ref())).unwrap();
+
+        assert_eq!(
+            json["webSocketDebuggerUrl"],
+            "wss://gw.example/browsers/node-3/devtools/browser/B1"
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert("host", "[::1]:6000".parse().unwrap());
+
+        assert_eq!(
+            PublicAddress::from_headers(&headers).map(|public| public.authority),
+            Some(format!("[::1]{}", *crate::conf::PROXY_PUBLIC_PORT))
+        );
+    }
 }
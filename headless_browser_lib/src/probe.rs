@@ -0,0 +1,224 @@
+use crate::cdp::CdpClient;
+use crate::conf::{CHROME_ADDRESS, READINESS_MAX_AGE, READINESS_PROBE_INTERVAL, TARGET};
+use serde_json::Value;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The max time of a single probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The result of the last CDP probe of an instance.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ProbeResult {
+    /// The process id. None for the proxy target chrome not launched by the server.
+    pub pid: Option<u32>,
+    /// The debugging address, ex: `127.0.0.1:9223`.
+    pub address: String,
+    /// Did the last probe complete the round trip.
+    pub ok: bool,
+    /// The latency of the last probe.
+    pub latency: Duration,
+    /// When the last probe ran in unix seconds.
+    pub checked_at: u64,
+    /// When the last probe succeeded in unix seconds.
+    pub last_success: Option<u64>,
+    /// The browser product of the last successful probe, ex: `HeadlessChrome/131.0.6778.139`.
+    pub browser: Option<String>,
+    /// The error of the last failed probe.
+    pub error: Option<String>,
+}
+
+lazy_static::lazy_static! {
+    /// The last probe results by address.
+    static ref PROBES: dashmap::DashMap<String, ProbeResult> = dashmap::DashMap::new();
+}
+
+/// The current unix time in seconds.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// The local debugging address of an instance port.
+pub(crate) fn instance_address(port: u32) -> String {
+    let host = match CHROME_ADDRESS.as_str() {
+        "" | "0.0.0.0" => "127.0.0.1",
+        "::" | "[::]" => "[::1]",
+        host => host,
+    };
+
+    format!("{}:{}", crate::listener::url_host(host), port)
+}
+
+/// Complete a `Browser.getVersion` round trip over the browser websocket returning the product.
+pub(crate) async fn probe(address: &str) -> std::io::Result<String> {
+    let round_trip = async {
+        let path = crate::launch::browser_path(address).await?;
+        let mut client = CdpClient::connect(address, &path).await?;
+        let version = client
+            .send("Browser.getVersion", serde_json::json!({}), None)
+            .await?;
+
+        Ok(version
+            .get("product")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string())
+    };
+
+    tokio::time::timeout(PROBE_TIMEOUT, round_trip)
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "probe timed out"))?
+}
+
+/// The instances to probe. The proxy target is probed when no instance was launched, ex: chrome
+/// started outside of the server.
+fn targets() -> Vec<(Option<u32>, String)> {
+    let targets = crate::instances::ports()
+        .into_iter()
+        .filter_map(|(pid, port)| port.map(|port| (Some(pid), instance_address(port))))
+        .collect::<Vec<_>>();
+
+    if targets.is_empty() {
+        vec![(None, TARGET.to_string())]
+    } else {
+        targets
+    }
+}
+
+/// Probe all the instances once and store the results.
+pub(crate) async fn probe_all() {
+    let targets = targets();
+    let mut probes = tokio::task::JoinSet::new();
+
+    for (pid, address) in targets.iter().cloned() {
+        probes.spawn(async move {
+            let started = Instant::now();
+            let result = probe(&address).await;
+            (pid, address, started.elapsed(), result)
+        });
+    }
+
+    while let Some(Ok((pid, address, latency, result))) = probes.join_next().await {
+        let last_success = PROBES
+            .get(&address)
+            .filter(|previous| previous.pid == pid)
+            .and_then(|previous| previous.last_success);
+        let checked_at = now();
+
+        let probe = match result {
+            Ok(browser) => {
+                // the first round trip of the instance.
+                if last_success.is_none() {
+                    crate::events::emit(crate::events::Event::InstanceReady {
+                        pid,
+                        address: address.clone(),
+                    });
+                }
+
+                ProbeResult {
+                    pid,
+                    address: address.clone(),
+                    ok: true,
+                    latency,
+                    checked_at,
+                    last_success: Some(checked_at),
+                    browser: Some(browser),
+                    error: None,
+                }
+            }
+            Err(e) => {
+                tracing::debug!("Readiness probe of {} failed: {}", address, e);
+                ProbeResult {
+                    pid,
+                    address: address.clone(),
+                    ok: false,
+                    latency,
+                    checked_at,
+                    last_success,
+                    browser: None,
+                    error: Some(e.to_string()),
+                }
+            }
+        };
+
+        PROBES.insert(address, probe);
+    }
+
+    PROBES.retain(|address, _| targets.iter().any(|(_, target)| target == address));
+}
+
+/// Probe the instances on the readiness interval.
+pub(crate) async fn monitor() {
+    let mut interval = tokio::time::interval(*READINESS_PROBE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        probe_all().await;
+    }
+}
+
+/// Did an instance complete a CDP round trip within `READINESS_MAX_AGE`.
+pub(crate) fn is_ready() -> bool {
+    if crate::conf::DRAINING.load(std::sync::atomic::Ordering::Relaxed) {
+        return false;
+    }
+
+    let now = now();
+
+    PROBES.iter().any(|probe| {
+        probe
+            .last_success
+            .is_some_and(|last_success| now.saturating_sub(last_success) <= *READINESS_MAX_AGE)
+    })
+}
+
+/// The readiness with the last probe of each instance.
+pub(crate) fn details() -> Value {
+    let mut probes = PROBES.iter().map(|probe| probe.clone()).collect::<Vec<_>>();
+    probes.sort_by(|a, b| a.address.cmp(&b.address));
+
+    serde_json::json!({
+        "ready": is_ready(),
+        "instances": probes
+            .iter()
+            .map(|probe| serde_json::json!({
+                "pid": probe.pid,
+                "address": probe.address,
+                "ok": probe.ok,
+                "latencyMs": probe.latency.as_millis() as u64,
+                "checkedAt": probe.checked_at,
+                "lastSuccessAt": probe.last_success,
+                "browser": probe.browser,
+                "error": probe.error,
+            }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::serve_recording;
+
+    #[tokio::test]
+    async fn test_probe() {
+        let recording = r#"{"timestamp":1,"direction":"send","session":"1","id":1,"method":"Browser.getVersion"}
+{"timestamp":2,"direction":"receive","session":"1","id":1,"method":"Browser.getVersion","result":{"product":"HeadlessChrome/131.0.6778.139"}}"#;
+
+        let address = serve_recording(recording).await;
+
+        assert_eq!(
+            probe(&address).await.expect("round trip"),
+            "HeadlessChrome/131.0.6778.139"
+        );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind");
+        let closed = listener.local_addr().expect("address").to_string();
+        drop(listener);
+
+        assert!(probe(&closed).await.is_err());
+    }
+}
@@ -1,46 +1,128 @@
 pub(crate) mod proxy {
-    use crate::conf::{BUFFER_SIZE, ENTRY, TARGET, TEN_SECONDS};
-    use crate::{connect_with_retries, fork, shutdown_instances, CACHEABLE, LAST_CACHE};
-    use std::{io::ErrorKind, time::Instant};
+    use crate::auth::{self, Claims, TenantSession};
+    use crate::conf::{
+        BASE_PATH, BUFFER_SIZE, CDP_RECORD, CHROME_RESTART_TIMEOUT, CLOSE_LEAKED_TARGETS, DRAINING,
+        ISOLATE_CONTEXTS, PROXY_LISTEN, SESSION_SIGNING_KEY, TARGET, TEN_SECONDS,
+    };
+    use crate::launch::{browser_path, DedicatedInstance, LaunchOptions, LAUNCH_PARAMS};
+    use crate::listener::{Listener, Stream};
+    use crate::proxy_protocol::client_info;
+    use crate::record::Recorder;
+    use crate::session::Session;
+    use crate::signing::{self, SessionLimits};
+    use crate::ws::{read_head, RequestHead};
+    use crate::ClientInfo;
+    use crate::{
+        connect_with_retries, forbidden_route, fork, shutdown_instance, CACHEABLE, LAST_CACHE,
+    };
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::{
+        io::ErrorKind,
+        time::{Duration, Instant},
+    };
     use tokio::{
         io::{AsyncReadExt, AsyncWriteExt},
-        net::{TcpListener, TcpStream},
+        net::TcpStream,
+        sync::Mutex,
     };
 
+    /// The max time to release the session resources after the client disconnects.
+    const SESSION_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// The proxied session counter.
+    static SESSION_ID: AtomicU64 = AtomicU64::new(1);
+    /// Only one client restarts chrome at a time. Canary recycling holds it too.
+    pub(crate) static RESTART_LOCK: Mutex<()> = Mutex::const_new(());
+
     /// Run the proxy forwarder for chrome. This allows connecting to chrome outside of the network.
     pub async fn run_proxy() -> std::io::Result<()> {
-        let listener = TcpListener::bind(*ENTRY).await?;
-        println!("Proxy Listening on {}", *ENTRY);
         let base_time = Instant::now();
+        let mut listeners = tokio::task::JoinSet::new();
+
+        let addrs = PROXY_LISTEN
+            .as_ref()
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e.as_str()))?;
+
+        for addr in addrs {
+            let listener = Listener::bind(addr).await?;
+            println!("Proxy Listening on {}", addr);
+            listeners.spawn(accept_connections(listener, base_time));
+        }
+
+        while let Some(result) = listeners.join_next().await {
+            if let Ok(Err(err)) = result {
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
 
+    /// Accept and proxy the connections of a listener.
+    async fn accept_connections(listener: Listener, base_time: Instant) -> std::io::Result<()> {
         loop {
-            let (mut client_stream, client_addr) = listener.accept().await?;
-            tracing::info!("Accepted connection from {}", client_addr);
+            let (mut client_stream, peer_addr) = listener.accept().await?;
 
             tokio::spawn(async move {
-                let mut should_retry = false;
-
-                if let Err(err) = handle_connection(&mut client_stream).await {
-                    if err.kind() == ErrorKind::NotConnected || err.kind() == ErrorKind::Other {
-                        should_retry = true;
-                        tracing::error!("Error handling connection: {}. Restarting Chrome.", err);
-                        // send a signal instead or channel to prevent race
-                        // todo: we need to swap to a new port and use a LB to track the drain to reset the ports used.
-                        shutdown_instances().await;
-                        fork(Some(*crate::DEFAULT_PORT));
-                        CACHEABLE.store(false, std::sync::atomic::Ordering::Relaxed);
-                        LAST_CACHE.store(
-                            base_time.elapsed().as_secs().try_into().unwrap_or_default(),
-                            std::sync::atomic::Ordering::Relaxed,
-                        );
-                    } else {
-                        // ignore connection resets by peer
-                        if err.kind() != ErrorKind::ConnectionReset {
-                            tracing::error!("Error handling connection: {}", err);
-                        }
+                let client = match client_info(&mut client_stream, peer_addr).await {
+                    Ok(client) => client,
+                    Err(err) => {
+                        tracing::warn!("Rejected connection: {}", err);
+                        return;
                     }
-                } else if !CACHEABLE.load(std::sync::atomic::Ordering::Relaxed) {
-                    let elasped = LAST_CACHE.load(std::sync::atomic::Ordering::Relaxed);
+                };
+
+                let mut client_stream = match crate::tls::accept(client_stream).await {
+                    Ok(client_stream) => client_stream,
+                    Err(err) => {
+                        tracing::warn!("TLS handshake failed for {}: {}", client, err);
+                        return;
+                    }
+                };
+
+                let mut client = client;
+                client.identity = crate::tls::client_identity(&client_stream);
+
+                tracing::info!("Accepted connection from {}", client);
+
+                match read_head(&mut client_stream).await {
+                    Ok((head, rest)) => {
+                        proxy_connection(
+                            &mut client_stream,
+                            &client,
+                            head,
+                            rest,
+                            base_time,
+                            *TARGET,
+                        )
+                        .await
+                    }
+                    Err(err) => tracing::error!("Error handling connection: {}", err),
+                }
+            });
+        }
+    }
+
+    /// Proxy a client connection after the request head was read to the shared chrome at the
+    /// target address.
+    pub(crate) async fn proxy_connection(
+        client_stream: &mut Stream,
+        client: &ClientInfo,
+        head: Vec<u8>,
+        rest: Vec<u8>,
+        base_time: Instant,
+        target: &str,
+    ) {
+        match handle_connection(client_stream, client, head, rest, base_time, target).await {
+            Err(err) => {
+                // ignore connection resets by peer
+                if err.kind() != ErrorKind::ConnectionReset {
+                    tracing::error!("Error handling connection: {}", err);
+                }
+            }
+            _ => {
+                if !CACHEABLE.load(Ordering::Relaxed) {
+                    let elasped = LAST_CACHE.load(Ordering::Relaxed);
 
                     if elasped > 0 {
                         let elapsed_since_base = base_time.elapsed();
@@ -48,24 +130,506 @@ pub(crate) mod proxy {
                             tokio::time::Duration::from_secs(elasped) + elapsed_since_base;
 
                         if total_elapsed >= *TEN_SECONDS {
-                            CACHEABLE.store(true, std::sync::atomic::Ordering::Relaxed);
+                            CACHEABLE.store(true, Ordering::Relaxed);
                         }
                     }
                 }
+            }
+        }
+    }
+
+    /// Restart chrome and park the client until the replacement accepts connections. Clients that
+    /// fail at the same time wait on the same restart instead of restarting chrome again.
+    async fn restart_and_connect(base_time: Instant) -> Option<TcpStream> {
+        let _restart = RESTART_LOCK.lock().await;
+
+        // another client restarted chrome while we waited.
+        if let Ok(stream) = TcpStream::connect(*TARGET).await {
+            return Some(stream);
+        }
+
+        if crate::instances::crash_loop_tripped() {
+            tracing::error!(
+                "Failed to connect to chrome. Restarts are paused by the crash loop breaker."
+            );
+            return None;
+        }
+
+        tracing::error!("Failed to connect to chrome. Restarting Chrome.");
+        crate::metrics::RESTARTS.inc();
+
+        // only the instance behind the target is replaced, the other forks and dedicated
+        // instances keep their sessions.
+        let target_port = TARGET
+            .rsplit(':')
+            .next()
+            .and_then(|port| port.parse::<u32>().ok());
+
+        for (pid, port) in crate::instances::ports() {
+            if port.is_some() && port == target_port {
+                shutdown_instance(pid);
+            }
+        }
+
+        fork(Some(*crate::DEFAULT_PORT));
+        CACHEABLE.store(false, Ordering::Relaxed);
+        LAST_CACHE.store(base_time.elapsed().as_secs(), Ordering::Relaxed);
+
+        wait_for_target(*TARGET, *CHROME_RESTART_TIMEOUT).await
+    }
+
+    /// Poll the address until it accepts a connection or the timeout passes.
+    async fn wait_for_target(address: &str, timeout: Duration) -> Option<TcpStream> {
+        let deadline = Instant::now() + timeout;
+
+        while Instant::now() < deadline {
+            if let Ok(stream) = TcpStream::connect(address).await {
+                return Some(stream);
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        None
+    }
+
+    /// Is the query or env flag enabled.
+    fn enabled(value: &str) -> bool {
+        value == "true" || value == "1"
+    }
+
+    /// Strip the base path prefix from the request head.
+    fn strip_base_path(head: Vec<u8>, base_path: &str) -> Vec<u8> {
+        if base_path.is_empty() {
+            return head;
+        }
+
+        match RequestHead::parse(&head) {
+            Some(mut request) => {
+                let path = crate::modify::strip_path_prefix(&request.path, base_path).into_owned();
+                if path != request.path {
+                    request.path = path;
+                    return request.to_bytes();
+                }
+                head
+            }
+            _ => head,
+        }
+    }
+
+    /// Verify the signed session token when the signing key is set, removing it from the request
+    /// before it is forwarded. Single use tokens are redeemed before any byte reaches chrome.
+    fn verify_session(key: &str, head: Vec<u8>) -> Result<(Vec<u8>, SessionLimits), &'static str> {
+        if key.is_empty() {
+            return Ok((head, SessionLimits::default()));
+        }
+
+        let mut request = RequestHead::parse(&head).ok_or("malformed request")?;
+        let token = request
+            .query_param(signing::TOKEN_PARAM)
+            .ok_or("missing token")?;
+        let limits = signing::verify(key.as_bytes(), &token, request.header("origin"))?;
+
+        request.remove_query_params(&[signing::TOKEN_PARAM]);
+
+        Ok((request.to_bytes(), limits))
+    }
+
+    /// Verify the JWT bearer token from the `Authorization` header or the `access_token` query
+    /// param when JWT auth is enabled, removing it from the request before it is forwarded.
+    /// Without JWT auth the client certificate identity is the tenant.
+    fn authorize(
+        head: Vec<u8>,
+        client: &ClientInfo,
+    ) -> Result<(Vec<u8>, Option<Claims>), &'static str> {
+        if !auth::enabled() {
+            let claims = client.identity.as_deref().map(auth::identity_claims);
+            return Ok((head, claims));
+        }
+
+        let mut request = RequestHead::parse(&head).ok_or("malformed request")?;
+        let token = auth::bearer_token(request.header("authorization"))
+            .map(String::from)
+            .or_else(|| request.query_param(auth::TOKEN_PARAM))
+            .ok_or("missing token")?;
+        let claims = auth::verify(&token)?;
+
+        request.remove_query_params(&[auth::TOKEN_PARAM]);
+        request
+            .headers
+            .retain(|(key, _)| !key.eq_ignore_ascii_case("authorization"));
+
+        Ok((request.to_bytes(), Some(claims)))
+    }
 
-                if should_retry {
-                    tokio::task::yield_now().await;
-                    let _ = handle_connection(&mut client_stream).await;
+    /// Launch a dedicated chrome when the client requests launch options that differ from the
+    /// shared instance. Returns the request head routed to the dedicated instance.
+    async fn launch_instance(
+        head: Vec<u8>,
+        claims: Option<&Claims>,
+    ) -> std::io::Result<(Vec<u8>, Option<DedicatedInstance>)> {
+        let mut request = match RequestHead::parse(&head) {
+            Some(request) if request.is_upgrade() => request,
+            _ => return Ok((head, None)),
+        };
+
+        let options = match LaunchOptions::from_request(&request)? {
+            Some(options) => options,
+            _ => return Ok((head, None)),
+        };
+
+        if let Some(claims) = claims {
+            for (key, _) in request.query_params() {
+                if LAUNCH_PARAMS.contains(&key.as_str()) && !claims.allows_launch_option(&key) {
+                    return Err(std::io::Error::new(
+                        ErrorKind::PermissionDenied,
+                        format!("launch option {} is not allowed", key),
+                    ));
                 }
-            });
+            }
+        }
+
+        request.remove_query_params(&LAUNCH_PARAMS);
+
+        if options.is_shared() {
+            // connections to the bare host, ex: `ws://host:9222?stealth=true`.
+            if !request.path.starts_with("/devtools/") {
+                request.path = browser_path(*TARGET).await?;
+            }
+            return Ok((request.to_bytes(), None));
+        }
+
+        if !crate::instances::has_capacity() {
+            return Err(std::io::Error::new(
+                ErrorKind::ResourceBusy,
+                "instance capacity exhausted",
+            ));
         }
+
+        let instance = DedicatedInstance::launch(&options).await?;
+
+        request.path = instance.browser_path.clone();
+
+        Ok((request.to_bytes(), Some(instance)))
     }
 
-    /// Handle the proxy connection.
-    async fn handle_connection(client_stream: &mut TcpStream) -> std::io::Result<()> {
-        let server_stream: Option<TcpStream> = connect_with_retries(*TARGET).await;
+    /// Prepare the session for the client request head returning the bytes to forward.
+    async fn prepare_session(
+        id: &str,
+        head: Vec<u8>,
+        address: &str,
+        dedicated: bool,
+        claims: Option<&Claims>,
+    ) -> std::io::Result<(Vec<u8>, Session)> {
+        match RequestHead::parse(&head) {
+            Some(mut request) if request.is_upgrade() => {
+                // the params can only opt in to recording and isolation enforced by the operator,
+                // and only admin tokens can record a session.
+                let record = *CDP_RECORD
+                    || (claims.is_some_and(|claims| claims.admin)
+                        && request
+                            .query_param("record")
+                            .is_some_and(|value| enabled(&value)));
+                let isolate = *ISOLATE_CONTEXTS
+                    || request
+                        .query_param("isolate")
+                        .is_some_and(|value| enabled(&value));
+
+                request.remove_query_params(&["record", "isolate"]);
+
+                let recorder = if record {
+                    match Recorder::create(id) {
+                        Ok(recorder) => Some(recorder),
+                        Err(e) => {
+                            tracing::error!("Failed to create the CDP recording: {:?}", e);
+                            None
+                        }
+                    }
+                } else {
+                    None
+                };
+
+                let mut session = Session::new(recorder);
+                let tenant = claims.map(|claims| claims.tenant.as_str());
+
+                if let Some(domains) = claims.and_then(|claims| claims.allowed_domains.clone()) {
+                    session.restrict_domains(domains);
+                }
+
+                // only browser connections can create targets.
+                if request.path.starts_with("/devtools/browser/") {
+                    // dedicated instances are torn down with the session.
+                    if *CLOSE_LEAKED_TARGETS && !dedicated {
+                        session.track_targets(address, &request.path);
+                    }
+                    if isolate {
+                        session.isolate(address, &request.path, tenant).await?;
+                    }
+                } else if isolate && request.path.starts_with("/devtools/") {
+                    // page and worker connections reach the target directly.
+                    let target_id = request.path.rsplit('/').next().unwrap_or_default();
+
+                    if !crate::session::owns_target(address, target_id, tenant).await? {
+                        return Err(std::io::Error::new(
+                            ErrorKind::PermissionDenied,
+                            "the target is outside the isolated session",
+                        ));
+                    }
+                }
+
+                // compressed frames cannot be inspected, the client falls back to plain frames.
+                if session.is_active() {
+                    request
+                        .headers
+                        .retain(|(key, _)| !key.eq_ignore_ascii_case("sec-websocket-extensions"));
+                }
+
+                Ok((request.to_bytes(), session))
+            }
+            _ => Ok((head, Session::new(None))),
+        }
+    }
+
+    /// A plain text http error response for clients rejected before the upgrade.
+    fn error_response(status: &str, message: &str) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+            status,
+            message.len(),
+            if status.starts_with("503") {
+                "Retry-After: 1\r\n"
+            } else {
+                ""
+            },
+            message
+        )
+        .into_bytes()
+    }
+
+    /// Forward a plain http request like `GET /json/version` to chrome. Restricted tenants get a
+    /// 403 and the connection closes after the response so later requests skip no checks.
+    async fn forward_request(
+        client_stream: &mut Stream,
+        mut request: RequestHead,
+        mut rest: Vec<u8>,
+        claims: Option<&Claims>,
+        base_time: Instant,
+        target: &str,
+    ) -> std::io::Result<()> {
+        if let Some(claims) = claims {
+            if claims.restricted() || forbidden_route(&request.method, &request.path, claims) {
+                client_stream
+                    .write_all(&error_response(
+                        "403 Forbidden",
+                        "The token is not allowed to call the route.",
+                    ))
+                    .await?;
+                return Err(std::io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("Rejected {} for tenant {}", request.path, claims.tenant),
+                ));
+            }
+        }
+
+        let server_stream = match connect_with_retries(target).await {
+            Some(server_stream) => Some(server_stream),
+            _ => restart_and_connect(base_time).await,
+        };
+
+        let Some(mut server_stream) = server_stream else {
+            client_stream.write_all(&restarting_response()).await?;
+            return Err(std::io::Error::other(
+                "Chrome was not ready before the restart timeout",
+            ));
+        };
+
+        // only the body of this request is forwarded, pipelined requests are dropped.
+        let length = request
+            .header("content-length")
+            .and_then(|length| length.trim().parse::<usize>().ok())
+            .unwrap_or_default();
+        rest.truncate(length);
+
+        request.headers.retain(|(key, _)| {
+            !key.eq_ignore_ascii_case("connection") && !key.eq_ignore_ascii_case("keep-alive")
+        });
+        request.headers.push(("Connection".into(), "close".into()));
+
+        server_stream.write_all(&request.to_bytes()).await?;
+        server_stream.write_all(&rest).await?;
+        tokio::io::copy(&mut server_stream, client_stream).await?;
+
+        Ok(())
+    }
+
+    /// The response to clients parked until the restart timeout without chrome coming back.
+    fn restarting_response() -> Vec<u8> {
+        error_response("503 Service Unavailable", "Chrome is restarting.")
+    }
+
+    /// The response of a rejected session token. Replayed single use tokens are forbidden.
+    fn session_rejection(reason: &str) -> Vec<u8> {
+        if reason == signing::USED_TOKEN {
+            error_response("403 Forbidden", "The session token was already used.")
+        } else {
+            error_response("401 Unauthorized", "Invalid session token.")
+        }
+    }
+
+    /// Handle the proxy connection. The client handshake is buffered before connecting so it can
+    /// be replayed to a restarted chrome instance.
+    async fn handle_connection(
+        client_stream: &mut Stream,
+        client: &ClientInfo,
+        head: Vec<u8>,
+        rest: Vec<u8>,
+        base_time: Instant,
+        target: &str,
+    ) -> std::io::Result<()> {
+        if head.is_empty() {
+            return Ok(());
+        }
+
+        if DRAINING.load(Ordering::Relaxed) {
+            client_stream
+                .write_all(&error_response(
+                    "503 Service Unavailable",
+                    "The node is draining.",
+                ))
+                .await?;
+            return Ok(());
+        }
+
+        let head = strip_base_path(head, &BASE_PATH);
+
+        let (head, limits) = match verify_session(&SESSION_SIGNING_KEY, head) {
+            Ok(verified) => verified,
+            Err(reason) => {
+                client_stream.write_all(&session_rejection(reason)).await?;
+                return Err(std::io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("Rejected session from {}: {}", client, reason),
+                ));
+            }
+        };
+
+        let deadline = limits
+            .max_duration
+            .and_then(|max_duration| tokio::time::Instant::now().checked_add(max_duration));
+
+        let (head, claims) = match authorize(head, client) {
+            Ok(authorized) => authorized,
+            Err(reason) => {
+                client_stream
+                    .write_all(&error_response("401 Unauthorized", "Invalid bearer token."))
+                    .await?;
+                return Err(std::io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("Rejected bearer token from {}: {}", client, reason),
+                ));
+            }
+        };
+
+        // the tenant session is released when the connection ends.
+        let _tenant_session = match claims.as_ref().map(TenantSession::acquire).transpose() {
+            Ok(tenant_session) => tenant_session,
+            Err(reason) => {
+                client_stream
+                    .write_all(&error_response(
+                        "429 Too Many Requests",
+                        "Too many sessions for the tenant.",
+                    ))
+                    .await?;
+                return Err(std::io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("Rejected tenant session from {}: {}", client, reason),
+                ));
+            }
+        };
+
+        if let Some(claims) = claims.as_ref() {
+            tracing::info!(
+                "Authorized session from {} for tenant {}",
+                client,
+                claims.tenant
+            );
+        }
+
+        if let Some(request) = RequestHead::parse(&head).filter(|request| !request.is_upgrade()) {
+            return forward_request(
+                client_stream,
+                request,
+                rest,
+                claims.as_ref(),
+                base_time,
+                target,
+            )
+            .await;
+        }
+
+        let (head, instance) = match launch_instance(head, claims.as_ref()).await {
+            Ok(launched) => launched,
+            Err(err) => {
+                let response = if err.kind() == ErrorKind::InvalidInput {
+                    error_response("400 Bad Request", &err.to_string())
+                } else if err.kind() == ErrorKind::PermissionDenied {
+                    error_response("403 Forbidden", &err.to_string())
+                } else if err.kind() == ErrorKind::ResourceBusy {
+                    error_response("503 Service Unavailable", &err.to_string())
+                } else {
+                    error_response("502 Bad Gateway", "Failed to launch chrome.")
+                };
+                client_stream.write_all(&response).await?;
+                return Err(err);
+            }
+        };
+
+        let address = instance
+            .as_ref()
+            .map_or(target, |instance| instance.address.as_str());
+
+        let server_stream = match connect_with_retries(address).await {
+            Some(server_stream) => Some(server_stream),
+            _ if instance.is_none() => restart_and_connect(base_time).await,
+            _ => None,
+        };
 
         if let Some(mut server_stream) = server_stream {
+            let _session_metrics = crate::metrics::SessionMetrics::start();
+            let id = SESSION_ID.fetch_add(1, Ordering::Relaxed).to_string();
+            let (head, mut session) = match prepare_session(
+                &id,
+                head,
+                address,
+                instance.is_some(),
+                claims.as_ref(),
+            )
+            .await
+            {
+                Ok(prepared) => prepared,
+                Err(err) => {
+                    let response = if err.kind() == ErrorKind::PermissionDenied {
+                        error_response("403 Forbidden", &err.to_string())
+                    } else {
+                        error_response("502 Bad Gateway", "Failed to prepare the session.")
+                    };
+                    client_stream.write_all(&response).await?;
+                    return Err(err);
+                }
+            };
+            let _session_events = crate::events::SessionEvents::open(
+                &id,
+                claims.as_ref().map(|claims| claims.tenant.as_str()),
+            );
+
+            server_stream.write_all(&head).await?;
+
+            if !rest.is_empty() {
+                match session.on_client_bytes(&rest) {
+                    Some(rest) => server_stream.write_all(&rest).await?,
+                    _ => server_stream.write_all(&rest).await?,
+                }
+            }
+
             let buffer_size = *BUFFER_SIZE;
             let mut buf1 = vec![0u8; buffer_size];
             let mut buf2 = vec![0u8; buffer_size];
@@ -80,9 +644,24 @@ pub(crate) mod proxy {
                         if size == 0 {
                             break;
                         }
-                        if let Err(_) = client_stream.write_all(&buf1[..size]).await  {
+                        crate::metrics::BROWSER_BYTES.add(size as u64);
+                        let written = match session.on_server_bytes(&buf1[..size]) {
+                            Some(bytes) => client_stream.write_all(&bytes).await,
+                            _ => client_stream.write_all(&buf1[..size]).await,
+                        };
+                        if written.is_err() || session.is_blocked() {
                             break;
                         }
+                        if let Some(commands) = session.take_server_commands() {
+                            if server_stream.write_all(&commands).await.is_err() {
+                                break;
+                            }
+                        }
+                        if let Some(replies) = session.take_client_replies() {
+                            if client_stream.write_all(&replies).await.is_err() {
+                                break;
+                            }
+                        }
                     },
                     b = client_stream.read(&mut buf2) => {
                         let size = match b {
@@ -92,9 +671,28 @@ pub(crate) mod proxy {
                         if size == 0 {
                             break;
                         }
-                        if let Err(_) = server_stream.write_all(&buf2[..size]).await {
+                        crate::metrics::CLIENT_BYTES.add(size as u64);
+                        let written = match session.on_client_bytes(&buf2[..size]) {
+                            Some(bytes) => server_stream.write_all(&bytes).await,
+                            _ => server_stream.write_all(&buf2[..size]).await,
+                        };
+                        if written.is_err() || session.is_blocked() {
                             break;
                         }
+                        if let Some(replies) = session.take_client_replies() {
+                            if client_stream.write_all(&replies).await.is_err() {
+                                break;
+                            }
+                        }
+                    },
+                    _ = async {
+                        match deadline {
+                            Some(deadline) => tokio::time::sleep_until(deadline).await,
+                            _ => std::future::pending().await,
+                        }
+                    } => {
+                        tracing::info!("Closing the session at the max duration");
+                        break;
                     },
                     else => {
                         break;
@@ -102,12 +700,166 @@ pub(crate) mod proxy {
                 }
             }
 
+            // a hung chrome cannot hold the session open.
+            if tokio::time::timeout(SESSION_CLOSE_TIMEOUT, session.close())
+                .await
+                .is_err()
+            {
+                tracing::warn!("Timed out releasing the session {}", id);
+            }
+
             Ok(())
         } else {
-            Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Failed to connect after several attempts",
+            client_stream.write_all(&restarting_response()).await?;
+
+            Err(std::io::Error::other(
+                "Chrome was not ready before the restart timeout",
             ))
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[tokio::test]
+        async fn test_park_until_restarted() {
+            let recording = r#"{"timestamp":1,"direction":"send","session":"1","id":1,"method":"Browser.getVersion"}
+{"timestamp":2,"direction":"receive","session":"1","id":1,"method":"Browser.getVersion","result":{"product":"HeadlessChrome/131.0.6778.139"}}"#;
+
+            // the handshake and first command buffered before chrome came back.
+            let head = b"GET /devtools/browser/replay HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+            let rest = crate::ws::encode_frame(
+                crate::ws::OPCODE_TEXT,
+                br#"{"id":1,"method":"Browser.getVersion"}"#,
+                Some([1, 2, 3, 4]),
+            );
+
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+                .await
+                .expect("bind");
+            let address = listener.local_addr().expect("address").to_string();
+            drop(listener);
+
+            let server = crate::replay::ReplayServer::from_jsonl(recording).expect("recording");
+            let late = address.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                server.serve(&late).await
+            });
+
+            let mut server_stream = wait_for_target(&address, Duration::from_secs(5))
+                .await
+                .expect("restarted target");
+            server_stream.write_all(head).await.expect("handshake");
+            server_stream.write_all(&rest).await.expect("command");
+
+            let mut response = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !response.ends_with(b"}}") {
+                let size = server_stream.read(&mut buf).await.expect("read");
+                assert!(size > 0, "closed before the reply");
+                response.extend_from_slice(&buf[..size]);
+            }
+            assert!(response.starts_with(b"HTTP/1.1 101"));
+            assert!(String::from_utf8_lossy(&response).contains("HeadlessChrome/131.0.6778.139"));
+
+            // a target that never comes back gets the 503.
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+                .await
+                .expect("bind");
+            let closed = listener.local_addr().expect("address").to_string();
+            drop(listener);
+
+            assert!(wait_for_target(&closed, Duration::from_millis(300))
+                .await
+                .is_none());
+            assert!(restarting_response().starts_with(b"HTTP/1.1 503 Service Unavailable"));
+        }
+
+        #[tokio::test]
+        async fn test_proxy_base_path() {
+            let recording = r#"{"timestamp":1,"direction":"send","session":"1","id":1,"method":"Browser.getVersion"}
+{"timestamp":2,"direction":"receive","session":"1","id":1,"method":"Browser.getVersion","result":{"product":"HeadlessChrome/131.0.6778.139"}}"#;
+            let target = crate::replay::serve_recording(recording).await;
+
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+                .await
+                .expect("bind");
+            let mut client_stream = TcpStream::connect(listener.local_addr().expect("address"))
+                .await
+                .expect("connect");
+            let (accepted, _) = listener.accept().await.expect("accept");
+
+            // the replay server only upgrades the devtools paths.
+            let head = strip_base_path(
+                b"GET /browsers/node-3/devtools/browser/replay HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n".to_vec(),
+                "/browsers/node-3",
+            );
+            let client = ClientInfo {
+                addr: None,
+                identity: None,
+            };
+
+            tokio::spawn(async move {
+                let mut accepted = Stream::Tcp(accepted);
+                proxy_connection(
+                    &mut accepted,
+                    &client,
+                    head,
+                    Vec::new(),
+                    Instant::now(),
+                    &target,
+                )
+                .await
+            });
+
+            client_stream
+                .write_all(&crate::ws::encode_frame(
+                    crate::ws::OPCODE_TEXT,
+                    br#"{"id":1,"method":"Browser.getVersion"}"#,
+                    Some([1, 2, 3, 4]),
+                ))
+                .await
+                .expect("command");
+
+            let mut response = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !response.ends_with(b"}}") {
+                let size = client_stream.read(&mut buf).await.expect("read");
+                assert!(size > 0, "closed before the reply");
+                response.extend_from_slice(&buf[..size]);
+            }
+            assert!(response.starts_with(b"HTTP/1.1 101"));
+            assert!(String::from_utf8_lossy(&response).contains("HeadlessChrome/131.0.6778.139"));
+        }
+
+        #[test]
+        fn test_single_use_session_url() {
+            let key = "test-signing-key";
+            let (token, _) = signing::sign(
+                key.as_bytes(),
+                &signing::SessionOptions {
+                    single_use: true,
+                    ..Default::default()
+                },
+            );
+            let head = format!(
+                "GET /devtools/browser/b1?{}={} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n",
+                signing::TOKEN_PARAM, token
+            )
+            .into_bytes();
+
+            let (forwarded, _) = verify_session(key, head.clone()).expect("first use");
+            assert!(RequestHead::parse(&forwarded)
+                .expect("head")
+                .query_param(signing::TOKEN_PARAM)
+                .is_none());
+
+            // the replay is rejected before the connection to chrome is opened.
+            let reason = verify_session(key, head).expect_err("replayed url");
+            assert!(session_rejection(reason).starts_with(b"HTTP/1.1 403 Forbidden"));
+            assert!(session_rejection("expired token").starts_with(b"HTTP/1.1 401 Unauthorized"));
+        }
+    }
 }
@@ -0,0 +1,200 @@
+use crate::conf::PROXY_PROTOCOL;
+use crate::ClientInfo;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The PROXY protocol v1 prefix.
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// The PROXY protocol v2 signature.
+const V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+/// The max length of a v1 header including the CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+/// An invalid PROXY protocol header.
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// The client of an accepted connection. Reads the PROXY protocol header within the
+/// `HANDSHAKE_TIMEOUT` when `PROXY_PROTOCOL` is enabled, falling back to the peer address.
+pub(crate) async fn client_info<S>(
+    stream: &mut S,
+    peer: Option<SocketAddr>,
+) -> std::io::Result<ClientInfo>
+where
+    S: AsyncRead + Unpin,
+{
+    resolve_client(stream, peer, *PROXY_PROTOCOL).await
+}
+
+/// The client of an accepted connection. Only tcp connections carry the PROXY protocol header,
+/// local sidecars on unix sockets connect without one.
+async fn resolve_client<S>(
+    stream: &mut S,
+    peer: Option<SocketAddr>,
+    proxy_protocol: bool,
+) -> std::io::Result<ClientInfo>
+where
+    S: AsyncRead + Unpin,
+{
+    let addr = if proxy_protocol && peer.is_some() {
+        tokio::time::timeout(crate::ws::HANDSHAKE_TIMEOUT, read_proxy_header(stream))
+            .await
+            .map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "timed out reading the PROXY protocol header",
+                )
+            })??
+            .or(peer)
+    } else {
+        peer
+    };
+
+    Ok(ClientInfo {
+        addr,
+        identity: None,
+    })
+}
+
+/// Read the HAProxy PROXY protocol v1 or v2 header from the start of the stream. Returns the
+/// real client address, or None for LOCAL and UNKNOWN connections such as load balancer health
+/// checks. Only the header bytes are consumed.
+pub(crate) async fn read_proxy_header<S>(stream: &mut S) -> std::io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = [0u8; 16];
+
+    stream.read_exact(&mut header[..8]).await?;
+
+    if header[..8] == V2_SIGNATURE[..8] {
+        stream.read_exact(&mut header[8..]).await?;
+
+        if header[..12] != V2_SIGNATURE {
+            return Err(invalid("invalid PROXY v2 signature"));
+        }
+
+        let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+        let mut body = vec![0u8; length];
+
+        stream.read_exact(&mut body).await?;
+
+        parse_v2(&header, &body)
+    } else if header.starts_with(V1_PREFIX) {
+        let mut line = header[..8].to_vec();
+
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LENGTH {
+                return Err(invalid("PROXY v1 header too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+
+        let line = std::str::from_utf8(&line).map_err(|_| invalid("invalid PROXY v1 header"))?;
+
+        parse_v1(line.trim_end())
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+/// Parse a v1 header line without the CRLF, ex: `PROXY TCP4 192.168.0.1 10.0.0.1 56324 9222`.
+fn parse_v1(line: &str) -> std::io::Result<Option<SocketAddr>> {
+    let mut parts = line.split(' ');
+
+    if parts.next() != Some("PROXY") {
+        return Err(invalid("invalid PROXY v1 header"));
+    }
+
+    match parts.next() {
+        Some("TCP4") | Some("TCP6") => {
+            let source = parts.next().and_then(|ip| ip.parse::<IpAddr>().ok());
+            let _destination = parts.next();
+            let port = parts.next().and_then(|port| port.parse::<u16>().ok());
+
+            match (source, port) {
+                (Some(ip), Some(port)) => Ok(Some(SocketAddr::new(ip, port))),
+                _ => Err(invalid("invalid PROXY v1 address")),
+            }
+        }
+        Some("UNKNOWN") => Ok(None),
+        _ => Err(invalid("invalid PROXY v1 protocol")),
+    }
+}
+
+/// Parse a v2 header and the address block that follows it.
+fn parse_v2(header: &[u8; 16], body: &[u8]) -> std::io::Result<Option<SocketAddr>> {
+    if header[12] >> 4 != 2 {
+        return Err(invalid("invalid PROXY v2 version"));
+    }
+
+    // LOCAL connections are sent by the proxy itself.
+    if header[12] & 0x0F == 0 {
+        return Ok(None);
+    }
+
+    match header[13] >> 4 {
+        // AF_INET
+        1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // AF_INET6
+        2 if body.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(octets)),
+                port,
+            )))
+        }
+        // AF_UNSPEC and AF_UNIX carry no usable client address.
+        0 | 3 => Ok(None),
+        _ => Err(invalid("invalid PROXY v2 address")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_proxy_v1() {
+        let mut stream: &[u8] = b"PROXY TCP4 192.168.0.1 10.0.0.1 56324 9222\r\nGET /";
+        let addr = read_proxy_header(&mut stream).await.expect("valid header");
+
+        assert_eq!(addr, Some("192.168.0.1:56324".parse().unwrap()));
+        assert_eq!(stream, b"GET /");
+    }
+
+    #[tokio::test]
+    async fn test_read_proxy_v2() {
+        let mut data = V2_SIGNATURE.to_vec();
+        // version 2 PROXY, TCP over IPv4, 12 byte address block.
+        data.extend_from_slice(&[0x21, 0x11, 0x00, 0x0C]);
+        data.extend_from_slice(&[10, 1, 2, 3, 10, 0, 0, 1]);
+        data.extend_from_slice(&443u16.to_be_bytes());
+        data.extend_from_slice(&9222u16.to_be_bytes());
+        data.extend_from_slice(b"GET /");
+
+        let mut stream: &[u8] = &data;
+        let addr = read_proxy_header(&mut stream).await.expect("valid header");
+
+        assert_eq!(addr, Some("10.1.2.3:443".parse().unwrap()));
+        assert_eq!(stream, b"GET /");
+
+        // unix socket connections have no peer and skip the header.
+        let mut stream: &[u8] = b"GET /";
+        let client = resolve_client(&mut stream, None, true)
+            .await
+            .expect("unix client");
+
+        assert_eq!(client.addr, None);
+        assert_eq!(stream, b"GET /");
+    }
+}
@@ -0,0 +1,304 @@
+use crate::conf::{
+    CDP_RECORD_DIR, CDP_RECORD_MAX_BYTES, CDP_RECORD_MAX_LENGTH, CDP_RECORD_MAX_SESSIONS,
+};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Keys that hold base64 binary payloads like screenshots, pdfs, and response bodies.
+const BINARY_KEYS: [&str; 3] = ["data", "body", "screenshot"];
+/// Binary values below this size are kept as is.
+const BINARY_MIN_LENGTH: usize = 256;
+/// The max lines queued for the writer thread before new lines are dropped.
+const PENDING_LINES: usize = 4096;
+
+/// The writer threads of the sessions being recorded.
+static RECORDERS: AtomicUsize = AtomicUsize::new(0);
+
+/// The direction of a recorded CDP message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Client to browser.
+    Send,
+    /// Browser to client.
+    Receive,
+}
+
+impl Direction {
+    /// The direction name written to the recording.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Send => "send",
+            Direction::Receive => "receive",
+        }
+    }
+}
+
+/// Records the CDP messages of a proxied session to a JSONL file. Lines are written on a
+/// dedicated thread so file I/O never blocks the proxy pump.
+pub struct Recorder {
+    /// The lines queued for the writer thread.
+    lines: SyncSender<Vec<u8>>,
+    /// The lines dropped while the writer thread was behind.
+    dropped: u64,
+    /// The proxied session id.
+    session: String,
+    /// Command methods by id used to label responses.
+    methods: HashMap<u64, String>,
+    /// The recording file path.
+    path: PathBuf,
+}
+
+impl Recorder {
+    /// Start the writer thread recording the session to a file in the `CDP_RECORD_DIR`. Errors
+    /// when `CDP_RECORD_MAX_SESSIONS` sessions are already recorded.
+    pub fn create(session: &str) -> std::io::Result<Self> {
+        RECORDERS
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |recorders| {
+                (recorders < *CDP_RECORD_MAX_SESSIONS).then_some(recorders + 1)
+            })
+            .map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::ResourceBusy,
+                    "too many recorded sessions",
+                )
+            })?;
+
+        let path = PathBuf::from(&*CDP_RECORD_DIR).join(format!(
+            "cdp-{}-{}.jsonl",
+            unix_millis(),
+            session
+        ));
+        let (lines, pending) = sync_channel(PENDING_LINES);
+        let file = path.clone();
+
+        let spawned = std::thread::Builder::new()
+            .name("cdp-recorder".into())
+            .spawn(move || {
+                if let Err(e) = write_lines(&file, pending, *CDP_RECORD_MAX_BYTES) {
+                    tracing::error!(
+                        "Failed to record CDP session to {}: {:?}",
+                        file.display(),
+                        e
+                    );
+                }
+                RECORDERS.fetch_sub(1, Ordering::AcqRel);
+            });
+
+        if let Err(e) = spawned {
+            RECORDERS.fetch_sub(1, Ordering::AcqRel);
+            return Err(e);
+        }
+
+        tracing::info!("Recording CDP session {} to {}", session, path.display());
+
+        Ok(Self {
+            lines,
+            dropped: 0,
+            session: session.to_string(),
+            methods: HashMap::new(),
+            path,
+        })
+    }
+
+    /// The recording file path.
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// Record a raw websocket message.
+    pub fn record(&mut self, direction: Direction, message: &[u8]) {
+        let line = match serde_json::from_slice::<Value>(message) {
+            Ok(Value::Object(message)) => self.entry(direction, message),
+            _ => {
+                let mut entry = self.base_entry(direction);
+                entry.insert(
+                    "raw".into(),
+                    Value::String(elide(&String::from_utf8_lossy(message))),
+                );
+                entry
+            }
+        };
+
+        if let Ok(mut line) = serde_json::to_vec(&line) {
+            line.push(b'\n');
+
+            match self.lines.try_send(line) {
+                Ok(_) | Err(TrySendError::Disconnected(_)) => (),
+                Err(TrySendError::Full(_)) => self.dropped += 1,
+            }
+        }
+    }
+
+    /// The fields shared by every line.
+    fn base_entry(&self, direction: Direction) -> Map<String, Value> {
+        let mut entry = Map::new();
+        entry.insert("timestamp".into(), unix_millis().into());
+        entry.insert("direction".into(), direction.as_str().into());
+        entry.insert("session".into(), self.session.clone().into());
+        entry
+    }
+
+    /// Build the line for a CDP message.
+    fn entry(
+        &mut self,
+        direction: Direction,
+        mut message: Map<String, Value>,
+    ) -> Map<String, Value> {
+        let mut entry = self.base_entry(direction);
+        let id = message.get("id").and_then(Value::as_u64);
+        let method = message
+            .get("method")
+            .and_then(Value::as_str)
+            .map(String::from);
+
+        match (direction, id, method) {
+            (Direction::Send, Some(id), Some(method)) => {
+                self.methods.insert(id, method.clone());
+                entry.insert("method".into(), method.into());
+            }
+            (Direction::Receive, Some(id), None) => {
+                if let Some(method) = self.methods.remove(&id) {
+                    entry.insert("method".into(), method.into());
+                }
+            }
+            (_, _, Some(method)) => {
+                entry.insert("method".into(), method.into());
+            }
+            _ => (),
+        }
+
+        if let Some(id) = id {
+            entry.insert("id".into(), id.into());
+        }
+
+        for key in ["sessionId", "params", "result", "error"] {
+            if let Some(mut value) = message.remove(key) {
+                redact(&mut value, None);
+                entry.insert(key.into(), value);
+            }
+        }
+
+        entry
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if self.dropped > 0 {
+            tracing::warn!(
+                "Dropped {} CDP messages recording {}",
+                self.dropped,
+                self.path.display()
+            );
+        }
+    }
+}
+
+/// Write the queued lines to the recording file until the recorder is dropped or the file
+/// reaches the max bytes. No limit when 0.
+fn write_lines(path: &Path, lines: Receiver<Vec<u8>>, max_bytes: u64) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    let mut written = 0u64;
+
+    for line in lines {
+        written += line.len() as u64;
+
+        if max_bytes > 0 && written > max_bytes {
+            tracing::warn!(
+                "Stopped recording {} at {} bytes",
+                path.display(),
+                max_bytes
+            );
+            break;
+        }
+
+        writer.write_all(&line)?;
+    }
+
+    writer.flush()
+}
+
+/// Milliseconds since the unix epoch.
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Elide a binary value keeping the size for debugging.
+fn elide(value: &str) -> String {
+    if value.len() > BINARY_MIN_LENGTH {
+        format!("<elided {} bytes>", value.len())
+    } else {
+        value.to_string()
+    }
+}
+
+/// Redact binary payloads and truncate long strings in place.
+fn redact(value: &mut Value, key: Option<&str>) {
+    match value {
+        Value::String(s) => {
+            if key.is_some_and(|key| BINARY_KEYS.contains(&key)) && s.len() > BINARY_MIN_LENGTH {
+                *s = elide(s);
+            } else if *CDP_RECORD_MAX_LENGTH > 0 && s.len() > *CDP_RECORD_MAX_LENGTH {
+                let mut end = *CDP_RECORD_MAX_LENGTH;
+                while !s.is_char_boundary(end) {
+                    end -= 1;
+                }
+                s.truncate(end);
+                s.push_str("...");
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                redact(value, key);
+            }
+        }
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                redact(value, Some(key));
+            }
+        }
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_screenshot_data() {
+        let mut value = serde_json::json!({
+            "data": "a".repeat(1024),
+            "frameId": "F1",
+        });
+
+        redact(&mut value, None);
+
+        assert_eq!(value["data"], "<elided 1024 bytes>");
+        assert_eq!(value["frameId"], "F1");
+
+        let path = std::env::temp_dir().join(format!("hb-record-{}.jsonl", std::process::id()));
+        let (sender, lines) = sync_channel(4);
+        for _ in 0..3 {
+            sender.send(b"{\"id\":1}\n".to_vec()).unwrap();
+        }
+        drop(sender);
+
+        write_lines(&path, lines, 20).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"{\"id\":1}\n{\"id\":1}\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
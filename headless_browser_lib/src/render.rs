@@ -0,0 +1,1260 @@
+use crate::auth::{Claims, TenantSession};
+use crate::body::ChannelBody;
+use crate::cdp::CdpClient;
+use crate::conf::{DRAINING, MAX_RENDERS, TARGET};
+use crate::instances::{self, InstanceKind};
+use hyper::body::Bytes;
+use serde_json::{Map, Value};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// The default max time to load, wait for, and capture a page.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The max time a request can ask for.
+const MAX_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The max viewport width or height.
+const MAX_VIEWPORT: u64 = 16384;
+
+/// The max bytes of a PDF stream chunk read from chrome.
+const PDF_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// The max time to read a PDF stream chunk once chrome started printing.
+const PDF_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The paper sizes by name in inches.
+const PAPER_SIZES: [(&str, f64, f64); 7] = [
+    ("letter", 8.5, 11.0),
+    ("legal", 8.5, 14.0),
+    ("tabloid", 11.0, 17.0),
+    ("ledger", 17.0, 11.0),
+    ("a3", 297.0 / 25.4, 420.0 / 25.4),
+    ("a4", 210.0 / 25.4, 297.0 / 25.4),
+    ("a5", 148.0 / 25.4, 210.0 / 25.4),
+];
+
+/// The next instance of the round robin pool.
+static NEXT_INSTANCE: AtomicUsize = AtomicUsize::new(0);
+
+/// The CDP domains of the screenshot jobs.
+pub(crate) const SCREENSHOT_DOMAINS: [&str; 4] = ["Emulation", "Page", "Runtime", "Target"];
+
+/// The CDP domains of the PDF jobs.
+pub(crate) const PDF_DOMAINS: [&str; 5] = ["Emulation", "IO", "Page", "Runtime", "Target"];
+
+lazy_static::lazy_static! {
+    /// The slots of the concurrent render jobs.
+    static ref RENDER_SLOTS: std::sync::Arc<tokio::sync::Semaphore> =
+        std::sync::Arc::new(tokio::sync::Semaphore::new(*MAX_RENDERS));
+}
+
+/// A render job counted against the `MAX_RENDERS` and the tenant sessions until dropped.
+pub(crate) struct RenderSlot {
+    /// The render slot.
+    _permit: tokio::sync::OwnedSemaphorePermit,
+    /// The session of the tenant.
+    _tenant_session: Option<TenantSession>,
+}
+
+impl RenderSlot {
+    /// Acquire a slot for a job calling the CDP domains. Errors while the node is draining, when
+    /// the tenant cannot call the domains or is at the max sessions, and when the slots are taken.
+    pub fn acquire(claims: Option<&Claims>, domains: &[&str]) -> std::io::Result<Self> {
+        use std::io::{Error, ErrorKind};
+
+        if DRAINING.load(Ordering::Relaxed) {
+            return Err(Error::new(ErrorKind::ResourceBusy, "the node is draining"));
+        }
+
+        if let Some(allowed) = claims.and_then(|claims| claims.allowed_domains.as_ref()) {
+            if let Some(domain) = domains.iter().find(|d| !allowed.iter().any(|a| a == *d)) {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("the {} domain is not allowed", domain),
+                ));
+            }
+        }
+
+        let tenant_session = claims
+            .map(TenantSession::acquire)
+            .transpose()
+            .map_err(|reason| Error::new(ErrorKind::QuotaExceeded, reason))?;
+
+        let permit = RENDER_SLOTS
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| Error::new(ErrorKind::ResourceBusy, "render capacity exhausted"))?;
+
+        Ok(Self {
+            _permit: permit,
+            _tenant_session: tenant_session,
+        })
+    }
+}
+
+/// The page to render.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Source {
+    /// Navigate to the http or https url.
+    Url(String),
+    /// Load the html document.
+    Html(String),
+}
+
+/// The page state to wait for before capturing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum WaitUntil {
+    /// The `load` event fired.
+    #[default]
+    Load,
+    /// The `DOMContentLoaded` event fired.
+    DomContentLoaded,
+    /// The page loaded and no resources were fetched for 500ms.
+    NetworkIdle,
+}
+
+impl WaitUntil {
+    /// The name of the wait condition.
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Load => "load",
+            Self::DomContentLoaded => "domcontentloaded",
+            Self::NetworkIdle => "networkidle",
+        }
+    }
+}
+
+/// When the page is ready to capture.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct WaitOptions {
+    /// The page state to wait for.
+    pub until: WaitUntil,
+    /// A css selector that must match an element.
+    pub selector: Option<String>,
+    /// A delay after the page is ready, ex: for animations.
+    pub delay: Duration,
+    /// The max time to load, wait for, and capture the page.
+    pub timeout: Duration,
+}
+
+impl Default for WaitOptions {
+    fn default() -> Self {
+        Self {
+            until: WaitUntil::default(),
+            selector: None,
+            delay: Duration::ZERO,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+/// The emulated viewport.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Viewport {
+    /// The width in css pixels.
+    pub width: u64,
+    /// The height in css pixels.
+    pub height: u64,
+    /// The device pixel ratio.
+    pub device_scale_factor: f64,
+    /// Emulate a mobile device.
+    pub mobile: bool,
+}
+
+/// A region of the page in css pixels.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Clip {
+    /// The left offset.
+    pub x: f64,
+    /// The top offset.
+    pub y: f64,
+    /// The width.
+    pub width: f64,
+    /// The height.
+    pub height: f64,
+    /// The scale of the captured image.
+    pub scale: f64,
+}
+
+/// The image format of a screenshot.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum ImageFormat {
+    /// Lossless png.
+    #[default]
+    Png,
+    /// Lossy jpeg.
+    Jpeg,
+    /// Lossy webp.
+    Webp,
+}
+
+impl ImageFormat {
+    /// The CDP name of the format.
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Jpeg => "jpeg",
+            Self::Webp => "webp",
+        }
+    }
+
+    /// The content type of the image.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::Jpeg => "image/jpeg",
+            Self::Webp => "image/webp",
+        }
+    }
+}
+
+/// The page to load and how to wait for it.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PageOptions {
+    /// The page to render.
+    pub source: Source,
+    /// The emulated viewport. The instance default when None.
+    pub viewport: Option<Viewport>,
+    /// When the page is ready.
+    pub wait: WaitOptions,
+}
+
+/// The options of a `POST /v1/screenshot` request.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ScreenshotOptions {
+    /// The page to load.
+    pub page: PageOptions,
+    /// Capture the full scrollable page instead of the viewport.
+    pub full_page: bool,
+    /// Capture a region of the page.
+    pub clip: Option<Clip>,
+    /// The image format.
+    pub format: ImageFormat,
+    /// The jpeg or webp quality from 0 to 100.
+    pub quality: Option<u64>,
+}
+
+/// The page margins in inches.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Margin {
+    /// The top margin.
+    pub top: f64,
+    /// The right margin.
+    pub right: f64,
+    /// The bottom margin.
+    pub bottom: f64,
+    /// The left margin.
+    pub left: f64,
+}
+
+impl Default for Margin {
+    /// The chrome default of 1cm.
+    fn default() -> Self {
+        let margin = 10.0 / 25.4;
+
+        Self {
+            top: margin,
+            right: margin,
+            bottom: margin,
+            left: margin,
+        }
+    }
+}
+
+/// The options of a `POST /v1/pdf` request.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PdfOptions {
+    /// The page to load.
+    pub page: PageOptions,
+    /// The paper width and height in inches.
+    pub paper: (f64, f64),
+    /// Print in landscape orientation.
+    pub landscape: bool,
+    /// The page margins.
+    pub margin: Margin,
+    /// The html template of the page header.
+    pub header_template: Option<String>,
+    /// The html template of the page footer.
+    pub footer_template: Option<String>,
+    /// Print the background graphics.
+    pub print_background: bool,
+    /// The pages to print, ex: `1-5, 8`. All the pages when None.
+    pub page_ranges: Option<String>,
+    /// Generate a tagged (accessible) PDF.
+    pub tagged: bool,
+}
+
+/// Read a json object of a request body.
+fn json_object(body: &[u8]) -> Result<Map<String, Value>, String> {
+    match serde_json::from_slice::<Value>(body).map_err(|e| e.to_string())? {
+        Value::Object(json) => Ok(json),
+        _ => Err("the body must be a json object".into()),
+    }
+}
+
+/// A positive number of the json object.
+fn number(json: &Map<String, Value>, object: &str, key: &str) -> Result<Option<f64>, String> {
+    match json.get(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => value
+            .as_f64()
+            .filter(|number| number.is_finite() && *number >= 0.0)
+            .map(Some)
+            .ok_or_else(|| format!("{}.{} must be a positive number", object, key)),
+    }
+}
+
+/// A duration in milliseconds up to the `MAX_TIMEOUT`.
+fn millis(key: &str, value: &Value) -> Result<Duration, String> {
+    value
+        .as_u64()
+        .map(Duration::from_millis)
+        .filter(|duration| *duration <= MAX_TIMEOUT)
+        .ok_or_else(|| {
+            format!(
+                "{} must be milliseconds up to {}",
+                key,
+                MAX_TIMEOUT.as_millis()
+            )
+        })
+}
+
+/// A length in inches. Numbers are inches and strings can have a `in`, `cm`, `mm`, or `px` unit.
+fn length(key: &str, value: &Value) -> Result<f64, String> {
+    let inches = match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(length) => {
+            let length = length.trim();
+            let (number, unit) = length.split_at(
+                length
+                    .find(|c: char| c.is_ascii_alphabetic())
+                    .unwrap_or(length.len()),
+            );
+
+            number
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .and_then(|number| match unit {
+                    "" | "in" => Some(number),
+                    "cm" => Some(number / 2.54),
+                    "mm" => Some(number / 25.4),
+                    "px" => Some(number / 96.0),
+                    _ => None,
+                })
+        }
+        _ => None,
+    };
+
+    inches
+        .filter(|inches| inches.is_finite() && *inches >= 0.0)
+        .ok_or_else(|| {
+            format!(
+                "{} must be a positive number of inches or a length in in, cm, mm, or px",
+                key
+            )
+        })
+}
+
+impl PageOptions {
+    /// Parse the page option of the key. Returns false for keys of other options.
+    fn parse(
+        source: &mut Option<Source>,
+        viewport: &mut Option<Viewport>,
+        wait: &mut WaitOptions,
+        key: &str,
+        value: &Value,
+    ) -> Result<bool, String> {
+        match (key, value) {
+            ("url" | "html", _) if source.is_some() => {
+                return Err("only one of url or html can be set".into())
+            }
+            ("url", Value::String(url)) => {
+                match url.parse::<hyper::Uri>() {
+                    Ok(uri) if matches!(uri.scheme_str(), Some("http" | "https")) => (),
+                    _ => return Err("url must be an http or https url".into()),
+                }
+                *source = Some(Source::Url(url.clone()));
+            }
+            ("html", Value::String(html)) => *source = Some(Source::Html(html.clone())),
+            ("url" | "html", _) => return Err(format!("{} must be a string", key)),
+            ("viewport", Value::Object(json)) => {
+                for key in json.keys() {
+                    if !matches!(
+                        key.as_str(),
+                        "width" | "height" | "device_scale_factor" | "mobile"
+                    ) {
+                        return Err(format!("unknown option viewport.{}", key));
+                    }
+                }
+
+                let size = |key: &str| match json.get(key).and_then(Value::as_u64) {
+                    Some(size) if (1..=MAX_VIEWPORT).contains(&size) => Ok(size),
+                    _ => Err(format!(
+                        "viewport.{} must be between 1 and {}",
+                        key, MAX_VIEWPORT
+                    )),
+                };
+
+                let device_scale_factor =
+                    number(json, "viewport", "device_scale_factor")?.unwrap_or(1.0);
+
+                if device_scale_factor <= 0.0 || device_scale_factor > 4.0 {
+                    return Err("viewport.device_scale_factor must be between 0 and 4".into());
+                }
+
+                *viewport = Some(Viewport {
+                    width: size("width")?,
+                    height: size("height")?,
+                    device_scale_factor,
+                    mobile: match json.get("mobile") {
+                        None | Some(Value::Null) => false,
+                        Some(Value::Bool(mobile)) => *mobile,
+                        _ => return Err("viewport.mobile must be a boolean".into()),
+                    },
+                });
+            }
+            ("viewport", _) => return Err("viewport must be an object".into()),
+            ("wait", Value::Object(json)) => {
+                for (key, value) in json.iter() {
+                    match (key.as_str(), value) {
+                        (_, Value::Null) => (),
+                        ("until", Value::String(until)) => {
+                            wait.until =
+                                match until.as_str() {
+                                    "load" => WaitUntil::Load,
+                                    "domcontentloaded" => WaitUntil::DomContentLoaded,
+                                    "networkidle" => WaitUntil::NetworkIdle,
+                                    _ => return Err(
+                                        "wait.until must be load, domcontentloaded, or networkidle"
+                                            .into(),
+                                    ),
+                                }
+                        }
+                        ("selector", Value::String(selector)) if !selector.is_empty() => {
+                            wait.selector = Some(selector.clone())
+                        }
+                        ("delay", value) => wait.delay = millis("wait.delay", value)?,
+                        ("timeout", value) => match millis("wait.timeout", value)? {
+                            timeout if !timeout.is_zero() => wait.timeout = timeout,
+                            _ => return Err("wait.timeout must be positive".into()),
+                        },
+                        ("until" | "selector", _) => {
+                            return Err(format!("wait.{} must be a string", key))
+                        }
+                        _ => return Err(format!("unknown option wait.{}", key)),
+                    }
+                }
+
+                if wait.delay >= wait.timeout {
+                    return Err("wait.delay must be less than wait.timeout".into());
+                }
+            }
+            ("wait", _) => return Err("wait must be an object".into()),
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+}
+
+impl ScreenshotOptions {
+    /// Parse the json body of a screenshot request. Unknown options are rejected.
+    pub fn from_json(body: &[u8]) -> Result<Self, String> {
+        let json = json_object(body)?;
+
+        let mut source = None;
+        let mut viewport = None;
+        let mut wait = WaitOptions::default();
+        let mut full_page = false;
+        let mut clip = None;
+        let mut format = ImageFormat::default();
+        let mut quality = None;
+
+        for (key, value) in json.iter() {
+            if value.is_null()
+                || PageOptions::parse(&mut source, &mut viewport, &mut wait, key, value)?
+            {
+                continue;
+            }
+
+            match (key.as_str(), value) {
+                ("full_page", Value::Bool(value)) => full_page = *value,
+                ("full_page", _) => return Err("full_page must be a boolean".into()),
+                ("clip", Value::Object(json)) => {
+                    for key in json.keys() {
+                        if !matches!(key.as_str(), "x" | "y" | "width" | "height" | "scale") {
+                            return Err(format!("unknown option clip.{}", key));
+                        }
+                    }
+
+                    let size = |key: &str| -> Result<f64, String> {
+                        number(json, "clip", key)?
+                            .filter(|size| *size > 0.0)
+                            .ok_or_else(|| format!("clip.{} must be a positive number", key))
+                    };
+
+                    clip = Some(Clip {
+                        x: number(json, "clip", "x")?.unwrap_or_default(),
+                        y: number(json, "clip", "y")?.unwrap_or_default(),
+                        width: size("width")?,
+                        height: size("height")?,
+                        scale: match number(json, "clip", "scale")? {
+                            Some(scale) if scale <= 0.0 || scale > 4.0 => {
+                                return Err("clip.scale must be between 0 and 4".into())
+                            }
+                            scale => scale.unwrap_or(1.0),
+                        },
+                    });
+                }
+                ("clip", _) => return Err("clip must be an object".into()),
+                ("format", Value::String(value)) => {
+                    format = match value.as_str() {
+                        "png" => ImageFormat::Png,
+                        "jpeg" | "jpg" => ImageFormat::Jpeg,
+                        "webp" => ImageFormat::Webp,
+                        _ => return Err("format must be png, jpeg, or webp".into()),
+                    }
+                }
+                ("format", _) => return Err("format must be a string".into()),
+                ("quality", value) => match value.as_u64() {
+                    Some(value) if value <= 100 => quality = Some(value),
+                    _ => return Err("quality must be between 0 and 100".into()),
+                },
+                _ => return Err(format!("unknown option {}", key)),
+            }
+        }
+
+        if full_page && clip.is_some() {
+            return Err("only one of full_page or clip can be set".into());
+        }
+
+        if quality.is_some() && format == ImageFormat::Png {
+            return Err("quality is only supported for jpeg and webp".into());
+        }
+
+        Ok(Self {
+            page: PageOptions {
+                source: source.ok_or("url or html is required")?,
+                viewport,
+                wait,
+            },
+            full_page,
+            clip,
+            format,
+            quality,
+        })
+    }
+}
+
+impl PdfOptions {
+    /// Parse the json body of a PDF request. Unknown options are rejected.
+    pub fn from_json(body: &[u8]) -> Result<Self, String> {
+        let json = json_object(body)?;
+
+        let mut source = None;
+        let mut viewport = None;
+        let mut wait = WaitOptions::default();
+        let mut paper = (PAPER_SIZES[0].1, PAPER_SIZES[0].2);
+        let mut landscape = false;
+        let mut margin = Margin::default();
+        let mut header_template = None;
+        let mut footer_template = None;
+        let mut print_background = false;
+        let mut page_ranges = None;
+        let mut tagged = true;
+
+        for (key, value) in json.iter() {
+            if value.is_null()
+                || PageOptions::parse(&mut source, &mut viewport, &mut wait, key, value)?
+            {
+                continue;
+            }
+
+            match (key.as_str(), value) {
+                ("paper", Value::String(name)) => paper = PAPER_SIZES
+                    .iter()
+                    .find(|(size, _, _)| size.eq_ignore_ascii_case(name))
+                    .map(|(_, width, height)| (*width, *height))
+                    .ok_or(
+                        "paper must be letter, legal, tabloid, ledger, a3, a4, a5, or an object",
+                    )?,
+                ("paper", Value::Object(json)) => {
+                    for key in json.keys() {
+                        if !matches!(key.as_str(), "width" | "height") {
+                            return Err(format!("unknown option paper.{}", key));
+                        }
+                    }
+
+                    let size = |key: &str| {
+                        let name = format!("paper.{}", key);
+
+                        match json.get(key) {
+                            Some(value) => match length(&name, value)? {
+                                size if size > 0.0 => Ok(size),
+                                _ => Err(format!("{} must be positive", name)),
+                            },
+                            None => Err(format!("{} is required", name)),
+                        }
+                    };
+
+                    paper = (size("width")?, size("height")?);
+                }
+                ("paper", _) => return Err("paper must be a string or an object".into()),
+                ("landscape", Value::Bool(value)) => landscape = *value,
+                ("margin", Value::Object(json)) => {
+                    for (key, value) in json.iter() {
+                        let side = match key.as_str() {
+                            "top" => &mut margin.top,
+                            "right" => &mut margin.right,
+                            "bottom" => &mut margin.bottom,
+                            "left" => &mut margin.left,
+                            _ => return Err(format!("unknown option margin.{}", key)),
+                        };
+
+                        *side = length(&format!("margin.{}", key), value)?;
+                    }
+                }
+                ("margin", value) => {
+                    let all = length("margin", value)?;
+
+                    margin = Margin {
+                        top: all,
+                        right: all,
+                        bottom: all,
+                        left: all,
+                    };
+                }
+                ("header_template", Value::String(template)) => {
+                    header_template = Some(template.clone())
+                }
+                ("footer_template", Value::String(template)) => {
+                    footer_template = Some(template.clone())
+                }
+                ("print_background", Value::Bool(value)) => print_background = *value,
+                ("tagged", Value::Bool(value)) => tagged = *value,
+                ("landscape" | "print_background" | "tagged", _) => {
+                    return Err(format!("{} must be a boolean", key))
+                }
+                ("page_ranges", Value::String(ranges)) => {
+                    // open ended ranges, ex: `-3` or `5-`, are supported by chrome.
+                    let valid = ranges.split(',').all(|range| {
+                        let range = range.trim();
+
+                        range.contains(|c: char| c.is_ascii_digit())
+                            && range.splitn(2, '-').all(|page| {
+                                page.is_empty() || page.parse::<u32>().is_ok_and(|page| page > 0)
+                            })
+                    });
+
+                    if !valid {
+                        return Err("page_ranges must be pages or ranges, ex: 1-5, 8".into());
+                    }
+
+                    page_ranges = Some(ranges.clone());
+                }
+                ("header_template" | "footer_template" | "page_ranges", _) => {
+                    return Err(format!("{} must be a string", key))
+                }
+                _ => return Err(format!("unknown option {}", key)),
+            }
+        }
+
+        let (width, height) = if landscape { (paper.1, paper.0) } else { paper };
+
+        if margin.left + margin.right >= width || margin.top + margin.bottom >= height {
+            return Err("the margins must leave room on the paper".into());
+        }
+
+        Ok(Self {
+            page: PageOptions {
+                source: source.ok_or("url or html is required")?,
+                viewport,
+                wait,
+            },
+            paper,
+            landscape,
+            margin,
+            header_template,
+            footer_template,
+            print_background,
+            page_ranges,
+            tagged,
+        })
+    }
+
+    /// The params of `Page.printToPDF` streaming the document.
+    fn params(&self) -> Value {
+        let mut params = serde_json::json!({
+            "paperWidth": self.paper.0,
+            "paperHeight": self.paper.1,
+            "landscape": self.landscape,
+            "marginTop": self.margin.top,
+            "marginRight": self.margin.right,
+            "marginBottom": self.margin.bottom,
+            "marginLeft": self.margin.left,
+            "printBackground": self.print_background,
+            "generateTaggedPDF": self.tagged,
+            "transferMode": "ReturnAsStream",
+        });
+
+        if self.header_template.is_some() || self.footer_template.is_some() {
+            // chrome prints its default header or footer for the template not set.
+            params["displayHeaderFooter"] = true.into();
+            params["headerTemplate"] = self
+                .header_template
+                .as_deref()
+                .unwrap_or("<span></span>")
+                .into();
+            params["footerTemplate"] = self
+                .footer_template
+                .as_deref()
+                .unwrap_or("<span></span>")
+                .into();
+        }
+
+        if let Some(page_ranges) = self.page_ranges.as_deref() {
+            params["pageRanges"] = page_ranges.into();
+        }
+
+        params
+    }
+}
+
+/// The debugging address of the next healthy forked instance in round robin order. The proxy
+/// target when no instance was forked.
+pub(crate) fn pooled_address() -> String {
+    let pool = instances::ports()
+        .into_iter()
+        .filter(|(pid, _)| {
+            instances::info(*pid)
+                .is_some_and(|info| info.kind == InstanceKind::Fork && info.healthy)
+        })
+        .filter_map(|(_, port)| port)
+        .collect::<Vec<_>>();
+
+    if pool.is_empty() {
+        return TARGET.to_string();
+    }
+
+    let next = NEXT_INSTANCE.fetch_add(1, Ordering::Relaxed) % pool.len();
+
+    crate::probe::instance_address(pool[next])
+}
+
+/// The script resolving when the page reached the wait condition.
+fn wait_script(wait: &WaitOptions) -> String {
+    format!(
+        r#"(async () => {{
+  const until = {};
+  const selector = {};
+  const sleep = (ms) => new Promise((resolve) => setTimeout(resolve, ms));
+  const loaded = () => until === "domcontentloaded" ? document.readyState !== "loading" : document.readyState === "complete";
+  while (!loaded()) await sleep(50);
+  if (until === "networkidle") {{
+    let count = -1;
+    let idle = 0;
+    while (idle < 500) {{
+      const next = performance.getEntriesByType("resource").length;
+      idle = next === count ? idle + 100 : 0;
+      count = next;
+      await sleep(100);
+    }}
+  }}
+  if (selector) while (!document.querySelector(selector)) await sleep(100);
+  return true;
+}})()"#,
+        Value::from(wait.until.as_str()),
+        wait.selector.as_deref().map_or(Value::Null, Value::from),
+    )
+}
+
+/// A target in its own browser context driven over CDP.
+pub(crate) struct RenderPage {
+    /// The browser websocket.
+    client: CdpClient,
+    /// The browser context disposed on close.
+    context_id: String,
+    /// The page target.
+    target_id: String,
+    /// The flattened session of the target.
+    session_id: String,
+}
+
+/// A string field of a CDP result.
+fn field(result: &Value, key: &str, method: &str) -> std::io::Result<String> {
+    result
+        .get(key)
+        .and_then(Value::as_str)
+        .map(String::from)
+        .ok_or_else(|| std::io::Error::other(format!("{} did not return a {}", method, key)))
+}
+
+impl RenderPage {
+    /// Open a blank target in a new browser context of the chrome address.
+    pub async fn open(address: &str) -> std::io::Result<Self> {
+        let path = crate::launch::browser_path(address).await?;
+        let mut client = CdpClient::connect(address, &path).await?;
+
+        let context = client
+            .send(
+                "Target.createBrowserContext",
+                serde_json::json!({ "disposeOnDetach": true }),
+                None,
+            )
+            .await?;
+        let context_id = field(&context, "browserContextId", "Target.createBrowserContext")?;
+
+        let target = client
+            .send(
+                "Target.createTarget",
+                serde_json::json!({ "url": "about:blank", "browserContextId": context_id }),
+                None,
+            )
+            .await?;
+        let target_id = field(&target, "targetId", "Target.createTarget")?;
+
+        let attached = client
+            .send(
+                "Target.attachToTarget",
+                serde_json::json!({ "targetId": target_id, "flatten": true }),
+                None,
+            )
+            .await?;
+        let session_id = field(&attached, "sessionId", "Target.attachToTarget")?;
+
+        Ok(Self {
+            client,
+            context_id,
+            target_id,
+            session_id,
+        })
+    }
+
+    /// Send a command to the page.
+    pub async fn send(&mut self, method: &str, params: Value) -> std::io::Result<Value> {
+        self.client
+            .send(method, params, Some(&self.session_id))
+            .await
+    }
+
+    /// Emulate the viewport, load the source, and wait for the page to be ready.
+    pub async fn load(&mut self, options: &PageOptions) -> std::io::Result<()> {
+        if let Some(viewport) = options.viewport.as_ref() {
+            self.send(
+                "Emulation.setDeviceMetricsOverride",
+                serde_json::json!({
+                    "width": viewport.width,
+                    "height": viewport.height,
+                    "deviceScaleFactor": viewport.device_scale_factor,
+                    "mobile": viewport.mobile,
+                }),
+            )
+            .await?;
+        }
+
+        match &options.source {
+            Source::Url(url) => {
+                let navigated = self
+                    .send("Page.navigate", serde_json::json!({ "url": url }))
+                    .await?;
+
+                if let Some(error) = navigated.get("errorText").and_then(Value::as_str) {
+                    return Err(std::io::Error::other(format!(
+                        "navigation failed: {}",
+                        error
+                    )));
+                }
+            }
+            Source::Html(html) => {
+                let tree = self
+                    .send("Page.getFrameTree", serde_json::json!({}))
+                    .await?;
+                let frame_id = tree
+                    .pointer("/frameTree/frame/id")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string();
+
+                self.send(
+                    "Page.setDocumentContent",
+                    serde_json::json!({ "frameId": frame_id, "html": html }),
+                )
+                .await?;
+            }
+        }
+
+        let waited = self
+            .send(
+                "Runtime.evaluate",
+                serde_json::json!({
+                    "expression": wait_script(&options.wait),
+                    "awaitPromise": true,
+                    "returnByValue": true,
+                }),
+            )
+            .await?;
+
+        if let Some(exception) = waited.get("exceptionDetails") {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("wait failed: {}", exception),
+            ));
+        }
+
+        if !options.wait.delay.is_zero() {
+            tokio::time::sleep(options.wait.delay).await;
+        }
+
+        Ok(())
+    }
+
+    /// Close the target and dispose the browser context.
+    pub async fn close(mut self) {
+        let _ = self
+            .client
+            .send(
+                "Target.closeTarget",
+                serde_json::json!({ "targetId": self.target_id }),
+                None,
+            )
+            .await;
+        let _ = self
+            .client
+            .send(
+                "Target.disposeBrowserContext",
+                serde_json::json!({ "browserContextId": self.context_id }),
+                None,
+            )
+            .await;
+    }
+}
+
+/// The error of a page not ready within the timeout.
+fn timed_out(timeout: Duration) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        format!("the page was not ready within {}ms", timeout.as_millis()),
+    )
+}
+
+/// Open a page on the chrome address and run the capture before the timeout of the wait
+/// options. The page is left open for the caller to close.
+async fn open_page<T>(
+    address: &str,
+    timeout: Duration,
+    capture: impl AsyncFnOnce(&mut RenderPage) -> std::io::Result<T>,
+) -> std::io::Result<(RenderPage, std::io::Result<T>)> {
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    let mut page = tokio::time::timeout_at(deadline, RenderPage::open(address))
+        .await
+        .map_err(|_| timed_out(timeout))??;
+
+    let captured = tokio::time::timeout_at(deadline, capture(&mut page))
+        .await
+        .unwrap_or_else(|_| Err(timed_out(timeout)));
+
+    Ok((page, captured))
+}
+
+/// Close the page without waiting on a hung renderer.
+async fn close_page(page: RenderPage) {
+    let _ = tokio::time::timeout(Duration::from_secs(2), page.close()).await;
+}
+
+/// Open a page on the chrome address and run the capture before the timeout of the wait
+/// options. The page is closed after.
+async fn with_page<T>(
+    address: &str,
+    timeout: Duration,
+    capture: impl AsyncFnOnce(&mut RenderPage) -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let (page, captured) = open_page(address, timeout, capture).await?;
+
+    close_page(page).await;
+
+    captured
+}
+
+/// Decode the base64 data of a CDP result.
+fn decode_data(result: &Value, method: &str) -> std::io::Result<Vec<u8>> {
+    use base64::Engine;
+
+    base64::engine::general_purpose::STANDARD
+        .decode(field(result, "data", method)?)
+        .map_err(std::io::Error::other)
+}
+
+/// Load the page and capture a screenshot returning the image bytes.
+pub(crate) async fn screenshot(
+    address: &str,
+    options: &ScreenshotOptions,
+) -> std::io::Result<Vec<u8>> {
+    with_page(address, options.page.wait.timeout, async |page| {
+        page.load(&options.page).await?;
+
+        let mut params = serde_json::json!({ "format": options.format.as_str() });
+
+        if let Some(quality) = options.quality {
+            params["quality"] = quality.into();
+        }
+
+        if options.full_page {
+            let metrics = page
+                .send("Page.getLayoutMetrics", serde_json::json!({}))
+                .await?;
+            let size = |key: &str| {
+                metrics
+                    .pointer(&format!("/cssContentSize/{}", key))
+                    .and_then(Value::as_f64)
+                    .unwrap_or(1.0)
+                    .ceil()
+            };
+
+            params["captureBeyondViewport"] = true.into();
+            params["clip"] = serde_json::json!({
+                "x": 0,
+                "y": 0,
+                "width": size("width"),
+                "height": size("height"),
+                "scale": 1,
+            });
+        } else if let Some(clip) = options.clip.as_ref() {
+            params["captureBeyondViewport"] = true.into();
+            params["clip"] = serde_json::json!({
+                "x": clip.x,
+                "y": clip.y,
+                "width": clip.width,
+                "height": clip.height,
+                "scale": clip.scale,
+            });
+        }
+
+        let captured = page.send("Page.captureScreenshot", params).await?;
+
+        decode_data(&captured, "Page.captureScreenshot")
+    })
+    .await
+}
+
+/// Load the page and print it to a PDF streamed from chrome as it is read. The page is closed
+/// and the slot released once the stream ends or the body is dropped.
+pub(crate) async fn pdf(
+    address: &str,
+    options: &PdfOptions,
+    slot: RenderSlot,
+) -> std::io::Result<ChannelBody> {
+    let (mut page, printed) = open_page(address, options.page.wait.timeout, async |page| {
+        page.load(&options.page).await?;
+
+        let printed = page.send("Page.printToPDF", options.params()).await?;
+
+        field(&printed, "stream", "Page.printToPDF")
+    })
+    .await?;
+
+    let handle = match printed {
+        Ok(handle) => handle,
+        Err(e) => {
+            close_page(page).await;
+            return Err(e);
+        }
+    };
+
+    let (sender, body) = ChannelBody::channel(4);
+
+    tokio::spawn(async move {
+        loop {
+            let read = tokio::time::timeout(
+                PDF_READ_TIMEOUT,
+                page.send(
+                    "IO.read",
+                    serde_json::json!({ "handle": handle, "size": PDF_CHUNK_SIZE }),
+                ),
+            )
+            .await
+            .unwrap_or_else(|_| {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "the PDF stream stalled",
+                ))
+            });
+
+            let chunk = read.and_then(|read| {
+                let data = if read.get("base64Encoded").and_then(Value::as_bool) == Some(true) {
+                    decode_data(&read, "IO.read")?
+                } else {
+                    field(&read, "data", "IO.read")?.into_bytes()
+                };
+
+                Ok((
+                    data,
+                    read.get("eof").and_then(Value::as_bool).unwrap_or(true),
+                ))
+            });
+
+            match chunk {
+                Ok((data, eof)) => {
+                    // the client disconnected when the body was dropped.
+                    if !data.is_empty() && sender.send(Ok(Bytes::from(data))).await.is_err() {
+                        break;
+                    }
+
+                    if eof {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to read the PDF stream: {}", e);
+                    let _ = sender.send(Err(e)).await;
+                    break;
+                }
+            }
+        }
+
+        let _ = tokio::time::timeout(
+            Duration::from_secs(2),
+            page.send("IO.close", serde_json::json!({ "handle": handle })),
+        )
+        .await;
+
+        close_page(page).await;
+        drop(slot);
+    });
+
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::serve_recording;
+
+    #[tokio::test]
+    async fn test_screenshot() {
+        assert_eq!(
+            ScreenshotOptions::from_json(br#"{"url":"file:///etc/passwd"}"#),
+            Err("url must be an http or https url".into())
+        );
+        assert_eq!(
+            ScreenshotOptions::from_json(br#"{"html":"<p>hi</p>","quality":80}"#),
+            Err("quality is only supported for jpeg and webp".into())
+        );
+        assert_eq!(
+            ScreenshotOptions::from_json(br#"{"html":"<p>hi</p>","wait":{"idle":true}}"#),
+            Err("unknown option wait.idle".into())
+        );
+
+        let options = ScreenshotOptions::from_json(
+            br#"{"url":"https://example.com","viewport":{"width":800,"height":600},"format":"jpeg","quality":80,"full_page":true,"wait":{"until":"networkidle","timeout":5000}}"#,
+        )
+        .expect("valid options");
+
+        assert_eq!(options.format.content_type(), "image/jpeg");
+
+        let restricted = Claims {
+            tenant: "acme".into(),
+            allowed_domains: Some(vec!["Page".into()]),
+            ..Default::default()
+        };
+        assert_eq!(
+            RenderSlot::acquire(Some(&restricted), &SCREENSHOT_DOMAINS)
+                .err()
+                .map(|e| e.kind()),
+            Some(std::io::ErrorKind::PermissionDenied)
+        );
+        assert_eq!(options.page.wait.until, WaitUntil::NetworkIdle);
+        assert_eq!(options.page.wait.timeout, Duration::from_secs(5));
+
+        let recording = r#"{"timestamp":1,"direction":"send","session":"1","id":1,"method":"Target.createBrowserContext"}
+{"timestamp":2,"direction":"receive","session":"1","id":1,"method":"Target.createBrowserContext","result":{"browserContextId":"C1"}}
+{"timestamp":3,"direction":"send","session":"1","id":2,"method":"Target.createTarget"}
+{"timestamp":4,"direction":"receive","session":"1","id":2,"method":"Target.createTarget","result":{"targetId":"T1"}}
+{"timestamp":5,"direction":"send","session":"1","id":3,"method":"Target.attachToTarget"}
+{"timestamp":6,"direction":"receive","session":"1","id":3,"method":"Target.attachToTarget","result":{"sessionId":"S1"}}
+{"timestamp":7,"direction":"send","session":"1","id":4,"method":"Emulation.setDeviceMetricsOverride"}
+{"timestamp":8,"direction":"receive","session":"1","id":4,"method":"Emulation.setDeviceMetricsOverride","result":{}}
+{"timestamp":9,"direction":"send","session":"1","id":5,"method":"Page.navigate"}
+{"timestamp":10,"direction":"receive","session":"1","id":5,"method":"Page.navigate","result":{"frameId":"F1"}}
+{"timestamp":11,"direction":"send","session":"1","id":6,"method":"Runtime.evaluate"}
+{"timestamp":12,"direction":"receive","session":"1","id":6,"method":"Runtime.evaluate","result":{"result":{"type":"boolean","value":true}}}
+{"timestamp":13,"direction":"send","session":"1","id":7,"method":"Page.getLayoutMetrics"}
+{"timestamp":14,"direction":"receive","session":"1","id":7,"method":"Page.getLayoutMetrics","result":{"cssContentSize":{"x":0,"y":0,"width":800,"height":2400}}}
+{"timestamp":15,"direction":"send","session":"1","id":8,"method":"Page.captureScreenshot"}
+{"timestamp":16,"direction":"receive","session":"1","id":8,"method":"Page.captureScreenshot","result":{"data":"/9j/4AAQ"}}"#;
+
+        let address = serve_recording(recording).await;
+
+        assert_eq!(
+            screenshot(&address, &options).await.expect("screenshot"),
+            [0xff, 0xd8, 0xff, 0xe0, 0x00, 0x10]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pdf() {
+        use http_body_util::BodyExt;
+
+        assert_eq!(
+            PdfOptions::from_json(br#"{"html":"<p>hi</p>","paper":"b5"}"#),
+            Err("paper must be letter, legal, tabloid, ledger, a3, a4, a5, or an object".into())
+        );
+        assert_eq!(
+            PdfOptions::from_json(br#"{"html":"<p>hi</p>","page_ranges":"1-x"}"#),
+            Err("page_ranges must be pages or ranges, ex: 1-5, 8".into())
+        );
+        assert_eq!(
+            PdfOptions::from_json(br#"{"html":"<p>hi</p>","paper":"a5","margin":"3in"}"#),
+            Err("the margins must leave room on the paper".into())
+        );
+
+        let options = PdfOptions::from_json(
+            br#"{"url":"https://example.com","paper":"a4","landscape":true,"margin":{"top":"2cm","left":"10mm","right":"48px","bottom":0.5},"footer_template":"<span class=pageNumber></span>","print_background":true,"page_ranges":"1-3, 5"}"#,
+        )
+        .expect("valid options");
+
+        let params = options.params();
+        assert_eq!(params["marginTop"], 2.0 / 2.54);
+        assert_eq!(params["marginLeft"], 10.0 / 25.4);
+        assert_eq!(params["marginRight"], 0.5);
+        assert_eq!(params["marginBottom"], 0.5);
+        assert_eq!(params["headerTemplate"], "<span></span>");
+        assert_eq!(params["displayHeaderFooter"], true);
+        assert_eq!(params["generateTaggedPDF"], true);
+        assert_eq!(params["transferMode"], "ReturnAsStream");
+
+        let recording = r#"{"timestamp":1,"direction":"send","session":"1","id":1,"method":"Target.createBrowserContext"}
+{"timestamp":2,"direction":"receive","session":"1","id":1,"method":"Target.createBrowserContext","result":{"browserContextId":"C1"}}
+{"timestamp":3,"direction":"send","session":"1","id":2,"method":"Target.createTarget"}
+{"timestamp":4,"direction":"receive","session":"1","id":2,"method":"Target.createTarget","result":{"targetId":"T1"}}
+{"timestamp":5,"direction":"send","session":"1","id":3,"method":"Target.attachToTarget"}
+{"timestamp":6,"direction":"receive","session":"1","id":3,"method":"Target.attachToTarget","result":{"sessionId":"S1"}}
+{"timestamp":7,"direction":"send","session":"1","id":4,"method":"Page.navigate"}
+{"timestamp":8,"direction":"receive","session":"1","id":4,"method":"Page.navigate","result":{"frameId":"F1"}}
+{"timestamp":9,"direction":"send","session":"1","id":5,"method":"Runtime.evaluate"}
+{"timestamp":10,"direction":"receive","session":"1","id":5,"method":"Runtime.evaluate","result":{"result":{"type":"boolean","value":true}}}
+{"timestamp":11,"direction":"send","session":"1","id":6,"method":"Page.printToPDF"}
+{"timestamp":12,"direction":"receive","session":"1","id":6,"method":"Page.printToPDF","result":{"data":"","stream":"H1"}}
+{"timestamp":13,"direction":"send","session":"1","id":7,"method":"IO.read"}
+{"timestamp":14,"direction":"receive","session":"1","id":7,"method":"IO.read","result":{"base64Encoded":true,"data":"JVBERi0=","eof":false}}
+{"timestamp":15,"direction":"send","session":"1","id":8,"method":"IO.read"}
+{"timestamp":16,"direction":"receive","session":"1","id":8,"method":"IO.read","result":{"base64Encoded":true,"data":"MS43","eof":true}}
+{"timestamp":17,"direction":"send","session":"1","id":9,"method":"IO.close"}
+{"timestamp":18,"direction":"receive","session":"1","id":9,"method":"IO.close","result":{}}"#;
+
+        let address = serve_recording(recording).await;
+
+        let slot = RenderSlot::acquire(None, &PDF_DOMAINS).expect("render slot");
+        let pdf = pdf(&address, &options, slot)
+            .await
+            .expect("pdf")
+            .collect()
+            .await
+            .expect("stream")
+            .to_bytes();
+
+        assert_eq!(pdf, "%PDF-1.7");
+    }
+}
@@ -0,0 +1,396 @@
+use crate::ws::{
+    accept_key, encode_frame, read_head, FrameReader, RequestHead, OPCODE_CLOSE, OPCODE_PING,
+    OPCODE_PONG, OPCODE_TEXT,
+};
+use serde_json::{Map, Value};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// The browser websocket path advertised by the replay server.
+const REPLAY_BROWSER_PATH: &str = "/devtools/browser/replay";
+
+/// The page websocket path listed by the replay server.
+const REPLAY_PAGE_PATH: &str = "/devtools/page/replay";
+
+/// A recorded CDP message.
+#[derive(Debug, Clone)]
+struct Entry {
+    /// Sent by the client.
+    send: bool,
+    /// The command id.
+    id: Option<u64>,
+    /// The CDP message without the recording fields.
+    message: Map<String, Value>,
+}
+
+impl Entry {
+    /// The command or event method.
+    fn method(&self) -> Option<&str> {
+        self.message.get("method").and_then(Value::as_str)
+    }
+}
+
+/// Serves a recorded CDP session as a fake browser. Commands are answered with the recorded
+/// responses and the recorded events are emitted in order, without a browser or network.
+#[derive(Debug, Clone)]
+pub struct ReplayServer {
+    /// The recorded messages in order.
+    entries: Arc<Vec<Entry>>,
+}
+
+impl ReplayServer {
+    /// Load a JSONL recording written by the proxy recorder.
+    pub fn from_file(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Self::from_jsonl(&std::fs::read_to_string(path)?)
+    }
+
+    /// Parse a JSONL recording.
+    pub fn from_jsonl(contents: &str) -> std::io::Result<Self> {
+        let mut entries = Vec::new();
+
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let mut message = match serde_json::from_str::<Value>(line) {
+                Ok(Value::Object(message)) => message,
+                _ => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Invalid recording line: {}", line),
+                    ))
+                }
+            };
+
+            let send = message.get("direction").and_then(Value::as_str) == Some("send");
+            let id = message.get("id").and_then(Value::as_u64);
+
+            message.remove("timestamp");
+            message.remove("direction");
+            message.remove("session");
+
+            // responses are labeled with the command method in the recording.
+            if !send && id.is_some() {
+                message.remove("method");
+            }
+
+            entries.push(Entry { send, id, message });
+        }
+
+        Ok(Self {
+            entries: Arc::new(entries),
+        })
+    }
+
+    /// Bind the address and serve the recording.
+    pub async fn serve(self, address: &str) -> std::io::Result<()> {
+        let listener = TcpListener::bind(address).await?;
+        println!("Replay server listening on {}", address);
+        self.serve_listener(listener).await
+    }
+
+    /// Serve the recording on a bound listener. Every connection replays from the start.
+    pub async fn serve_listener(self, listener: TcpListener) -> std::io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let entries = self.entries.clone();
+
+            tokio::spawn(async move {
+                if let Err(err) = handle_connection(entries, stream).await {
+                    tracing::error!("Replay connection failed: {}", err);
+                }
+            });
+        }
+    }
+}
+
+/// Handle a replay http or websocket connection.
+async fn handle_connection(entries: Arc<Vec<Entry>>, mut stream: TcpStream) -> std::io::Result<()> {
+    let (head, rest) = read_head(&mut stream).await?;
+
+    let request = match RequestHead::parse(&head) {
+        Some(request) => request,
+        _ => return Ok(()),
+    };
+
+    // like chrome only the devtools paths upgrade.
+    if request.is_upgrade() && request.path.starts_with("/devtools/") {
+        let key = request.header("sec-websocket-key").unwrap_or_default();
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(key)
+        );
+        stream.write_all(response.as_bytes()).await?;
+
+        return replay_session(Replay::new(entries), stream, rest).await;
+    }
+
+    let host = request.header("host").unwrap_or("127.0.0.1");
+
+    let (status, body) = match request.path.as_str() {
+        "/json/version" => (
+            "200 OK",
+            serde_json::json!({
+                "Browser": "HeadlessChrome/Replay",
+                "Protocol-Version": "1.3",
+                "User-Agent": "",
+                "V8-Version": "",
+                "WebKit-Version": "",
+                "webSocketDebuggerUrl": format!("ws://{}{}", host, REPLAY_BROWSER_PATH),
+            })
+            .to_string(),
+        ),
+        "/json" | "/json/list" => (
+            "200 OK",
+            serde_json::json!([{
+                "id": "replay",
+                "type": "page",
+                "title": "about:blank",
+                "url": "about:blank",
+                "devtoolsFrontendUrl": format!("/devtools/inspector.html?ws={}{}", host, REPLAY_PAGE_PATH),
+                "webSocketDebuggerUrl": format!("ws://{}{}", host, REPLAY_PAGE_PATH),
+            }])
+            .to_string(),
+        ),
+        _ => ("404 Not Found", "Not Found".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+
+    stream.write_all(response.as_bytes()).await
+}
+
+/// Answer the websocket commands with the recording.
+async fn replay_session(
+    mut replay: Replay,
+    mut stream: TcpStream,
+    rest: Vec<u8>,
+) -> std::io::Result<()> {
+    let mut reader = FrameReader::new();
+    let mut buf = vec![0u8; 16384];
+
+    for message in replay.initial() {
+        stream
+            .write_all(&encode_frame(
+                OPCODE_TEXT,
+                message.to_string().as_bytes(),
+                None,
+            ))
+            .await?;
+    }
+
+    reader.feed(&rest);
+
+    loop {
+        while let Some(frame) = reader.next_frame() {
+            match frame.opcode {
+                OPCODE_CLOSE => {
+                    stream
+                        .write_all(&encode_frame(OPCODE_CLOSE, &frame.payload, None))
+                        .await?;
+                    return Ok(());
+                }
+                OPCODE_PING => {
+                    stream
+                        .write_all(&encode_frame(OPCODE_PONG, &frame.payload, None))
+                        .await?;
+                }
+                _ => {
+                    let command = reader
+                        .push_frame(frame)
+                        .and_then(|message| serde_json::from_slice::<Value>(&message).ok());
+
+                    if let Some(Value::Object(command)) = command {
+                        for message in replay.reply(&command) {
+                            stream
+                                .write_all(&encode_frame(
+                                    OPCODE_TEXT,
+                                    message.to_string().as_bytes(),
+                                    None,
+                                ))
+                                .await?;
+                        }
+                    }
+                }
+            }
+        }
+
+        let size = stream.read(&mut buf).await?;
+
+        if size == 0 {
+            return Ok(());
+        }
+
+        reader.feed(&buf[..size]);
+    }
+}
+
+/// The replay state of a single websocket connection.
+struct Replay {
+    /// The recorded messages in order.
+    entries: Arc<Vec<Entry>>,
+    /// The entries already answered or emitted.
+    used: Vec<bool>,
+}
+
+impl Replay {
+    /// A new replay from the start of the recording.
+    fn new(entries: Arc<Vec<Entry>>) -> Self {
+        let used = vec![false; entries.len()];
+        Self { entries, used }
+    }
+
+    /// The next command sent after the index that has not been answered.
+    fn next_command(&self, after: usize) -> usize {
+        (after + 1..self.entries.len())
+            .find(|&i| self.entries[i].send && !self.used[i])
+            .unwrap_or(self.entries.len())
+    }
+
+    /// The events recorded before the first command.
+    fn initial(&mut self) -> Vec<Value> {
+        let end = self
+            .entries
+            .iter()
+            .position(|entry| entry.send)
+            .unwrap_or(self.entries.len());
+
+        self.emit_events(0, end)
+    }
+
+    /// Emit the unused events in the range.
+    fn emit_events(&mut self, start: usize, end: usize) -> Vec<Value> {
+        let mut messages = Vec::new();
+
+        for i in start..end {
+            let entry = &self.entries[i];
+            if !entry.send && entry.id.is_none() && !self.used[i] {
+                self.used[i] = true;
+                messages.push(Value::Object(entry.message.clone()));
+            }
+        }
+
+        messages
+    }
+
+    /// The recorded response and events for a client command.
+    fn reply(&mut self, command: &Map<String, Value>) -> Vec<Value> {
+        let id = command.get("id").cloned().unwrap_or(Value::Null);
+        let method = command.get("method").and_then(Value::as_str);
+
+        let index = (0..self.entries.len())
+            .find(|&i| self.entries[i].send && !self.used[i] && self.entries[i].method() == method);
+
+        let index = match index {
+            Some(index) => index,
+            _ => {
+                return vec![serde_json::json!({
+                    "id": id,
+                    "error": {
+                        "code": -32601,
+                        "message": format!("'{}' wasn't found in the recording", method.unwrap_or_default()),
+                    },
+                })]
+            }
+        };
+
+        self.used[index] = true;
+
+        let recorded_id = self.entries[index].id;
+        let response = (index + 1..self.entries.len())
+            .find(|&i| !self.entries[i].send && self.entries[i].id == recorded_id);
+
+        let mut messages = self.emit_events(index + 1, response.unwrap_or(index + 1));
+
+        let mut message = match response {
+            Some(response) => {
+                self.used[response] = true;
+                self.entries[response].message.clone()
+            }
+            _ => {
+                let mut message = Map::new();
+                message.insert("result".into(), Value::Object(Map::new()));
+                message
+            }
+        };
+
+        message.insert("id".into(), id);
+        messages.push(Value::Object(message));
+
+        let after = response.unwrap_or(index);
+        let end = self.next_command(after);
+        messages.extend(self.emit_events(after + 1, end));
+
+        messages
+    }
+}
+
+/// Serve the recording on a local port for the tests. Returns the address.
+#[cfg(test)]
+pub(crate) async fn serve_recording(recording: &str) -> String {
+    let server = ReplayServer::from_jsonl(recording).expect("valid recording");
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+    let address = listener.local_addr().expect("address").to_string();
+
+    tokio::spawn(server.serve_listener(listener));
+
+    address
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ws::OPCODE_TEXT;
+
+    const RECORDING: &str = r#"{"timestamp":1,"direction":"send","session":"1","id":1,"method":"Browser.getVersion"}
+{"timestamp":2,"direction":"receive","session":"1","id":1,"method":"Browser.getVersion","result":{"product":"HeadlessChrome/131.0.6778.139"}}
+{"timestamp":3,"direction":"receive","session":"1","method":"Target.targetCreated","params":{"targetInfo":{"targetId":"T1"}}}"#;
+
+    #[tokio::test]
+    async fn test_replay_session() {
+        let address = serve_recording(RECORDING).await;
+
+        let mut stream = TcpStream::connect(address).await.expect("connect");
+
+        stream
+            .write_all(b"GET /devtools/browser/replay HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n")
+            .await
+            .expect("handshake");
+
+        let (head, rest) = read_head(&mut stream).await.expect("response");
+        assert!(head.starts_with(b"HTTP/1.1 101"));
+
+        let command = br#"{"id":7,"method":"Browser.getVersion"}"#;
+        stream
+            .write_all(&encode_frame(OPCODE_TEXT, command, Some([1, 2, 3, 4])))
+            .await
+            .expect("command");
+
+        let mut reader = FrameReader::new();
+        let mut messages = Vec::new();
+        let mut buf = vec![0u8; 4096];
+
+        reader.feed(&rest);
+
+        while messages.len() < 2 {
+            while let Some(message) = reader.next_message() {
+                messages.push(serde_json::from_slice::<Value>(&message).expect("json"));
+            }
+            if messages.len() < 2 {
+                let size = stream.read(&mut buf).await.expect("read");
+                reader.feed(&buf[..size]);
+            }
+        }
+
+        assert_eq!(messages[0]["id"], 7);
+        assert_eq!(
+            messages[0]["result"]["product"],
+            "HeadlessChrome/131.0.6778.139"
+        );
+        assert_eq!(messages[1]["method"], "Target.targetCreated");
+    }
+}
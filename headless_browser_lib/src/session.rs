@@ -0,0 +1,1043 @@
+use crate::cdp::CdpClient;
+use crate::record::{Direction, Recorder};
+use crate::ws::{
+    encode_frame, find_head_end, FrameReader, MAX_HEAD_SIZE, OPCODE_BINARY, OPCODE_CONTINUATION,
+    OPCODE_TEXT,
+};
+use serde_json::Value;
+use std::collections::HashSet;
+
+/// The target commands of isolated sessions limited to the targets of the session context.
+const SCOPED_TARGET_METHODS: [&str; 5] = [
+    "Target.activateTarget",
+    "Target.attachToTarget",
+    "Target.closeTarget",
+    "Target.exposeDevToolsProtocol",
+    "Target.getTargetInfo",
+];
+
+/// The commands of isolated sessions that always run in the session context.
+const CONTEXT_METHODS: [&str; 8] = [
+    "Browser.grantPermissions",
+    "Browser.resetPermissions",
+    "Browser.setDownloadBehavior",
+    "Browser.setPermission",
+    "Storage.clearCookies",
+    "Storage.getCookies",
+    "Storage.setCookies",
+    "Target.createTarget",
+];
+
+/// The commands blocked on isolated sessions since they reach the shared browser or the other
+/// browser contexts.
+const BROWSER_METHODS: [&str; 6] = [
+    "Browser.close",
+    "Browser.crash",
+    "Browser.crashGpuProcess",
+    "Target.getBrowserContexts",
+    "Target.getTargets",
+    "Target.sendMessageToTarget",
+];
+
+/// The first id of the commands the session sends to the browser, counting down to stay clear
+/// of the client ids.
+const INTERNAL_COMMAND_ID: u64 = i32::MAX as u64;
+
+lazy_static::lazy_static! {
+    /// The browser contexts of the isolated sessions and the tenants that own them.
+    static ref ISOLATED_CONTEXTS: dashmap::DashMap<String, Option<String>> = dashmap::DashMap::new();
+}
+
+/// Does the message contain the bytes.
+fn contains(message: &[u8], needle: &[u8]) -> bool {
+    message.windows(needle.len()).any(|window| window == needle)
+}
+
+/// Can the json message contain the string. Escaped strings have to be parsed to tell.
+fn may_contain(message: &[u8], needle: &[u8]) -> bool {
+    contains(message, needle) || contains(message, b"\\u")
+}
+
+/// Is the target in the browser context of an isolated session of the tenant. Connections to the
+/// page websockets of isolated sessions are limited to these targets.
+pub(crate) async fn owns_target(
+    address: &str,
+    target_id: &str,
+    tenant: Option<&str>,
+) -> std::io::Result<bool> {
+    let path = crate::launch::browser_path(address).await?;
+    let mut control = CdpClient::connect(address, &path).await?;
+
+    let info = match control
+        .send(
+            "Target.getTargetInfo",
+            serde_json::json!({ "targetId": target_id }),
+            None,
+        )
+        .await
+    {
+        Ok(info) => info,
+        // the target does not exist.
+        Err(_) => return Ok(false),
+    };
+
+    Ok(info
+        .pointer("/targetInfo/browserContextId")
+        .and_then(Value::as_str)
+        .and_then(|browser_context_id| ISOLATED_CONTEXTS.get(browser_context_id))
+        .is_some_and(|owner| owner.as_deref() == tenant))
+}
+
+/// Inspects the CDP messages of a proxied websocket session.
+pub(crate) struct Session {
+    /// The traffic recorder.
+    recorder: Option<Recorder>,
+    /// The manager connection to the browser used to scope the session.
+    control: Option<CdpClient>,
+    /// The isolated browser context of the session.
+    browser_context_id: Option<String>,
+    /// The browser address and websocket path when the session targets are tracked.
+    browser_path: Option<(String, String)>,
+    /// The targets opened by the session that are still alive.
+    targets: Vec<String>,
+    /// The pending `Target.createTarget` commands by session and command id.
+    pending_targets: HashSet<(Option<String>, u64)>,
+    /// Client to browser frames.
+    client_frames: FrameReader,
+    /// Browser to client frames.
+    server_frames: FrameReader,
+    /// The browser response head before the frames start.
+    server_head: Option<Vec<u8>>,
+    /// The CDP domains the client can call. None allows all.
+    allowed_domains: Option<Vec<String>>,
+    /// The raw frames of the client message being held until it is complete.
+    held_frames: Vec<u8>,
+    /// The opcode of the client message being held.
+    held_opcode: u8,
+    /// The error replies to blocked client commands waiting for a browser frame boundary.
+    client_replies: Vec<u8>,
+    /// The raw frames of the browser message being held until it is complete.
+    server_held: Vec<u8>,
+    /// The sessions auto attached to the targets of other browser contexts hidden from the client.
+    hidden_sessions: HashSet<String>,
+    /// The commands the session sends to the browser between client messages.
+    server_commands: Vec<u8>,
+    /// The ids of the commands sent by the session whose replies are hidden from the client.
+    internal_ids: HashSet<u64>,
+    /// The id of the next command sent by the session.
+    next_internal_id: u64,
+}
+
+impl Session {
+    /// A new session inspector.
+    pub fn new(recorder: Option<Recorder>) -> Self {
+        Self {
+            recorder,
+            control: None,
+            browser_context_id: None,
+            browser_path: None,
+            targets: Vec::new(),
+            pending_targets: HashSet::new(),
+            client_frames: FrameReader::new(),
+            server_frames: FrameReader::new(),
+            server_head: Some(Vec::new()),
+            allowed_domains: None,
+            held_frames: Vec::new(),
+            held_opcode: OPCODE_TEXT,
+            client_replies: Vec::new(),
+            server_held: Vec::new(),
+            hidden_sessions: HashSet::new(),
+            server_commands: Vec::new(),
+            internal_ids: HashSet::new(),
+            next_internal_id: INTERNAL_COMMAND_ID,
+        }
+    }
+
+    /// Scope the session to a new browser context owned by the tenant. The context is created on
+    /// a manager connection to the browser websocket path and disposed when the session closes.
+    pub async fn isolate(
+        &mut self,
+        address: &str,
+        path: &str,
+        tenant: Option<&str>,
+    ) -> std::io::Result<()> {
+        let mut control = CdpClient::connect(address, path).await?;
+        let result = control
+            .send(
+                "Target.createBrowserContext",
+                serde_json::json!({ "disposeOnDetach": true }),
+                None,
+            )
+            .await?;
+
+        let browser_context_id = result
+            .get("browserContextId")
+            .and_then(Value::as_str)
+            .map(String::from)
+            .ok_or_else(|| std::io::Error::other("missing browserContextId"))?;
+
+        tracing::info!("Isolated session in browser context {}", browser_context_id);
+
+        ISOLATED_CONTEXTS.insert(browser_context_id.clone(), tenant.map(String::from));
+
+        self.control = Some(control);
+        self.browser_context_id = Some(browser_context_id);
+
+        Ok(())
+    }
+
+    /// Track the targets the client opens on the browser websocket path so they can be closed
+    /// if the client disconnects without closing them.
+    pub fn track_targets(&mut self, address: &str, path: &str) {
+        self.browser_path = Some((address.into(), path.into()));
+    }
+
+    /// Only forward the client commands of the CDP domains, ex: `["Page", "Runtime"]`.
+    pub fn restrict_domains(&mut self, domains: Vec<String>) {
+        self.allowed_domains = Some(domains);
+    }
+
+    /// The client or browser sent frames that cannot be inspected on a restricted or isolated
+    /// session and the connection has to be closed.
+    pub fn is_blocked(&self) -> bool {
+        (self.rewrites() && self.client_frames.is_poisoned())
+            || (self.browser_context_id.is_some() && self.server_frames.is_poisoned())
+    }
+
+    /// Take the commands for the browser. The client bytes forwarded by isolated sessions always
+    /// end between frames so the commands can be written right away.
+    pub fn take_server_commands(&mut self) -> Option<Vec<u8>> {
+        if self.server_commands.is_empty() {
+            return None;
+        }
+
+        Some(std::mem::take(&mut self.server_commands))
+    }
+
+    /// Take the error replies for the client once the browser is between messages.
+    pub fn take_client_replies(&mut self) -> Option<Vec<u8>> {
+        let at_boundary = self.server_head.is_none() && self.server_frames.at_boundary();
+
+        if self.client_replies.is_empty() || !at_boundary {
+            return None;
+        }
+
+        Some(std::mem::take(&mut self.client_replies))
+    }
+
+    /// Does the session need to inspect the traffic.
+    pub fn is_active(&self) -> bool {
+        self.recorder.is_some()
+            || self.browser_path.is_some()
+            || self.allowed_domains.is_some()
+            || self.rewrites()
+    }
+
+    /// Does the session rewrite the client messages.
+    fn rewrites(&self) -> bool {
+        self.browser_context_id.is_some() || self.allowed_domains.is_some()
+    }
+
+    /// Inspect bytes sent from the client to the browser. Returns the bytes to forward instead
+    /// when the session rewrites messages, holding back incomplete frames.
+    pub fn on_client_bytes(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        if !self.is_active() {
+            return None;
+        }
+
+        self.client_frames.feed(data);
+
+        if self.is_blocked() {
+            self.client_frames.take_pending();
+            return Some(Vec::new());
+        }
+
+        if !self.rewrites() {
+            while let Some(message) = self.client_frames.next_message() {
+                self.on_message(Direction::Send, &message);
+            }
+            if self.client_frames.is_poisoned() {
+                self.client_frames.take_pending();
+            }
+            return None;
+        }
+
+        let mut out = Vec::with_capacity(data.len());
+
+        while let Some((frame, raw)) = self.client_frames.next_frame_raw() {
+            // control frames can be sent between the fragments of a message.
+            if frame.opcode > OPCODE_BINARY {
+                out.extend(raw);
+                continue;
+            }
+
+            if frame.opcode != OPCODE_CONTINUATION {
+                self.held_opcode = frame.opcode;
+            }
+
+            // fragments are held until the message is complete and can be scoped.
+            self.held_frames.extend(raw);
+
+            let message = match self.client_frames.push_frame(frame) {
+                Some(message) => message,
+                _ => continue,
+            };
+
+            let raw = std::mem::take(&mut self.held_frames);
+
+            if let Some(reply) = self.blocked_reply(&message) {
+                self.client_replies
+                    .extend(encode_frame(OPCODE_TEXT, &reply, None));
+                continue;
+            }
+
+            self.on_message(Direction::Send, &message);
+
+            match self.rewrite_command(&message) {
+                Some(command) => out.extend(encode_frame(
+                    self.held_opcode,
+                    &command,
+                    Some(rand::random()),
+                )),
+                _ => out.extend(raw),
+            }
+        }
+
+        if self.is_blocked() {
+            tracing::warn!("Closing a scoped session with frames that cannot be inspected");
+            self.client_frames.take_pending();
+            self.held_frames.clear();
+        }
+
+        Some(out)
+    }
+
+    /// Inspect bytes sent from the browser to the client. Returns the bytes to forward instead
+    /// when the session hides the targets of other browser contexts, holding back incomplete
+    /// frames.
+    pub fn on_server_bytes(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        if !self.is_active() {
+            return None;
+        }
+
+        let filters = self.browser_context_id.is_some();
+
+        let mut out = match self.server_head.as_mut() {
+            Some(head) => {
+                let start = head.len();
+                head.extend_from_slice(data);
+
+                match find_head_end(head) {
+                    Some(end) => {
+                        head.truncate(end);
+                        self.server_head = None;
+                        self.server_frames.feed(&data[end - start..]);
+                        data[..end - start].to_vec()
+                    }
+                    _ => {
+                        // not a websocket response, stop inspecting.
+                        if head.len() > MAX_HEAD_SIZE {
+                            self.server_head = None;
+                            self.recorder = None;
+                        }
+                        return filters.then(|| data.to_vec());
+                    }
+                }
+            }
+            _ => {
+                self.server_frames.feed(data);
+                Vec::new()
+            }
+        };
+
+        if !filters {
+            while let Some(message) = self.server_frames.next_message() {
+                self.on_message(Direction::Receive, &message);
+            }
+
+            if self.server_frames.is_poisoned() {
+                self.server_frames.take_pending();
+            }
+
+            return None;
+        }
+
+        while let Some((frame, raw)) = self.server_frames.next_frame_raw() {
+            if frame.opcode > OPCODE_BINARY {
+                out.extend(raw);
+                continue;
+            }
+
+            self.server_held.extend(raw);
+
+            let message = match self.server_frames.push_frame(frame) {
+                Some(message) => message,
+                _ => continue,
+            };
+
+            let raw = std::mem::take(&mut self.server_held);
+
+            if !self.hides(&message) {
+                self.on_message(Direction::Receive, &message);
+                out.extend(raw);
+            }
+        }
+
+        if self.server_frames.is_poisoned() {
+            tracing::warn!("Closing an isolated session with frames that cannot be inspected");
+            self.server_frames.take_pending();
+            self.server_held.clear();
+        }
+
+        Some(out)
+    }
+
+    /// Is the browser message about the targets of another browser context. Auto attached
+    /// sessions of those targets are resumed and detached so they are not left waiting.
+    fn hides(&mut self, message: &[u8]) -> bool {
+        let relevant = may_contain(message, b"Target.")
+            || (!self.internal_ids.is_empty() && message.starts_with(b"{\"id\":"))
+            || self
+                .hidden_sessions
+                .iter()
+                .any(|session_id| contains(message, session_id.as_bytes()));
+
+        if !relevant {
+            return false;
+        }
+
+        let message = match serde_json::from_slice::<Value>(message) {
+            Ok(message) => message,
+            _ => return false,
+        };
+
+        let method = message.get("method").and_then(Value::as_str);
+
+        if let (None, Some(id)) = (method, message.get("id").and_then(Value::as_u64)) {
+            return self.internal_ids.remove(&id);
+        }
+
+        if message
+            .get("sessionId")
+            .and_then(Value::as_str)
+            .is_some_and(|session_id| self.hidden_sessions.contains(session_id))
+        {
+            return true;
+        }
+
+        let params = message.get("params");
+        let param = |key: &str| {
+            params
+                .and_then(|params| params.get(key))
+                .and_then(Value::as_str)
+        };
+        let foreign = || {
+            params
+                .and_then(|params| params.pointer("/targetInfo/browserContextId"))
+                .and_then(Value::as_str)
+                != self.browser_context_id.as_deref()
+        };
+
+        match method {
+            Some("Target.targetCreated" | "Target.targetInfoChanged") => foreign(),
+            Some("Target.attachedToTarget") if foreign() => {
+                if let Some(session_id) = param("sessionId") {
+                    let session_id = session_id.to_string();
+
+                    self.send_internal(
+                        "Runtime.runIfWaitingForDebugger",
+                        serde_json::json!({}),
+                        Some(&session_id),
+                    );
+                    self.send_internal(
+                        "Target.detachFromTarget",
+                        serde_json::json!({ "sessionId": session_id }),
+                        None,
+                    );
+                    self.hidden_sessions.insert(session_id);
+                }
+                true
+            }
+            Some("Target.detachedFromTarget") => {
+                param("sessionId").is_some_and(|session_id| self.hidden_sessions.remove(session_id))
+            }
+            Some("Target.targetDestroyed" | "Target.targetCrashed") => param("targetId")
+                .is_some_and(|target_id| !self.targets.iter().any(|t| t == target_id)),
+            _ => false,
+        }
+    }
+
+    /// Queue a command for the browser whose reply is hidden from the client.
+    fn send_internal(&mut self, method: &str, params: Value, session_id: Option<&str>) {
+        let id = self.next_internal_id;
+        self.next_internal_id -= 1;
+        self.internal_ids.insert(id);
+
+        let mut command = serde_json::json!({ "id": id, "method": method, "params": params });
+
+        if let Some(session_id) = session_id {
+            command["sessionId"] = session_id.into();
+        }
+
+        if let Ok(command) = serde_json::to_vec(&command) {
+            self.server_commands
+                .extend(encode_frame(OPCODE_TEXT, &command, Some(rand::random())));
+        }
+    }
+
+    /// Handle a complete CDP message.
+    fn on_message(&mut self, direction: Direction, message: &[u8]) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.record(direction, message);
+        }
+        if self.browser_path.is_some() || self.browser_context_id.is_some() {
+            self.track_target(direction, message);
+        }
+    }
+
+    /// Track the targets created by the client, popups opened by them, and the targets closed.
+    fn track_target(&mut self, direction: Direction, message: &[u8]) {
+        let relevant = match direction {
+            Direction::Send => may_contain(message, b"Target.createTarget"),
+            Direction::Receive => {
+                (!self.pending_targets.is_empty() && message.starts_with(b"{\"id\":"))
+                    || contains(message, b"Target.attachedToTarget")
+                    || contains(message, b"Target.targetCreated")
+                    || contains(message, b"Target.targetDestroyed")
+            }
+        };
+
+        if !relevant {
+            return;
+        }
+
+        let message = match serde_json::from_slice::<Value>(message) {
+            Ok(message) => message,
+            _ => return,
+        };
+
+        let session_id = message
+            .get("sessionId")
+            .and_then(Value::as_str)
+            .map(String::from);
+        let id = message.get("id").and_then(Value::as_u64);
+        let method = message.get("method").and_then(Value::as_str);
+        let params = message.get("params");
+
+        match (direction, method, id) {
+            (Direction::Send, Some("Target.createTarget"), Some(id)) => {
+                self.pending_targets.insert((session_id, id));
+            }
+            (Direction::Receive, None, Some(id))
+                if self.pending_targets.remove(&(session_id, id)) =>
+            {
+                if let Some(target_id) = message
+                    .get("result")
+                    .and_then(|result| result.get("targetId"))
+                    .and_then(Value::as_str)
+                {
+                    self.add_target(target_id);
+                }
+            }
+            (Direction::Receive, Some("Target.attachedToTarget" | "Target.targetCreated"), _) => {
+                let target_info = params.and_then(|params| params.get("targetInfo"));
+                let field = |key: &str| {
+                    target_info
+                        .and_then(|info| info.get(key))
+                        .and_then(Value::as_str)
+                };
+
+                // only targets opened by the session. Existing pages of other clients are also
+                // attached when auto attaching.
+                let owned = field("openerId")
+                    .is_some_and(|opener| self.targets.iter().any(|t| t == opener))
+                    || (self.browser_context_id.is_some()
+                        && field("browserContextId") == self.browser_context_id.as_deref());
+
+                if let (true, Some(target_id)) = (owned, field("targetId")) {
+                    self.add_target(target_id);
+                }
+            }
+            (Direction::Receive, Some("Target.targetDestroyed"), _) => {
+                if let Some(target_id) = params
+                    .and_then(|params| params.get("targetId"))
+                    .and_then(Value::as_str)
+                {
+                    self.targets.retain(|t| t != target_id);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Add a target opened by the session.
+    fn add_target(&mut self, target_id: &str) {
+        if !self.targets.iter().any(|t| t == target_id) {
+            self.targets.push(target_id.into());
+        }
+    }
+
+    /// The error code and message of a client command the session does not forward.
+    fn block_reason(&self, method: &str, params: Option<&Value>) -> Option<(i64, String)> {
+        if let Some(allowed_domains) = self.allowed_domains.as_ref() {
+            let domain = method.split_once('.').map_or("", |(domain, _)| domain);
+
+            // tunneled commands cannot be inspected.
+            if method == "Target.sendMessageToTarget"
+                || !allowed_domains.iter().any(|d| d == domain)
+            {
+                return Some((-32601, format!("'{}' is not allowed", method)));
+            }
+        }
+
+        if let Some(browser_context_id) = self.browser_context_id.as_deref() {
+            if BROWSER_METHODS.contains(&method) {
+                return Some((
+                    -32601,
+                    format!("'{}' is not allowed on isolated sessions", method),
+                ));
+            }
+
+            let param = |key: &str| {
+                params
+                    .and_then(|params| params.get(key))
+                    .and_then(Value::as_str)
+            };
+
+            if SCOPED_TARGET_METHODS.contains(&method)
+                && param("targetId")
+                    .is_some_and(|target_id| !self.targets.iter().any(|t| t == target_id))
+            {
+                return Some((-32602, "No target with given id found".into()));
+            }
+
+            // the context methods are scoped by the rewrite instead.
+            if !CONTEXT_METHODS.contains(&method)
+                && param("browserContextId").is_some_and(|id| id != browser_context_id)
+            {
+                return Some((
+                    -32602,
+                    "Failed to find browser context with given id".into(),
+                ));
+            }
+        }
+
+        None
+    }
+
+    /// The error reply to a client command outside the allowed CDP domains or the browser context
+    /// of an isolated session.
+    fn blocked_reply(&self, message: &[u8]) -> Option<Vec<u8>> {
+        // skip parsing the messages of isolated sessions that cannot be blocked.
+        if self.allowed_domains.is_none()
+            && !may_contain(message, b"Target.")
+            && !may_contain(message, b"Browser.")
+            && !may_contain(message, b"browserContextId")
+        {
+            return None;
+        }
+
+        let command = serde_json::from_slice::<Value>(message).unwrap_or_default();
+        let method = command
+            .get("method")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+
+        let (code, message) = self.block_reason(method, command.get("params"))?;
+
+        tracing::warn!("Blocked the CDP command {:?}", method);
+
+        let mut reply = serde_json::json!({
+            "id": command.get("id").cloned().unwrap_or_default(),
+            "error": {
+                "code": code,
+                "message": message,
+            },
+        });
+
+        if let Some(session_id) = command.get("sessionId") {
+            reply["sessionId"] = session_id.clone();
+        }
+
+        serde_json::to_vec(&reply).ok()
+    }
+
+    /// Scope a client command to the session returning the rewritten message. Targets, cookies,
+    /// and permissions always use the session context, even when the client set another.
+    fn rewrite_command(&self, message: &[u8]) -> Option<Vec<u8>> {
+        let browser_context_id = self.browser_context_id.as_ref()?;
+
+        // skip parsing messages that do not need to be scoped.
+        if !may_contain(message, b"Target.createTarget")
+            && !may_contain(message, b"Storage.")
+            && !may_contain(message, b"Browser.")
+        {
+            return None;
+        }
+
+        let mut command = serde_json::from_slice::<Value>(message).ok()?;
+        let method = command.get("method").and_then(Value::as_str)?;
+
+        if !CONTEXT_METHODS.contains(&method) {
+            return None;
+        }
+
+        let params = command
+            .as_object_mut()?
+            .entry("params")
+            .or_insert_with(|| serde_json::json!({}))
+            .as_object_mut()?;
+
+        if params.get("browserContextId").and_then(Value::as_str) == Some(browser_context_id) {
+            return None;
+        }
+
+        params.insert("browserContextId".into(), browser_context_id.clone().into());
+
+        serde_json::to_vec(&command).ok()
+    }
+
+    /// Close the targets the client left open.
+    async fn close_targets(&mut self) {
+        let (address, path) = match self.browser_path.as_ref() {
+            Some(browser_path) if !self.targets.is_empty() => browser_path,
+            _ => return,
+        };
+
+        if self.control.is_none() {
+            match CdpClient::connect(address, path).await {
+                Ok(control) => self.control = Some(control),
+                Err(e) => {
+                    tracing::warn!("Failed to connect to close the leaked targets: {}", e);
+                    return;
+                }
+            }
+        }
+
+        let targets = std::mem::take(&mut self.targets);
+        let mut closed = 0;
+
+        if let Some(control) = self.control.as_mut() {
+            for target_id in targets.iter() {
+                // targets already closed without discovery events fail here and are not counted.
+                if let Ok(result) = control
+                    .send(
+                        "Target.closeTarget",
+                        serde_json::json!({ "targetId": target_id }),
+                        None,
+                    )
+                    .await
+                {
+                    if result.get("success").and_then(Value::as_bool) != Some(false) {
+                        closed += 1;
+                    }
+                }
+            }
+        }
+
+        if closed > 0 {
+            tracing::info!(
+                "Closed {} leaked targets of {} tracked",
+                closed,
+                targets.len()
+            );
+        }
+    }
+
+    /// Release the resources of the session after the client disconnects.
+    pub async fn close(&mut self) {
+        self.close_targets().await;
+
+        if let (Some(control), Some(browser_context_id)) =
+            (self.control.as_mut(), self.browser_context_id.take())
+        {
+            ISOLATED_CONTEXTS.remove(&browser_context_id);
+
+            match control
+                .send(
+                    "Target.disposeBrowserContext",
+                    serde_json::json!({ "browserContextId": browser_context_id }),
+                    None,
+                )
+                .await
+            {
+                Ok(_) => tracing::info!("Disposed browser context {}", browser_context_id),
+                Err(e) => tracing::warn!(
+                    "Failed to dispose browser context {}: {}",
+                    browser_context_id,
+                    e
+                ),
+            }
+        }
+
+        self.control = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An isolated session in the `C1` browser context after the upgrade.
+    fn isolated() -> Session {
+        let mut session = Session::new(None);
+        session.browser_context_id = Some("C1".into());
+        session.on_server_bytes(b"HTTP/1.1 101 Switching Protocols\r\n\r\n");
+        session
+    }
+
+    /// The json messages of the frames.
+    fn messages(frames: &[u8]) -> Vec<Value> {
+        let mut reader = FrameReader::new();
+        reader.feed(frames);
+        std::iter::from_fn(|| reader.next_message())
+            .map(|message| serde_json::from_slice(&message).unwrap())
+            .collect()
+    }
+
+    /// Send the client commands returning the forwarded commands and the error replies.
+    fn send(session: &mut Session, commands: &[&[u8]]) -> (Vec<Value>, Vec<Value>) {
+        let frames = commands
+            .iter()
+            .flat_map(|command| encode_frame(OPCODE_TEXT, command, Some([1, 2, 3, 4])))
+            .collect::<Vec<_>>();
+        let forwarded = session.on_client_bytes(&frames).expect("rewritten");
+        let replies = session.take_client_replies().unwrap_or_default();
+
+        (messages(&forwarded), messages(&replies))
+    }
+
+    #[test]
+    fn test_track_targets() {
+        let mut session = Session::new(None);
+        session.track_targets("127.0.0.1:9222", "/devtools/browser/test");
+        session.on_server_bytes(b"HTTP/1.1 101 Switching Protocols\r\n\r\n");
+
+        let command = br#"{"id":3,"method":"Target.createTarget","params":{"url":"about:blank"}}"#;
+        assert!(session
+            .on_client_bytes(&encode_frame(OPCODE_TEXT, command, Some([1, 2, 3, 4])))
+            .is_none());
+
+        let messages: [&[u8]; 3] = [
+            br#"{"id":3,"result":{"targetId":"T1"}}"#,
+            br#"{"method":"Target.attachedToTarget","params":{"sessionId":"S2","targetInfo":{"targetId":"T2","openerId":"T1"}}}"#,
+            br#"{"method":"Target.attachedToTarget","params":{"sessionId":"S3","targetInfo":{"targetId":"T3"}}}"#,
+        ];
+
+        for message in messages {
+            session.on_server_bytes(&encode_frame(OPCODE_TEXT, message, None));
+        }
+
+        assert_eq!(session.targets, ["T1", "T2"]);
+
+        session.on_server_bytes(&encode_frame(
+            OPCODE_TEXT,
+            br#"{"method":"Target.targetDestroyed","params":{"targetId":"T1"}}"#,
+            None,
+        ));
+
+        assert_eq!(session.targets, ["T2"]);
+    }
+
+    #[test]
+    fn test_restrict_domains() {
+        let mut session = Session::new(None);
+        session.restrict_domains(vec!["Page".into()]);
+        session.on_server_bytes(b"HTTP/1.1 101 Switching Protocols\r\n\r\n");
+
+        let allowed = br#"{"id":1,"method":"Page.navigate","params":{"url":"about:blank"}}"#;
+        let blocked = br#"{"id":2,"method":"Browser.close","sessionId":"S1"}"#;
+
+        let mut frames = encode_frame(OPCODE_TEXT, allowed, Some([1, 2, 3, 4]));
+        frames.extend(encode_frame(OPCODE_TEXT, blocked, Some([1, 2, 3, 4])));
+
+        let forwarded = session.on_client_bytes(&frames).expect("rewritten");
+
+        let mut reader = FrameReader::new();
+        reader.feed(&forwarded);
+        assert_eq!(reader.next_message().as_deref(), Some(&allowed[..]));
+        assert!(reader.next_message().is_none());
+
+        let mut reader = FrameReader::new();
+        reader.feed(&session.take_client_replies().expect("error reply"));
+        let reply: Value = serde_json::from_slice(&reader.next_message().unwrap()).unwrap();
+
+        assert_eq!(reply["id"], 2);
+        assert_eq!(reply["sessionId"], "S1");
+        assert_eq!(reply["error"]["code"], -32601);
+    }
+
+    #[test]
+    fn test_isolate() {
+        let mut session = Session::new(None);
+        session.browser_context_id = Some("C1".into());
+        session.on_server_bytes(b"HTTP/1.1 101 Switching Protocols\r\n\r\n");
+
+        // a fragmented command setting another context is reassembled and scoped.
+        let command =
+            br#"{"id":1,"method":"Target.createTarget","params":{"url":"about:blank","browserContextId":"C2"}}"#;
+        let (first, second) = command.split_at(20);
+        let mut frames = encode_frame(OPCODE_TEXT, first, Some([1, 2, 3, 4]));
+        frames[0] &= 0x7F;
+        let last = encode_frame(OPCODE_CONTINUATION, second, Some([1, 2, 3, 4]));
+
+        assert_eq!(session.on_client_bytes(&frames), Some(Vec::new()));
+
+        let mut reader = FrameReader::new();
+        reader.feed(&session.on_client_bytes(&last).expect("rewritten"));
+        let forwarded: Value = serde_json::from_slice(&reader.next_message().unwrap()).unwrap();
+
+        assert_eq!(forwarded["params"]["browserContextId"], "C1");
+        assert_eq!(forwarded["params"]["url"], "about:blank");
+
+        session.on_server_bytes(&encode_frame(
+            OPCODE_TEXT,
+            br#"{"id":1,"result":{"targetId":"T1"}}"#,
+            None,
+        ));
+
+        // targets outside the session context cannot be reached.
+        let owned = br#"{"id":2,"method":"Target.attachToTarget","params":{"targetId":"T1","flatten":true}}"#;
+        let foreign = br#"{"id":3,"method":"Target.attachToTarget","params":{"targetId":"T9","flatten":true}}"#;
+        let listed = br#"{"id":4,"method":"Target.get\u0054argets"}"#;
+
+        let mut frames = encode_frame(OPCODE_TEXT, owned, Some([1, 2, 3, 4]));
+        frames.extend(encode_frame(OPCODE_TEXT, foreign, Some([1, 2, 3, 4])));
+        frames.extend(encode_frame(OPCODE_TEXT, listed, Some([1, 2, 3, 4])));
+
+        let mut reader = FrameReader::new();
+        reader.feed(&session.on_client_bytes(&frames).expect("rewritten"));
+        assert_eq!(reader.next_message().as_deref(), Some(&owned[..]));
+        assert!(reader.next_message().is_none());
+
+        let mut reader = FrameReader::new();
+        reader.feed(&session.take_client_replies().expect("error replies"));
+        let reply: Value = serde_json::from_slice(&reader.next_message().unwrap()).unwrap();
+        assert_eq!(
+            (reply["id"].as_u64(), reply["error"]["code"].as_i64()),
+            (Some(3), Some(-32602))
+        );
+        let reply: Value = serde_json::from_slice(&reader.next_message().unwrap()).unwrap();
+        assert_eq!(
+            (reply["id"].as_u64(), reply["error"]["code"].as_i64()),
+            (Some(4), Some(-32601))
+        );
+
+        // compressed frames cannot be inspected and close the session.
+        assert_eq!(
+            session.on_client_bytes(&[0xC1, 0x80, 0, 0, 0, 0]),
+            Some(Vec::new())
+        );
+        assert!(session.is_blocked());
+    }
+
+    #[test]
+    fn test_isolate_browser_contexts() {
+        let mut session = isolated();
+
+        let (forwarded, replies) = send(
+            &mut session,
+            &[
+                br#"{"id":1,"method":"Storage.getCookies"}"#,
+                br#"{"id":2,"method":"Storage.setCookies","params":{"cookies":[],"browserContextId":"C2"}}"#,
+                br#"{"id":3,"method":"Target.disposeBrowserContext","params":{"browserContextId":"C2"}}"#,
+                br#"{"id":4,"method":"Target.createTarget","params":{"url":"about:blank","browserContextId":"C2"}}"#,
+            ],
+        );
+
+        assert_eq!(forwarded.len(), 3);
+        for command in forwarded.iter() {
+            assert_eq!(command["params"]["browserContextId"], "C1");
+        }
+        assert_eq!(replies.len(), 1);
+        assert_eq!(
+            (
+                replies[0]["id"].as_u64(),
+                replies[0]["error"]["code"].as_i64()
+            ),
+            (Some(3), Some(-32602))
+        );
+    }
+
+    #[test]
+    fn test_isolate_browser_close() {
+        let mut session = isolated();
+
+        let (forwarded, replies) = send(
+            &mut session,
+            &[
+                br#"{"id":1,"method":"Browser.close"}"#,
+                br#"{"id":2,"method":"Browser.crashGpuProcess"}"#,
+                br#"{"id":3,"method":"Browser.getVersion"}"#,
+            ],
+        );
+
+        assert_eq!(forwarded.len(), 1);
+        assert_eq!(forwarded[0]["method"], "Browser.getVersion");
+        assert_eq!(
+            replies
+                .iter()
+                .map(|reply| reply["error"]["code"].as_i64())
+                .collect::<Vec<_>>(),
+            [Some(-32601), Some(-32601)]
+        );
+    }
+
+    #[test]
+    fn test_isolate_discovery() {
+        let mut session = isolated();
+
+        let (forwarded, _) = send(
+            &mut session,
+            &[
+                br#"{"id":1,"method":"Target.setDiscoverTargets","params":{"discover":true}}"#,
+                br#"{"id":2,"method":"Target.setAutoAttach","params":{"autoAttach":true,"waitForDebuggerOnStart":true,"flatten":true}}"#,
+            ],
+        );
+        assert_eq!(forwarded.len(), 2);
+
+        let events: [&[u8]; 8] = [
+            br#"{"method":"Target.targetCreated","params":{"targetInfo":{"targetId":"T1","browserContextId":"C1"}}}"#,
+            br#"{"method":"Target.targetCreated","params":{"targetInfo":{"targetId":"T9","browserContextId":"C2"}}}"#,
+            br#"{"method":"Target.attachedToTarget","params":{"sessionId":"S9","targetInfo":{"targetId":"T9","browserContextId":"C2"},"waitingForDebugger":true}}"#,
+            br#"{"method":"Runtime.executionContextCreated","params":{},"sessionId":"S9"}"#,
+            br#"{"method":"Target.detachedFromTarget","params":{"sessionId":"S9","targetId":"T9"}}"#,
+            br#"{"method":"Target.targetDestroyed","params":{"targetId":"T9"}}"#,
+            br#"{"method":"Target.targetDestroyed","params":{"targetId":"T1"}}"#,
+            br#"{"id":2,"result":{}}"#,
+        ];
+        let mut frames = Vec::new();
+        for event in events {
+            frames.extend(encode_frame(OPCODE_TEXT, event, None));
+        }
+
+        let forwarded = messages(&session.on_server_bytes(&frames).expect("filtered"));
+        assert_eq!(
+            forwarded
+                .iter()
+                .map(|message| message["method"].as_str().unwrap_or("reply"))
+                .collect::<Vec<_>>(),
+            ["Target.targetCreated", "Target.targetDestroyed", "reply"]
+        );
+        assert_eq!(forwarded[0]["params"]["targetInfo"]["targetId"], "T1");
+
+        // the foreign target is resumed and detached without the client seeing the replies.
+        let commands = messages(&session.take_server_commands().expect("commands"));
+        assert_eq!(commands[0]["method"], "Runtime.runIfWaitingForDebugger");
+        assert_eq!(commands[0]["sessionId"], "S9");
+        assert_eq!(commands[1]["method"], "Target.detachFromTarget");
+        assert_eq!(commands[1]["params"]["sessionId"], "S9");
+
+        let mut replies = Vec::new();
+        for command in commands.iter() {
+            let reply = serde_json::json!({ "id": command["id"], "result": {} });
+            replies.extend(encode_frame(
+                OPCODE_TEXT,
+                reply.to_string().as_bytes(),
+                None,
+            ));
+        }
+        assert_eq!(session.on_server_bytes(&replies), Some(Vec::new()));
+        assert!(session.hidden_sessions.is_empty() && session.internal_ids.is_empty());
+    }
+}
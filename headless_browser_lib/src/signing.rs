@@ -0,0 +1,228 @@
+use crate::conf::{SESSION_SIGNING_KEY, SESSION_TTL};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The query param carrying the signed session token.
+pub(crate) const TOKEN_PARAM: &str = "token";
+/// The rejection reason of a single use token that was already redeemed.
+pub(crate) const USED_TOKEN: &str = "token already used";
+
+lazy_static::lazy_static! {
+    /// The single use nonces already redeemed with their expiry.
+    static ref USED_NONCES: dashmap::DashMap<String, u64> = dashmap::DashMap::new();
+}
+
+/// The current unix time in seconds.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// The signature of the payload.
+fn signature(key: &[u8], payload: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(payload);
+    mac
+}
+
+/// Is session signing enabled. Every proxied upgrade then requires a valid token.
+pub(crate) fn enabled() -> bool {
+    !SESSION_SIGNING_KEY.is_empty()
+}
+
+/// The options of a minted session url.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct SessionOptions {
+    /// Seconds until the url expires. Defaults to and is capped at `SESSION_TTL`.
+    pub ttl: Option<u64>,
+    /// Only allow the url to be used once.
+    pub single_use: bool,
+    /// The max seconds the websocket session can stay connected.
+    pub max_duration: Option<u64>,
+    /// The allowed `Origin` headers of the upgrade.
+    pub origins: Vec<String>,
+}
+
+impl SessionOptions {
+    /// Parse the options from a json body, ex: `{"ttl":60,"single_use":true,"max_duration":300,"origins":["https://app.example"]}`.
+    pub fn from_json(body: &[u8]) -> Result<Self, String> {
+        if body.iter().all(u8::is_ascii_whitespace) {
+            return Ok(Self::default());
+        }
+
+        let json = serde_json::from_slice::<Value>(body).map_err(|e| e.to_string())?;
+        let seconds = |key: &str| match json.get(key) {
+            None | Some(Value::Null) => Ok(None),
+            Some(value) => value
+                .as_u64()
+                .map(Some)
+                .ok_or_else(|| format!("{} must be a positive integer", key)),
+        };
+
+        Ok(Self {
+            ttl: seconds("ttl")?,
+            single_use: json
+                .get("single_use")
+                .and_then(Value::as_bool)
+                .unwrap_or_default(),
+            max_duration: seconds("max_duration")?,
+            origins: json
+                .get("origins")
+                .and_then(Value::as_array)
+                .map(|origins| {
+                    origins
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .map(String::from)
+                        .collect()
+                })
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// Sign a session token with the options returning the token and the expiry in unix seconds.
+pub(crate) fn sign(key: &[u8], options: &SessionOptions) -> (String, u64) {
+    // the nonces of single use urls are kept until they expire.
+    let ttl = options
+        .ttl
+        .map_or(*SESSION_TTL, |ttl| ttl.min(*SESSION_TTL));
+    let exp = now().saturating_add(ttl);
+
+    let mut claims = serde_json::json!({ "exp": exp });
+
+    if options.single_use {
+        claims["nonce"] = format!("{:032x}", rand::random::<u128>()).into();
+    }
+    if let Some(max_duration) = options.max_duration {
+        claims["max_duration"] = max_duration.into();
+    }
+    if !options.origins.is_empty() {
+        claims["origins"] = options.origins.clone().into();
+    }
+
+    let payload = URL_SAFE_NO_PAD.encode(claims.to_string());
+    let mac = URL_SAFE_NO_PAD.encode(signature(key, payload.as_bytes()).finalize().into_bytes());
+
+    (format!("{}.{}", payload, mac), exp)
+}
+
+/// The limits of a verified session.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct SessionLimits {
+    /// How long the websocket session can stay connected.
+    pub max_duration: Option<Duration>,
+}
+
+/// Verify a session token for the upgrade origin. Single use tokens are redeemed by the first
+/// connection that verifies them.
+pub(crate) fn verify(
+    key: &[u8],
+    token: &str,
+    origin: Option<&str>,
+) -> Result<SessionLimits, &'static str> {
+    let (payload, mac) = token.split_once('.').ok_or("malformed token")?;
+    let mac = URL_SAFE_NO_PAD
+        .decode(mac)
+        .map_err(|_| "malformed signature")?;
+
+    signature(key, payload.as_bytes())
+        .verify_slice(&mac)
+        .map_err(|_| "invalid signature")?;
+
+    let claims = URL_SAFE_NO_PAD
+        .decode(payload)
+        .ok()
+        .and_then(|payload| serde_json::from_slice::<Value>(&payload).ok())
+        .ok_or("malformed claims")?;
+
+    let exp = claims
+        .get("exp")
+        .and_then(Value::as_u64)
+        .ok_or("missing expiry")?;
+    let now = now();
+
+    if exp <= now {
+        return Err("expired token");
+    }
+
+    if let Some(origins) = claims.get("origins").and_then(Value::as_array) {
+        let allowed =
+            origin.is_some_and(|origin| origins.iter().any(|o| o.as_str() == Some(origin)));
+        if !allowed {
+            return Err("origin not allowed");
+        }
+    }
+
+    if let Some(nonce) = claims.get("nonce").and_then(Value::as_str) {
+        USED_NONCES.retain(|_, exp| *exp > now);
+
+        // the insert is the check so concurrent connections cannot both redeem the nonce.
+        if USED_NONCES.insert(nonce.to_string(), exp).is_some() {
+            return Err(USED_TOKEN);
+        }
+    }
+
+    Ok(SessionLimits {
+        max_duration: claims
+            .get("max_duration")
+            .and_then(Value::as_u64)
+            .map(Duration::from_secs),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signed_session_token() {
+        let key = b"test-signing-key";
+        let options = SessionOptions {
+            single_use: true,
+            max_duration: Some(30),
+            origins: vec!["https://app.example".into()],
+            ..Default::default()
+        };
+        let (token, _) = sign(key, &options);
+
+        assert_eq!(
+            verify(key, &token, Some("https://other.example")),
+            Err("origin not allowed")
+        );
+
+        let limits = verify(key, &token, Some("https://app.example")).expect("valid token");
+        assert_eq!(limits.max_duration, Some(Duration::from_secs(30)));
+        assert_eq!(
+            verify(key, &token, Some("https://app.example")),
+            Err(USED_TOKEN)
+        );
+        assert_eq!(
+            verify(b"other-key", &token, Some("https://app.example")),
+            Err("invalid signature")
+        );
+
+        let (expired, _) = sign(
+            key,
+            &SessionOptions {
+                ttl: Some(0),
+                ..Default::default()
+            },
+        );
+        assert_eq!(verify(key, &expired, None), Err("expired token"));
+
+        let (_, exp) = sign(
+            key,
+            &SessionOptions {
+                ttl: Some(u64::MAX),
+                ..Default::default()
+            },
+        );
+        assert!(exp <= now() + *SESSION_TTL);
+    }
+}
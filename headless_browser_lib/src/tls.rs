@@ -0,0 +1,429 @@
+use crate::conf::{TLS_CERT, TLS_CLIENT_CA, TLS_KEY};
+use crate::listener::Stream;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+use tokio_rustls::TlsAcceptor;
+
+/// How often the certificate files are checked for rotation.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The acceptor for the control server and proxy when TLS is configured.
+static TLS_ACCEPTOR: OnceLock<TlsAcceptor> = OnceLock::new();
+
+/// An invalid certificate or key.
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+/// The modified time of the file.
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+/// Load the PEM certificate chain and private key.
+fn load_certified_key(
+    provider: &CryptoProvider,
+    cert_path: &Path,
+    key_path: &Path,
+) -> std::io::Result<CertifiedKey> {
+    let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(std::fs::File::open(
+        cert_path,
+    )?))
+    .collect::<Result<Vec<CertificateDer<'static>>, _>>()?;
+
+    if certs.is_empty() {
+        return Err(invalid(format!(
+            "no certificates found in {}",
+            cert_path.display()
+        )));
+    }
+
+    let key: PrivateKeyDer<'static> =
+        rustls_pemfile::private_key(&mut std::io::BufReader::new(std::fs::File::open(key_path)?))?
+            .ok_or_else(|| invalid(format!("no private key found in {}", key_path.display())))?;
+
+    let key = provider
+        .key_provider
+        .load_private_key(key)
+        .map_err(|e| invalid(format!("invalid private key: {}", e)))?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// The loaded certificate with the file times it was loaded at.
+#[derive(Debug)]
+struct LoadedCert {
+    /// The certificate chain and signing key.
+    key: Arc<CertifiedKey>,
+    /// The certificate file modified time.
+    cert_modified: Option<SystemTime>,
+    /// The key file modified time.
+    key_modified: Option<SystemTime>,
+}
+
+/// Serves the certificate files. A background thread reloads them when they are rotated on disk
+/// so the handshake only reads the current certificate.
+#[derive(Debug)]
+pub(crate) struct ReloadingCertResolver {
+    /// The crypto provider used to load keys.
+    provider: Arc<CryptoProvider>,
+    /// The PEM certificate chain path.
+    cert_path: PathBuf,
+    /// The PEM private key path.
+    key_path: PathBuf,
+    /// The current certificate.
+    loaded: RwLock<Arc<LoadedCert>>,
+}
+
+impl ReloadingCertResolver {
+    /// Load the certificate and key files and start the reload thread. The thread stops once the
+    /// resolver is dropped.
+    pub fn new(
+        provider: Arc<CryptoProvider>,
+        cert_path: impl Into<PathBuf>,
+        key_path: impl Into<PathBuf>,
+    ) -> std::io::Result<Arc<Self>> {
+        let cert_path = cert_path.into();
+        let key_path = key_path.into();
+        let loaded = Self::load(&provider, &cert_path, &key_path)?;
+
+        let resolver = Arc::new(Self {
+            provider,
+            cert_path,
+            key_path,
+            loaded: RwLock::new(Arc::new(loaded)),
+        });
+
+        let watched = Arc::downgrade(&resolver);
+
+        std::thread::Builder::new()
+            .name("tls-reloader".into())
+            .spawn(move || Self::watch(watched))?;
+
+        Ok(resolver)
+    }
+
+    /// Check the files every `RELOAD_INTERVAL` while the resolver is alive.
+    fn watch(resolver: Weak<Self>) {
+        loop {
+            std::thread::sleep(RELOAD_INTERVAL);
+
+            match resolver.upgrade() {
+                Some(resolver) => resolver.reload_if_changed(),
+                _ => break,
+            }
+        }
+    }
+
+    /// Load the files recording the modified times.
+    fn load(
+        provider: &CryptoProvider,
+        cert_path: &Path,
+        key_path: &Path,
+    ) -> std::io::Result<LoadedCert> {
+        let cert_modified = modified(cert_path);
+        let key_modified = modified(key_path);
+        let key = load_certified_key(provider, cert_path, key_path)?;
+
+        Ok(LoadedCert {
+            key: Arc::new(key),
+            cert_modified,
+            key_modified,
+        })
+    }
+
+    /// Reload the files when they changed since the last load. A failed reload keeps serving the
+    /// previous certificate.
+    fn reload_if_changed(&self) {
+        let changed = match self.loaded.read() {
+            Ok(loaded) => {
+                loaded.cert_modified != modified(&self.cert_path)
+                    || loaded.key_modified != modified(&self.key_path)
+            }
+            _ => false,
+        };
+
+        if !changed {
+            return;
+        }
+
+        match Self::load(&self.provider, &self.cert_path, &self.key_path) {
+            Ok(reloaded) => {
+                if let Ok(mut loaded) = self.loaded.write() {
+                    *loaded = Arc::new(reloaded);
+                    tracing::info!("Reloaded the TLS certificate {}", self.cert_path.display());
+                }
+            }
+            Err(e) => tracing::error!("Failed to reload the TLS certificate: {}", e),
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.loaded.read().ok().map(|loaded| loaded.key.clone())
+    }
+}
+
+/// Load the PEM CA bundle for client certificate verification.
+fn load_client_roots(client_ca_path: &str) -> std::io::Result<rustls::RootCertStore> {
+    let mut roots = rustls::RootCertStore::empty();
+
+    for cert in rustls_pemfile::certs(&mut std::io::BufReader::new(std::fs::File::open(
+        client_ca_path,
+    )?)) {
+        roots
+            .add(cert?)
+            .map_err(|e| invalid(format!("invalid client CA certificate: {}", e)))?;
+    }
+
+    if roots.is_empty() {
+        return Err(invalid(format!(
+            "no certificates found in {}",
+            client_ca_path
+        )));
+    }
+
+    Ok(roots)
+}
+
+/// Build the server config for the certificate files. Client certificates are required and
+/// verified against the CA bundle when set.
+pub(crate) fn server_config(
+    cert_path: &str,
+    key_path: &str,
+    client_ca_path: Option<&str>,
+) -> std::io::Result<rustls::ServerConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let resolver = ReloadingCertResolver::new(provider.clone(), cert_path, key_path)?;
+
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid(e.to_string()))?;
+
+    let builder = match client_ca_path {
+        Some(client_ca_path) => {
+            let verifier = rustls::server::WebPkiClientVerifier::builder_with_provider(
+                Arc::new(load_client_roots(client_ca_path)?),
+                provider,
+            )
+            .build()
+            .map_err(|e| invalid(format!("invalid client CA: {}", e)))?;
+
+            builder.with_client_cert_verifier(verifier)
+        }
+        _ => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder.with_cert_resolver(resolver);
+
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(config)
+}
+
+/// Load the `TLS_CERT` and `TLS_KEY` files when configured. Called once at startup.
+pub(crate) fn init() -> std::io::Result<()> {
+    if TLS_CERT.is_empty() && TLS_KEY.is_empty() {
+        if !TLS_CLIENT_CA.is_empty() {
+            return Err(invalid(
+                "TLS_CLIENT_CA requires TLS_CERT and TLS_KEY".into(),
+            ));
+        }
+        return Ok(());
+    }
+
+    if TLS_CERT.is_empty() || TLS_KEY.is_empty() {
+        return Err(invalid("TLS_CERT and TLS_KEY must both be set".into()));
+    }
+
+    let client_ca = Some(TLS_CLIENT_CA.as_str()).filter(|client_ca| !client_ca.is_empty());
+    let config = server_config(&TLS_CERT, &TLS_KEY, client_ca)?;
+    let _ = TLS_ACCEPTOR.set(TlsAcceptor::from(Arc::new(config)));
+
+    Ok(())
+}
+
+/// Is TLS enabled on the listeners.
+pub(crate) fn enabled() -> bool {
+    TLS_ACCEPTOR.get().is_some()
+}
+
+/// Complete the TLS handshake of an accepted connection within the `HANDSHAKE_TIMEOUT` when TLS
+/// is enabled.
+pub(crate) async fn accept(stream: Stream) -> std::io::Result<Stream> {
+    match TLS_ACCEPTOR.get() {
+        Some(acceptor) => {
+            let stream =
+                tokio::time::timeout(crate::ws::HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+                    .await
+                    .map_err(|_| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            "timed out completing the TLS handshake",
+                        )
+                    })??;
+
+            Ok(Stream::Tls(Box::new(stream)))
+        }
+        _ => Ok(stream),
+    }
+}
+
+/// The identity of a verified client certificate. The first URI, DNS, or email SAN, falling back
+/// to the subject common name or the full subject.
+pub(crate) fn certificate_identity(cert: &CertificateDer<'_>) -> Option<String> {
+    use x509_parser::extensions::GeneralName;
+
+    let (_, cert) = x509_parser::parse_x509_certificate(cert.as_ref()).ok()?;
+
+    let san = cert
+        .subject_alternative_name()
+        .ok()
+        .flatten()
+        .and_then(|san| {
+            san.value.general_names.iter().find_map(|name| match name {
+                GeneralName::URI(name)
+                | GeneralName::DNSName(name)
+                | GeneralName::RFC822Name(name) => Some(name.to_string()),
+                _ => None,
+            })
+        });
+
+    san.or_else(|| {
+        cert.subject()
+            .iter_common_name()
+            .next()
+            .and_then(|common_name| common_name.as_str().ok())
+            .map(String::from)
+    })
+    .or_else(|| Some(cert.subject().to_string()).filter(|subject| !subject.is_empty()))
+}
+
+/// The client certificate identity of a mutual TLS connection.
+pub(crate) fn client_identity(stream: &Stream) -> Option<String> {
+    match stream {
+        Stream::Tls(stream) => stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(certificate_identity),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::ServerName;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Write a new self signed certificate for localhost returning the der certificate.
+    fn write_self_signed(dir: &Path) -> CertificateDer<'static> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert.cert.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), cert.key_pair.serialize_pem()).unwrap();
+        cert.cert.der().clone()
+    }
+
+    /// Connect trusting the certificate and return the certificate the server presented.
+    async fn handshake(
+        acceptor: TlsAcceptor,
+        trusted: CertificateDer<'static>,
+    ) -> CertificateDer<'static> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            stream.write_all(b"ok").await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(trusted).unwrap();
+
+        let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+        let stream = TcpStream::connect(address).await.unwrap();
+        let mut stream = connector
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+
+        let mut body = Vec::new();
+        stream.read_to_end(&mut body).await.unwrap();
+        assert_eq!(body, b"ok");
+
+        stream.get_ref().1.peer_certificates().unwrap()[0].clone()
+    }
+
+    #[tokio::test]
+    async fn test_tls_hot_reload() {
+        let dir = std::env::temp_dir().join(format!("headless-browser-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let first = write_self_signed(&dir);
+        let config = server_config(
+            dir.join("cert.pem").to_str().unwrap(),
+            dir.join("key.pem").to_str().unwrap(),
+            None,
+        )
+        .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        assert_eq!(handshake(acceptor.clone(), first.clone()).await, first);
+
+        // rotate the certificate on disk and wait for the reload thread.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let second = write_self_signed(&dir);
+        tokio::time::sleep(RELOAD_INTERVAL * 2).await;
+
+        assert_eq!(handshake(acceptor, second.clone()).await, second);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_certificate_identity() {
+        let mut params = rcgen::CertificateParams::new(vec!["worker.internal".into()]).unwrap();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "crawler");
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key_pair).unwrap();
+
+        assert_eq!(
+            certificate_identity(cert.der()),
+            Some("worker.internal".to_string())
+        );
+
+        let mut params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "crawler");
+        let cert = params.self_signed(&key_pair).unwrap();
+
+        assert_eq!(
+            certificate_identity(cert.der()),
+            Some("crawler".to_string())
+        );
+    }
+}
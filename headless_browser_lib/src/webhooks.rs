@@ -0,0 +1,370 @@
+use crate::conf::{WEBHOOK_CA, WEBHOOK_MAX_ATTEMPTS, WEBHOOK_SECRET, WEBHOOK_URLS};
+use crate::events::Event;
+use hmac::{Hmac, Mac};
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::{Request, StatusCode, Uri};
+use hyper_util::rt::TokioIo;
+use sha2::Sha256;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast;
+
+/// The lifecycle events delivered to the webhooks.
+const WEBHOOK_EVENTS: [&str; 4] = [
+    "instance.crashed",
+    "instance.crash_loop",
+    "capacity.exhausted",
+    "node.draining",
+];
+
+/// The backoff before the first retry. Doubled on every attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// The max backoff between attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The max time of a single delivery attempt.
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The deliveries still retrying.
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static::lazy_static! {
+    /// The TLS connector for https webhook urls verified against the `WEBHOOK_CA` bundle.
+    static ref TLS_CONNECTOR: Option<tokio_rustls::TlsConnector> = match tls_connector() {
+        Ok(connector) => Some(connector),
+        Err(e) => {
+            if WEBHOOK_URLS.iter().any(|url| url.starts_with("https://")) {
+                tracing::error!("Failed to load the webhook CA bundle {}: {}", *WEBHOOK_CA, e);
+            }
+            None
+        }
+    };
+}
+
+/// Build the TLS connector with the roots of the CA bundle.
+fn tls_connector() -> std::io::Result<tokio_rustls::TlsConnector> {
+    let mut roots = rustls::RootCertStore::empty();
+
+    for cert in rustls_pemfile::certs(&mut std::io::BufReader::new(std::fs::File::open(
+        &*WEBHOOK_CA,
+    )?)) {
+        let _ = roots.add(cert?);
+    }
+
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(std::io::Error::other)?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+
+    Ok(tokio_rustls::TlsConnector::from(Arc::new(config)))
+}
+
+/// Lowercase hex of the bytes.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The `X-Webhook-Signature` of a payload, the HMAC-SHA256 of `{timestamp}.{body}`.
+pub(crate) fn signature(secret: &[u8], timestamp: u64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac accepts any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+
+    format!("sha256={}", hex(&mac.finalize().into_bytes()))
+}
+
+/// Send the request over the stream returning the response status.
+async fn send<S>(stream: S, request: Request<Full<Bytes>>) -> std::io::Result<StatusCode>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .map_err(std::io::Error::other)?;
+
+    tokio::spawn(async move {
+        let _ = conn.await;
+    });
+
+    let response = sender
+        .send_request(request)
+        .await
+        .map_err(std::io::Error::other)?;
+
+    Ok(response.status())
+}
+
+/// POST the signed payload to the url once.
+async fn post(url: &Uri, event: &str, id: &str, body: &Bytes) -> std::io::Result<StatusCode> {
+    let https = url.scheme_str() == Some("https");
+    let host = url
+        .host()
+        .ok_or_else(|| std::io::Error::other("webhook url has no host"))?;
+    let port = url.port_u16().unwrap_or(if https { 443 } else { 80 });
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+
+    let mut request = Request::post(
+        url.path_and_query()
+            .map_or("/", |path_and_query| path_and_query.as_str()),
+    )
+    .header(
+        hyper::header::HOST,
+        url.authority().map_or(host, |authority| authority.as_str()),
+    )
+    .header(hyper::header::CONTENT_TYPE, "application/json")
+    .header(hyper::header::USER_AGENT, "headless-browser")
+    .header("X-Webhook-Event", event)
+    .header("X-Webhook-Id", id)
+    .header("X-Webhook-Timestamp", timestamp);
+
+    if !WEBHOOK_SECRET.is_empty() {
+        request = request.header(
+            "X-Webhook-Signature",
+            signature(WEBHOOK_SECRET.as_bytes(), timestamp, body),
+        );
+    }
+
+    let request = request
+        .body(Full::new(body.clone()))
+        .map_err(std::io::Error::other)?;
+
+    let stream = TcpStream::connect((host.trim_matches(['[', ']']), port)).await?;
+
+    if https {
+        let connector = TLS_CONNECTOR
+            .as_ref()
+            .ok_or_else(|| std::io::Error::other("the webhook CA bundle is not loaded"))?;
+        let server_name = rustls::pki_types::ServerName::try_from(host.to_string())
+            .map_err(std::io::Error::other)?;
+
+        send(connector.connect(server_name, stream).await?, request).await
+    } else {
+        send(stream, request).await
+    }
+}
+
+/// Deliver the payload to the url retrying with exponential backoff. Client errors other than
+/// 408 and 429 are not retried. Returns true when the receiver accepted the payload.
+pub(crate) async fn deliver_to(
+    url: &str,
+    event: &str,
+    body: Bytes,
+    max_attempts: u32,
+    initial_backoff: Duration,
+) -> bool {
+    let uri = match url.parse::<Uri>() {
+        Ok(uri) if matches!(uri.scheme_str(), Some("http" | "https")) => uri,
+        _ => {
+            tracing::error!("Invalid webhook url {}", url);
+            return false;
+        }
+    };
+
+    let id = hex(&rand::random::<[u8; 16]>());
+    let mut backoff = initial_backoff;
+
+    for attempt in 1..=max_attempts {
+        let status = tokio::time::timeout(ATTEMPT_TIMEOUT, post(&uri, event, &id, &body))
+            .await
+            .unwrap_or_else(|_| {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "webhook timed out",
+                ))
+            });
+
+        match status {
+            Ok(status) if status.is_success() => return true,
+            Ok(status)
+                if status.is_client_error()
+                    && status != StatusCode::REQUEST_TIMEOUT
+                    && status != StatusCode::TOO_MANY_REQUESTS =>
+            {
+                tracing::error!("Webhook {} rejected {} with {}", url, event, status);
+                return false;
+            }
+            Ok(status) => tracing::warn!(
+                "Webhook {} responded {} to {}. Attempt {} of {}",
+                url,
+                status,
+                event,
+                attempt,
+                max_attempts
+            ),
+            Err(e) => tracing::warn!(
+                "Webhook {} failed for {}: {}. Attempt {} of {}",
+                url,
+                event,
+                e,
+                attempt,
+                max_attempts
+            ),
+        }
+
+        if attempt < max_attempts {
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    tracing::error!("Dropped the {} webhook to {}", event, url);
+
+    false
+}
+
+/// Deliver the crash, crash loop, capacity, and draining events to the `WEBHOOK_URLS`.
+pub(crate) async fn deliver(mut events: broadcast::Receiver<Event>) {
+    if WEBHOOK_URLS.is_empty() {
+        return;
+    }
+
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                tracing::warn!("Skipped {} events for the webhooks", skipped);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+
+        if !WEBHOOK_EVENTS.contains(&event.name()) {
+            continue;
+        }
+
+        let name = event.name();
+        let body = Bytes::from(event.to_json().to_string());
+
+        for url in WEBHOOK_URLS.iter() {
+            let body = body.clone();
+
+            IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+
+            tokio::spawn(async move {
+                deliver_to(url, name, body, *WEBHOOK_MAX_ATTEMPTS, INITIAL_BACKOFF).await;
+                IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+    }
+}
+
+/// Wait for the deliveries in flight until the deadline.
+pub(crate) async fn flush(deadline: tokio::time::Instant) {
+    // let the delivery task pick up the events published right before.
+    tokio::time::sleep_until(
+        deadline.min(tokio::time::Instant::now() + Duration::from_millis(100)),
+    )
+    .await;
+
+    while IN_FLIGHT.load(Ordering::Relaxed) > 0 && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Read a request head and body from the stand-in receiver connection.
+    async fn read_request(stream: &mut TcpStream) -> (String, Vec<u8>) {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 4096];
+
+        loop {
+            let size = stream.read(&mut chunk).await.expect("read");
+            buf.extend_from_slice(&chunk[..size]);
+
+            if let Some(end) = buf.windows(4).position(|window| window == b"\r\n\r\n") {
+                let head = String::from_utf8_lossy(&buf[..end]).to_lowercase();
+                let length = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length: "))
+                    .and_then(|length| length.trim().parse::<usize>().ok())
+                    .unwrap_or_default();
+
+                while buf.len() < end + 4 + length {
+                    let size = stream.read(&mut chunk).await.expect("read");
+                    buf.extend_from_slice(&chunk[..size]);
+                }
+
+                return (head, buf[end + 4..end + 4 + length].to_vec());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_webhook_delivery() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind");
+        let url = format!("http://{}/hooks", listener.local_addr().expect("address"));
+
+        // the stand-in receiver fails the first attempt and accepts the retry.
+        let receiver = tokio::spawn(async move {
+            let mut requests = Vec::new();
+
+            for status in ["503 Service Unavailable", "204 No Content"] {
+                let (mut stream, _) = listener.accept().await.expect("accept");
+                requests.push(read_request(&mut stream).await);
+                stream
+                    .write_all(
+                        format!(
+                            "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                            status
+                        )
+                        .as_bytes(),
+                    )
+                    .await
+                    .expect("write");
+            }
+
+            requests
+        });
+
+        let body = Bytes::from(r#"{"type":"instance.crashed","pid":1}"#);
+
+        assert!(
+            deliver_to(
+                &url,
+                "instance.crashed",
+                body.clone(),
+                3,
+                Duration::from_millis(10)
+            )
+            .await
+        );
+
+        let requests = receiver.await.expect("receiver");
+        assert_eq!(requests.len(), 2);
+
+        let (head, received) = &requests[1];
+        assert!(head.starts_with("post /hooks http/1.1"));
+        assert!(head.contains("x-webhook-event: instance.crashed"));
+        assert_eq!(received, &body.to_vec());
+
+        // the retry keeps the delivery id for idempotent receivers.
+        let id = |head: &str| {
+            head.lines()
+                .find_map(|line| line.strip_prefix("x-webhook-id: "))
+                .map(str::to_string)
+        };
+        assert_eq!(id(&requests[0].0), id(head));
+
+        assert_eq!(
+            signature(b"secret", 1700000000, br#"{"pid":1}"#),
+            "sha256=a14b277548ab441b04f1a24d297b9c902fbc83298fcab4273ad15c52d0db99ff"
+        );
+    }
+}
@@ -0,0 +1,515 @@
+/// Continuation frame opcode.
+pub(crate) const OPCODE_CONTINUATION: u8 = 0x0;
+/// Text frame opcode.
+pub(crate) const OPCODE_TEXT: u8 = 0x1;
+/// Binary frame opcode.
+pub(crate) const OPCODE_BINARY: u8 = 0x2;
+/// Close frame opcode.
+pub(crate) const OPCODE_CLOSE: u8 = 0x8;
+/// Ping frame opcode.
+pub(crate) const OPCODE_PING: u8 = 0x9;
+/// Pong frame opcode.
+pub(crate) const OPCODE_PONG: u8 = 0xA;
+
+/// The websocket accept key guid.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The max size of a single frame we decode. Anything larger stops inspection.
+const MAX_FRAME_SIZE: u64 = 256 * 1024 * 1024;
+/// The max size of the http head before the websocket frames.
+pub(crate) const MAX_HEAD_SIZE: usize = 16 * 1024;
+/// The max time a connection can take to send its handshake before it is dropped.
+pub(crate) const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// A decoded websocket frame.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Frame {
+    /// Final fragment of the message.
+    pub fin: bool,
+    /// Frame opcode.
+    pub opcode: u8,
+    /// Unmasked frame payload.
+    pub payload: Vec<u8>,
+}
+
+/// Incremental websocket frame decoder for a single direction of a connection.
+#[derive(Debug, Default)]
+pub(crate) struct FrameReader {
+    /// Pending bytes not yet decoded.
+    buffer: Vec<u8>,
+    /// Fragments of the current data message.
+    fragments: Vec<u8>,
+    /// The opcode of the fragmented data message.
+    fragment_opcode: u8,
+    /// Decoding stopped due to an invalid or oversized frame.
+    poisoned: bool,
+}
+
+impl FrameReader {
+    /// A new frame reader.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Push raw bytes read from the socket.
+    pub fn feed(&mut self, data: &[u8]) {
+        if !self.poisoned {
+            self.buffer.extend_from_slice(data);
+        }
+    }
+
+    /// Decoding stopped and the reader ignores the rest of the stream.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    /// No frame or fragmented message is partially read.
+    pub fn at_boundary(&self) -> bool {
+        !self.poisoned && self.buffer.is_empty() && self.fragment_opcode == 0
+    }
+
+    /// Take the bytes that were not decoded.
+    pub fn take_pending(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buffer)
+    }
+
+    /// Decode the next complete frame returning the frame and the raw bytes it used.
+    pub fn next_frame_raw(&mut self) -> Option<(Frame, Vec<u8>)> {
+        if self.poisoned {
+            return None;
+        }
+
+        let (header_len, payload_len, mask) = match parse_header(&self.buffer) {
+            Ok(Some(header)) => header,
+            Ok(None) => return None,
+            Err(_) => {
+                self.poisoned = true;
+                return None;
+            }
+        };
+
+        let total = header_len + payload_len;
+
+        if self.buffer.len() < total {
+            return None;
+        }
+
+        let raw: Vec<u8> = self.buffer.drain(..total).collect();
+        let mut payload = raw[header_len..].to_vec();
+
+        if let Some(mask) = mask {
+            apply_mask(&mut payload, mask);
+        }
+
+        let frame = Frame {
+            fin: raw[0] & 0x80 != 0,
+            opcode: raw[0] & 0x0F,
+            payload,
+        };
+
+        Some((frame, raw))
+    }
+
+    /// Decode the next complete frame.
+    pub fn next_frame(&mut self) -> Option<Frame> {
+        self.next_frame_raw().map(|(frame, _)| frame)
+    }
+
+    /// Decode the next complete data message joining fragments and skipping control frames.
+    pub fn next_message(&mut self) -> Option<Vec<u8>> {
+        while let Some(frame) = self.next_frame() {
+            if let Some(message) = self.push_frame(frame) {
+                return Some(message);
+            }
+        }
+        None
+    }
+
+    /// Track a decoded frame returning the data message once it is complete.
+    pub fn push_frame(&mut self, frame: Frame) -> Option<Vec<u8>> {
+        match frame.opcode {
+            OPCODE_TEXT | OPCODE_BINARY => {
+                if frame.fin {
+                    self.fragments.clear();
+                    Some(frame.payload)
+                } else {
+                    self.fragment_opcode = frame.opcode;
+                    self.fragments = frame.payload;
+                    None
+                }
+            }
+            OPCODE_CONTINUATION if self.fragment_opcode != 0 => {
+                self.fragments.extend_from_slice(&frame.payload);
+                if frame.fin {
+                    self.fragment_opcode = 0;
+                    Some(std::mem::take(&mut self.fragments))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The frame header length, payload length, and mask key.
+type FrameHeader = (usize, usize, Option<[u8; 4]>);
+
+/// Parse a frame header. Errors on frames that cannot be inspected.
+fn parse_header(buf: &[u8]) -> Result<Option<FrameHeader>, ()> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+
+    // compressed frames (rsv1) are not inspected.
+    if buf[0] & 0x70 != 0 {
+        return Err(());
+    }
+
+    let masked = buf[1] & 0x80 != 0;
+    let len = (buf[1] & 0x7F) as u64;
+
+    let (mut header_len, payload_len) = match len {
+        126 => {
+            if buf.len() < 4 {
+                return Ok(None);
+            }
+            (4, u16::from_be_bytes([buf[2], buf[3]]) as u64)
+        }
+        127 => {
+            if buf.len() < 10 {
+                return Ok(None);
+            }
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&buf[2..10]);
+            (10, u64::from_be_bytes(bytes))
+        }
+        len => (2, len),
+    };
+
+    if payload_len > MAX_FRAME_SIZE {
+        return Err(());
+    }
+
+    let mask = if masked {
+        if buf.len() < header_len + 4 {
+            return Ok(None);
+        }
+        let mut key = [0u8; 4];
+        key.copy_from_slice(&buf[header_len..header_len + 4]);
+        header_len += 4;
+        Some(key)
+    } else {
+        None
+    };
+
+    Ok(Some((header_len, payload_len as usize, mask)))
+}
+
+/// Apply (or remove) a websocket mask in place.
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+/// Encode a single final frame. Client frames must pass a mask.
+pub(crate) fn encode_frame(opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+
+    frame.push(0x80 | (opcode & 0x0F));
+
+    if payload.len() < 126 {
+        frame.push(mask_bit | payload.len() as u8);
+    } else if payload.len() <= u16::MAX as usize {
+        frame.push(mask_bit | 126);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+        frame.push(mask_bit | 127);
+        frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+
+    match mask {
+        Some(mask) => {
+            frame.extend_from_slice(&mask);
+            let start = frame.len();
+            frame.extend_from_slice(payload);
+            apply_mask(&mut frame[start..], mask);
+        }
+        _ => frame.extend_from_slice(payload),
+    }
+
+    frame
+}
+
+/// The `Sec-WebSocket-Accept` value for the client key.
+pub(crate) fn accept_key(key: &str) -> String {
+    use base64::Engine;
+    use sha1::{Digest, Sha1};
+
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+
+    base64::engine::general_purpose::STANDARD.encode(hasher.finalize())
+}
+
+/// Find the end of the http head returning the length including the blank line.
+pub(crate) fn find_head_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|pos| pos + 4)
+}
+
+/// Read the http head of a request or response within the `HANDSHAKE_TIMEOUT`. Returns the head
+/// and any bytes read past it.
+pub(crate) async fn read_head<S>(stream: &mut S) -> std::io::Result<(Vec<u8>, Vec<u8>)>
+where
+    S: tokio::io::AsyncRead + Unpin,
+{
+    use tokio::io::AsyncReadExt;
+
+    let mut head = Vec::with_capacity(1024);
+    let mut buf = [0u8; 4096];
+
+    let read = async {
+        loop {
+            let size = stream.read(&mut buf).await?;
+
+            if size == 0 {
+                return Ok((head, Vec::new()));
+            }
+
+            head.extend_from_slice(&buf[..size]);
+
+            if let Some(end) = find_head_end(&head) {
+                let rest = head.split_off(end);
+                return Ok((head, rest));
+            }
+
+            if head.len() > MAX_HEAD_SIZE {
+                return Ok((head, Vec::new()));
+            }
+        }
+    };
+
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, read)
+        .await
+        .unwrap_or_else(|_| {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "timed out reading the http head",
+            ))
+        })
+}
+
+/// Decode a percent encoded query component.
+pub(crate) fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = |b: u8| (b as char).to_digit(16);
+
+                match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                    (Some(high), Some(low)) => {
+                        out.push((high * 16 + low) as u8);
+                        i += 2;
+                    }
+                    _ => out.push(b'%'),
+                }
+            }
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Parse the query string into key value pairs.
+pub(crate) fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => (percent_decode(key), percent_decode(value)),
+            _ => (percent_decode(pair), String::new()),
+        })
+        .collect()
+}
+
+/// The http request head of a proxied connection.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RequestHead {
+    /// The request method.
+    pub method: String,
+    /// The request path without the query.
+    pub path: String,
+    /// The raw query without the leading '?'.
+    pub query: String,
+    /// The http version.
+    pub version: String,
+    /// The request headers in order.
+    pub headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    /// Parse the http head bytes (including the blank line).
+    pub fn parse(head: &[u8]) -> Option<Self> {
+        let head = std::str::from_utf8(head).ok()?;
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next()?.split(' ');
+
+        let method = request_line.next()?.to_string();
+        let target = request_line.next()?;
+        let version = request_line.next()?.to_string();
+
+        if !version.starts_with("HTTP/") {
+            return None;
+        }
+
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path.to_string(), query.to_string()),
+            _ => (target.to_string(), String::new()),
+        };
+
+        let headers = lines
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| {
+                line.split_once(':')
+                    .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+            })
+            .collect();
+
+        Some(Self {
+            method,
+            path,
+            query,
+            version,
+            headers,
+        })
+    }
+
+    /// Get the first header value by case-insensitive name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Is the request a websocket upgrade.
+    pub fn is_upgrade(&self) -> bool {
+        self.header("upgrade")
+            .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+    }
+
+    /// The decoded query parameters.
+    pub fn query_params(&self) -> Vec<(String, String)> {
+        parse_query(&self.query)
+    }
+
+    /// Get a decoded query parameter by name.
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query_params()
+            .into_iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Remove query parameters by name keeping the remaining raw pairs untouched.
+    pub fn remove_query_params(&mut self, names: &[&str]) {
+        self.query = self
+            .query
+            .split('&')
+            .filter(|pair| {
+                let key = pair.split_once('=').map_or(*pair, |(key, _)| key);
+                !pair.is_empty() && !names.contains(&percent_decode(key).as_str())
+            })
+            .collect::<Vec<_>>()
+            .join("&");
+    }
+
+    /// Serialize the head back to bytes for forwarding.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = String::with_capacity(256);
+
+        out.push_str(&self.method);
+        out.push(' ');
+        out.push_str(&self.path);
+        if !self.query.is_empty() {
+            out.push('?');
+            out.push_str(&self.query);
+        }
+        out.push(' ');
+        out.push_str(&self.version);
+        out.push_str("\r\n");
+
+        for (key, value) in &self.headers {
+            out.push_str(key);
+            out.push_str(": ");
+            out.push_str(value);
+            out.push_str("\r\n");
+        }
+
+        out.push_str("\r\n");
+        out.into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_masked_partial() {
+        // "Hi" masked with the key [1, 2, 3, 4].
+        let encoded = [0x81, 0x82, 1, 2, 3, 4, b'H' ^ 1, b'i' ^ 2];
+        let mut reader = FrameReader::new();
+
+        reader.feed(&encoded[..3]);
+        assert_eq!(reader.next_message(), None);
+        reader.feed(&encoded[3..]);
+        assert_eq!(reader.next_message(), Some(b"Hi".to_vec()));
+    }
+
+    #[test]
+    fn test_frame_roundtrip_large() {
+        let payload = vec![b'a'; 70_000];
+        let encoded = encode_frame(OPCODE_TEXT, &payload, Some([9, 8, 7, 6]));
+        let mut reader = FrameReader::new();
+
+        reader.feed(&encoded);
+        assert_eq!(reader.next_message(), Some(payload));
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // the sample handshake from rfc 6455.
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_request_head_query() {
+        let head = b"GET /devtools/browser/abc?record=true&x=a%20b HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\r\n";
+        let mut request = RequestHead::parse(head).expect("valid head");
+
+        assert!(request.is_upgrade());
+        assert_eq!(request.query_param("record").as_deref(), Some("true"));
+        assert_eq!(request.query_param("x").as_deref(), Some("a b"));
+
+        request.remove_query_params(&["record"]);
+        assert!(request
+            .to_bytes()
+            .starts_with(b"GET /devtools/browser/abc?x=a%20b HTTP/1.1\r\n"));
+    }
+}